@@ -0,0 +1,397 @@
+//! User archives, for `feoblog user export` and `feoblog user import`.
+//!
+//! An archive is a tar file containing:
+//!
+//!  * `manifest.json`: Lists the user's items and attachments. Always first.
+//!  * `items/<signature>.proto3`: The signed bytes of each Item.
+//!  * `files/<sha512 hex>`: The contents of each file attachment we have.
+//!
+//! Archives aren't trusted on import. Every signature and hash is checked.
+
+use std::{collections::{HashMap, HashSet}, fmt, io::{Read, Seek, SeekFrom, Write}};
+
+use anyhow::{Context, Error, bail, format_err};
+use protobuf::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::{backend::{Backend, ItemRow, ItemTypes, SHA512, Signature, TimeSpan, Timestamp, UserID}, output::CsvRow, protos::{Item, ProtoValid}, util::AsHex};
+
+const FORMAT: &str = "feoblog-user-archive";
+const VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+
+/// Don't read huge entries into memory. (Items are much smaller than this.)
+const MAX_ITEM_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format: String,
+    version: u32,
+    user_id: String,
+    created_ms: i64,
+    items: Vec<ManifestItem>,
+    attachments: Vec<ManifestAttachment>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestItem {
+    signature: String,
+    timestamp_ms_utc: i64,
+    path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestAttachment {
+    /// The signature of the Item that lists this attachment.
+    signature: String,
+    name: String,
+    size: u64,
+    hash: String,
+    /// None if the server didn't have the file's contents.
+    path: Option<String>,
+}
+
+fn item_path(signature: &Signature) -> String {
+    format!("items/{}.proto3", signature.to_base58())
+}
+
+fn file_path(hash: &SHA512) -> String {
+    format!("files/{}", hash.bytes().as_hex())
+}
+
+/// Write all of a user's items & attachments to a tar archive.
+pub(crate) fn export<W: Write>(backend: &mut dyn Backend, user_id: &UserID, mut out: W) -> Result<ExportResult, Error> {
+    let mut result = ExportResult::default();
+    // Read everything from one snapshot, so that the manifest matches the items & files we write:
+    backend.read_transaction(&mut |backend| {
+        result = export_snapshot(backend, user_id, &mut out)?;
+        Ok(())
+    })?;
+    out.flush()?;
+    Ok(result)
+}
+
+fn export_snapshot<W: Write>(backend: &dyn Backend, user_id: &UserID, out: W) -> Result<ExportResult, Error> {
+    let mut manifest = Manifest {
+        format: FORMAT.into(),
+        version: VERSION,
+        user_id: user_id.to_base58(),
+        created_ms: Timestamp::now().unix_utc_ms,
+        items: vec![],
+        attachments: vec![],
+    };
+
+    // First pass: collect metadata for the manifest.
+    // (Items are small, but a user could have a lot of them, so we don't keep their bytes.)
+    let mut ids = vec![];
+    backend.user_items(user_id, all_time(), &ItemTypes::all(), &mut |row| {
+        let mut item = Item::new();
+        item.merge_from_bytes(&row.item_bytes)?;
+
+        manifest.items.push(ManifestItem {
+            signature: row.signature.to_base58(),
+            timestamp_ms_utc: row.timestamp.unix_utc_ms,
+            path: item_path(&row.signature),
+        });
+
+        for file in item.get_post().get_attachments().get_file() {
+            let hash = SHA512::from_hash_bytes(&file.hash)?;
+            manifest.attachments.push(ManifestAttachment {
+                signature: row.signature.to_base58(),
+                name: file.name.clone(),
+                size: file.size,
+                hash: hash.bytes().as_hex().to_string(),
+                path: None,
+            });
+            ids.push((user_id.clone(), row.signature.clone(), file.name.clone()));
+        }
+        Ok(true)
+    })?;
+
+    // Check which files we have:
+    let mut metas = HashMap::with_capacity(ids.len());
+    backend.attachments_meta(&ids, &mut |(id, meta)| {
+        metas.insert(id, meta);
+        Ok(true)
+    })?;
+
+    // Each file only needs to be written once, even if several attachments share it:
+    let mut files = vec![];
+    let mut file_paths = HashSet::new();
+    for (attachment, id) in manifest.attachments.iter_mut().zip(&ids) {
+        let meta = match metas.get(id) {
+            Some(meta) if meta.exists => meta,
+            _ => continue,
+        };
+        let path = file_path(&meta.hash);
+        if file_paths.insert(path.clone()) {
+            files.push((path.clone(), meta));
+        }
+        attachment.path = Some(path);
+    }
+
+    let mut result = ExportResult::default();
+    let mut tar = tar::Builder::new(out);
+
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    append(&mut tar, MANIFEST, json.len() as u64, json.as_slice())?;
+
+    // Second pass: the items themselves.
+    backend.user_items(user_id, all_time(), &ItemTypes::all(), &mut |row| {
+        append(&mut tar, &item_path(&row.signature), row.item_bytes.len() as u64, row.item_bytes.as_slice())?;
+        result.items += 1;
+        Ok(true)
+    })?;
+
+    // Stream files straight from the database:
+    for (path, meta) in files {
+        let found = backend.read_attachment(&meta.hash, &mut |size, contents| {
+            if size != meta.size {
+                bail!("Expected {} to be {} bytes, but found {}", path, meta.size, size);
+            }
+            append(&mut tar, &path, size, contents)
+        })?;
+        if !found {
+            bail!("{} is missing from the database", path);
+        }
+        result.files += 1;
+        result.file_bytes += meta.size;
+    }
+    result.missing_files = manifest.attachments.iter().filter(|a| a.path.is_none()).count();
+
+    tar.finish()?;
+    Ok(result)
+}
+
+fn all_time() -> TimeSpan {
+    TimeSpan::Before(Timestamp{ unix_utc_ms: i64::MAX })
+}
+
+fn append<W: Write, R: Read>(tar: &mut tar::Builder<W>, path: &str, size: u64, data: R) -> Result<(), Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(Timestamp::now().unix_utc_ms as u64 / 1000);
+    header.set_cksum();
+    tar.append_data(&mut header, path, data)
+        .with_context(|| format!("Error writing {} to archive", path))?;
+    Ok(())
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct ExportResult {
+    items: usize,
+    files: usize,
+    file_bytes: u64,
+    missing_files: usize,
+}
+
+impl CsvRow for ExportResult {
+    const HEADERS: &'static [&'static str] = &["items", "files", "file_bytes", "missing_files"];
+}
+
+impl fmt::Display for ExportResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Exported {} items and {} files ({} bytes).", self.items, self.files, self.file_bytes)?;
+        if self.missing_files > 0 {
+            writeln!(f, "{} attachments were not available on this server.", self.missing_files)?;
+        }
+        Ok(())
+    }
+}
+
+/// Import a user's items & attachments from a tar archive.
+/// The user must already be known to this server. (ex: `feoblog user add`)
+pub(crate) fn import<R: Read>(backend: &mut dyn Backend, input: R) -> Result<ImportResult, Error> {
+    let mut archive = tar::Archive::new(input);
+    let mut entries = archive.entries()?;
+
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let entry = entry?;
+            if entry.path()?.to_str() != Some(MANIFEST) {
+                bail!("Expected {} to be the first file in the archive", MANIFEST);
+            }
+            serde_json::from_reader(entry.take(MAX_ITEM_BYTES)).context("Error reading manifest")?
+        },
+        None => bail!("Empty archive"),
+    };
+
+    if manifest.format != FORMAT {
+        bail!("Not a FeoBlog user archive");
+    }
+    if manifest.version != VERSION {
+        bail!("Unsupported archive version: {}", manifest.version);
+    }
+
+    let user_id = UserID::from_base58(&manifest.user_id).context("Invalid user ID in manifest")?;
+    if !backend.user_known(&user_id)? {
+        bail!("User {} is not known to this server. Add them with `feoblog user add` first.", manifest.user_id);
+    }
+
+    // Where to find each file's metadata:
+    let attachments: HashMap<&str, &ManifestAttachment> = manifest.attachments.iter()
+        .filter_map(|a| a.path.as_ref().map(|p| (p.as_str(), a)))
+        .collect();
+
+    let mut result = ImportResult::default();
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path()?.to_str().map(String::from)
+            .ok_or_else(|| format_err!("Invalid path in archive"))?;
+
+        if let Some(name) = path.strip_prefix("items/").and_then(|p| p.strip_suffix(".proto3")) {
+            let signature = Signature::from_base58(name).with_context(|| format!("Invalid signature in {}", path))?;
+            let mut bytes = vec![];
+            entry.take(MAX_ITEM_BYTES).read_to_end(&mut bytes)?;
+            import_item(backend, &user_id, signature, bytes, &mut result)
+                .with_context(|| format!("Error importing {}", path))?;
+        } else if let Some(attachment) = attachments.get(path.as_str()) {
+            import_file(backend, &user_id, attachment, entry, &mut result)
+                .with_context(|| format!("Error importing {}", path))?;
+        } else {
+            bail!("Unexpected file in archive: {}", path);
+        }
+    }
+
+    Ok(result)
+}
+
+fn import_item(
+    backend: &mut dyn Backend,
+    user_id: &UserID,
+    signature: Signature,
+    bytes: Vec<u8>,
+    result: &mut ImportResult,
+) -> Result<(), Error> {
+    if !signature.is_valid(user_id, &bytes) {
+        bail!("Invalid signature");
+    }
+
+    let mut item = Item::new();
+    item.merge_from_bytes(&bytes)?;
+    item.validate()?;
+
+    if backend.user_item_exists(user_id, &signature)? {
+        result.existing_items += 1;
+        return Ok(());
+    }
+
+    let row = ItemRow {
+        user: user_id.clone(),
+        signature,
+        timestamp: Timestamp{ unix_utc_ms: item.get_timestamp_ms_utc() },
+        received: Timestamp::now(),
+        item_bytes: bytes,
+    };
+    backend.save_user_item(&row, &item)?;
+    result.items += 1;
+    Ok(())
+}
+
+fn import_file<R: Read>(
+    backend: &mut dyn Backend,
+    user_id: &UserID,
+    attachment: &ManifestAttachment,
+    mut contents: R,
+    result: &mut ImportResult,
+) -> Result<(), Error> {
+    // Trust the Item (which we've verified), not the manifest:
+    let signature = Signature::from_base58(&attachment.signature)?;
+    let meta = backend.get_attachment_meta(user_id, &signature, &attachment.name)?
+        .ok_or_else(|| format_err!("No Item lists this attachment"))?;
+
+    if meta.exists {
+        result.existing_files += 1;
+        return Ok(());
+    }
+
+    let mut temp = tempfile::tempfile().context("Error opening temp file")?;
+    let size = std::io::copy(&mut contents, &mut temp)?;
+    if size != meta.size {
+        bail!("Expected {} bytes but found {}", meta.size, size);
+    }
+    let hash = SHA512::from_file(&mut temp)?;
+    if hash != meta.hash {
+        bail!("Invalid data. Expected {}", meta.hash);
+    }
+
+    temp.seek(SeekFrom::Start(0))?;
+    backend.save_attachment(meta.size, &meta.hash, &mut temp)?;
+    result.files += 1;
+    Ok(())
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct ImportResult {
+    items: usize,
+    existing_items: usize,
+    files: usize,
+    existing_files: usize,
+}
+
+impl CsvRow for ImportResult {
+    const HEADERS: &'static [&'static str] = &["items", "existing_items", "files", "existing_files"];
+}
+
+impl fmt::Display for ImportResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Imported {} items ({} already existed).", self.items, self.existing_items)?;
+        writeln!(f, "Imported {} files ({} already existed).", self.files, self.existing_files)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{backend::{Backend, sqlite::tests::{TestDb, add_server_user, post, post_with_file, save_item}}, keys::SigningKey};
+
+    #[test]
+    fn export_import() {
+        let source = TestDb::new();
+        let mut backend = source.open();
+        let key = SigningKey::generate().unwrap();
+        let user = key.user_id();
+        add_server_user(backend.as_ref(), user);
+
+        let contents = b"Hello, world!";
+        let (item, hash) = post_with_file(1000, "hello.txt", contents);
+        let with_file = save_item(backend.as_mut(), &key, &item);
+        backend.save_attachment(contents.len() as u64, &hash, &mut &contents[..]).unwrap();
+        // A second item listing the same file, which should only be archived once:
+        let (item, _) = post_with_file(2000, "again.txt", contents);
+        let again = save_item(backend.as_mut(), &key, &item);
+        // ... and one whose file we don't have:
+        let (item, _) = post_with_file(3000, "missing.txt", b"Not uploaded");
+        save_item(backend.as_mut(), &key, &item);
+        save_item(backend.as_mut(), &key, &post(4000, "No files"));
+
+        let mut archive = vec![];
+        let exported = super::export(backend.as_mut(), user, &mut archive).unwrap();
+        assert_eq!(exported.items, 4);
+        assert_eq!(exported.files, 1);
+        assert_eq!(exported.file_bytes, contents.len() as u64);
+        assert_eq!(exported.missing_files, 1);
+
+        let dest = TestDb::new();
+        let mut backend = dest.open();
+        add_server_user(backend.as_ref(), user);
+        let imported = super::import(backend.as_mut(), archive.as_slice()).unwrap();
+        assert_eq!(imported.items, 4);
+        assert_eq!(imported.files, 1);
+
+        // Both attachments share the same contents:
+        let meta = backend.get_attachment_meta(user, &with_file, "hello.txt").unwrap().unwrap();
+        assert!(meta.exists);
+        let meta = backend.get_attachment_meta(user, &again, "again.txt").unwrap().unwrap();
+        assert!(meta.exists);
+        let mut copied = vec![];
+        assert!(backend.copy_attachment(&hash, &mut copied).unwrap());
+        assert_eq!(copied, contents);
+
+        // Importing again changes nothing:
+        let imported = super::import(backend.as_mut(), archive.as_slice()).unwrap();
+        assert_eq!(imported.items, 0);
+        assert_eq!(imported.existing_items, 4);
+    }
+}
@@ -2,14 +2,14 @@
 
 pub(crate) mod sqlite;
 
-use crate::protos::Item;
+use crate::protos::{Item, ItemType};
 use core::str::FromStr;
-use std::{fmt::Display, io::{Read, Seek, SeekFrom}, marker::PhantomData};
+use std::{fmt::Display, io::{Read, Seek, SeekFrom, Write}, marker::PhantomData, path::Path};
 use actix_web::{web::Bytes};
 use anyhow::{Error, Context, bail, format_err};
 use bs58;
 use futures::Stream;
-use serde::{Deserialize, de::{self, Visitor}};
+use serde::{Deserialize, Serialize, de::{self, Visitor}};
 use sizedisplay::SizeDisplay;
 use sodiumoxide::crypto::{hash::sha512, sign};
 
@@ -29,7 +29,56 @@ pub trait FactoryBuilder {
 
     /// Upgrade the database to the currently supported version.
     fn db_upgrade(&self) -> Result<(), Error>;
+
+    /// Run the upgrade against a temporary copy of the database, leaving the
+    /// original untouched.
+    fn db_upgrade_dry_run(&self) -> Result<(), Error>;
+
+    /// The database's version, and the upgrades it needs.
+    fn db_status(&self) -> Result<DbStatus, Error>;
+
+    /// Copy the database to a new file at `out`. (See: Backend::backup())
+    /// Works even if the database needs an upgrade.
+    fn db_backup(&self, out: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<(), Error>;
+}
+
+#[derive(Serialize)]
+pub struct DbStatus {
+    /// The database's schema version.
+    pub version: u32,
+
+    /// The version this build of FeoBlog uses.
+    pub current_version: u32,
+
+    /// Upgrades needed to reach `current_version`.
+    pub pending: Vec<UpgradeStep>,
+}
+
+#[derive(Serialize)]
+pub struct UpgradeStep {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub description: String,
 }
+
+impl Display for DbStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Database version: {}", self.version)?;
+        writeln!(f, "Current version:  {}", self.current_version)?;
+        if self.version > self.current_version {
+            writeln!(f, "This database is from a newer version of FeoBlog.")?;
+        } else if self.pending.is_empty() {
+            writeln!(f, "The database is up to date.")?;
+        } else {
+            writeln!(f, "Pending upgrades: (Run `feoblog db upgrade`)")?;
+            for step in &self.pending {
+                writeln!(f, "  {} -> {}: {}", step.from_version, step.to_version, step.description)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Knows how to open Backend "connections".
 pub trait Factory: Send + Sync
 {
@@ -40,6 +89,18 @@ pub trait Factory: Send + Sync
     /// Open a single Backend connection.
     /// It is recommended that Factory implementions use their own connection pooling.
     fn open(&self) -> Result<Box<dyn Backend>, Error>;
+
+    /// Report on the state of the connection pool.
+    fn pool_state(&self) -> PoolState;
+}
+
+/// A snapshot of a Factory's connection pool.
+pub struct PoolState {
+    /// Connections currently open. (idle or in use)
+    pub connections: u32,
+    pub idle_connections: u32,
+    /// The most connections the pool will open.
+    pub max_size: u32,
 }
 
 /// Dumb hack to make dyn Factory impl Cloneable
@@ -71,6 +132,7 @@ pub trait Backend
     fn homepage_items<'a>(
         &self, 
         time_span: TimeSpan,
+        item_types: &ItemTypes,
         callback: &'a mut dyn FnMut(ItemDisplayRow) -> Result<bool,Error>
     ) -> Result<(), Error>;
 
@@ -79,6 +141,7 @@ pub trait Backend
         &self,
         user: &UserID,
         time_span: TimeSpan,
+        item_types: &ItemTypes,
         callback: RowCallback<'a, ItemRow>,
     ) -> Result<(), Error>;
 
@@ -88,6 +151,7 @@ pub trait Backend
         user: &UserID,
         signature: &Signature,
         before: Timestamp,
+        item_types: &ItemTypes,
         callback: RowCallback<'a, ItemRow>,
     ) -> Result<(), Error>;
 
@@ -96,15 +160,60 @@ pub trait Backend
         &self,
         user_id: &UserID,
         time_span: TimeSpan,
+        item_types: &ItemTypes,
         callback: RowCallback<'a, ItemDisplayRow>,
     ) -> Result<(), Error>;
 
+    /// List the timestamps & signatures of a user's items in `[start, end)`.
+    /// Results are in no particular order.
+    fn user_item_signatures<'a>(
+        &self,
+        user: &UserID,
+        start: Timestamp,
+        end: Timestamp,
+        callback: RowCallback<'a, (Timestamp, Signature)>,
+    ) -> Result<(), Error>;
+
+    /// Find items from known users in the order this server saved them, with
+    /// their change sequence numbers.
+    ///
+    /// Only lists items whose sequence number is greater than `after`. Items
+    /// saved later always get greater numbers, so a caller that passes the last
+    /// number it saw won't skip any.
+    fn changes_after<'a>(
+        &self,
+        after: i64,
+        callback: RowCallback<'a, (i64, ItemRow)>,
+    ) -> Result<(), Error>;
+
     /// Find one particular UserItem
     fn user_item(&self, user: &UserID, signature: &Signature) -> Result<Option<ItemRow>, Error>;
 
+    /// Find many items at once, by their (user, signature).
+    /// Only returns items that exist, in no particular order.
+    fn user_items_by_id<'a>(
+        &self,
+        ids: &[(UserID, Signature)],
+        callback: RowCallback<'a, ItemRow>,
+    ) -> Result<(), Error>;
+
+    /// Every item in the database, from all users, in no particular order.
+    fn all_items<'a>(&self, callback: RowCallback<'a, ItemRow>) -> Result<(), Error>;
+
     /// Effieicntly check whether a user item exists:
     fn user_item_exists(&self, user: &UserID, signature: &Signature) -> Result<bool, Error>;
 
+    /// Check which of many items exist. Calls `callback` for each one that does.
+    fn user_items_exist<'a>(&self, ids: &[(UserID, Signature)], callback: RowCallback<'a, (UserID, Signature)>) -> Result<(), Error>;
+
+    /// Run `f` in a single transaction.
+    /// Commits if `f` returns Ok, otherwise rolls back.
+    fn transaction(&mut self, f: &mut dyn FnMut(&mut dyn Backend) -> Result<(), Error>) -> Result<(), Error>;
+
+    /// Run `f` in a single read transaction, so that all of its reads see the
+    /// same snapshot of the database. Unlike transaction(), doesn't block writers.
+    fn read_transaction(&mut self, f: &mut dyn FnMut(&dyn Backend) -> Result<(), Error>) -> Result<(), Error>;
+
     /// Save an uploaded item to the data store.
     fn save_user_item(&mut self, item_row: &ItemRow, item: &Item) -> Result<(), Error>;
 
@@ -118,6 +227,9 @@ pub trait Backend
     /// Add a new "server user" who is explicitly allowed to post to this server.
     fn add_server_user(&self, server_user: &ServerUser) -> Result<(), Error>;
 
+    /// Update an existing server user's notes, homepage setting, and role.
+    fn update_server_user(&self, server_user: &ServerUser) -> Result<(), Error>;
+
     /// Get the Item(Row) that represents the user's most recently saved profile, if it exists.
     fn user_profile(&self, user_id: &UserID) -> Result<Option<ItemRow>, Error>;
 
@@ -125,7 +237,8 @@ pub trait Backend
     ///
     /// This is true if any of these are true:
     /// * The user is a "server user" (given direct permission to post to this server)
-    /// * The user is followed by a "server user". (We want their content so we can create a feed.)
+    /// * The user is followed by a "server user" whose Role can post.
+    ///   (We want their content so we can create a feed.)
     fn user_known(&self, user_id: &UserID) -> Result<bool, Error>;
 
     /// Check whether a user has remaiing quota/permissions to upload a particular item.
@@ -137,16 +250,50 @@ pub trait Backend
 
     fn get_attachment_meta(&self, user_id: &UserID, signature: &Signature, file_name: &str) -> Result<Option<FileMeta>, Error>;
 
+    /// Find the FileMeta for many attachments at once, by their (user, signature, file name).
+    /// Only returns attachments that exist, in no particular order.
+    fn attachments_meta<'a>(
+        &self,
+        ids: &[(UserID, Signature, String)],
+        callback: RowCallback<'a, ((UserID, Signature, String), FileMeta)>,
+    ) -> Result<(), Error>;
+
     /// Save a file attachment to our content store.
     /// This assumes you have already validated the content's size and hash match those returned by get_attachment_meta().
     fn save_attachment(&self, size: u64, hash: &SHA512, file: &mut dyn Read) -> Result<(), Error>;
 
+    /// Write the contents of a stored file attachment to `out`.
+    /// Returns false if we don't have the contents.
+    fn copy_attachment(&self, hash: &SHA512, out: &mut dyn Write) -> Result<bool, Error>;
+
+    /// Call `f` with the size and contents of a stored file attachment.
+    /// Returns false if we don't have the contents.
+    fn read_attachment(&self, hash: &SHA512, f: &mut dyn FnMut(u64, &mut dyn Read) -> Result<(), Error>) -> Result<bool, Error>;
+
     /// Report on database size usage by user.
     /// Results sorted by total size desc. 
     fn usage_by_user(&self, callback: RowCallback<'_, UsageByUserRow>) -> Result<(), Error>;
 
+    /// The total size of the database, in bytes.
+    fn db_size(&self) -> Result<u64, Error>;
+
+    /// Database-wide totals, counted like `usage_by_user()`.
+    fn usage_totals(&self) -> Result<UsageTotals, Error>;
+
     /// Remove unused data from the database.
     fn prune(&self, opts: PruneOpts) -> Result<PruneResult, Error>;
+
+    /// Run database maintenance tasks. (See: MaintainOpts)
+    fn maintain(&self, opts: MaintainOpts) -> Result<MaintainResult, Error>;
+
+    /// Copy a consistent snapshot of the database to a new file at `out`,
+    /// while allowing other connections to keep using it.
+    fn backup(&self, out: &Path, progress: &mut dyn FnMut(BackupProgress)) -> Result<(), Error>;
+
+    /// Check stored items & files against their signatures & hashes, and
+    /// derived data against the items it came from.
+    /// Each problem found is passed to `problems`.
+    fn verify(&mut self, opts: VerifyOpts, problems: RowCallback<'_, VerifyProblem>) -> Result<VerifyResult, Error>;
 }
 
 pub struct FileStream {
@@ -168,7 +315,8 @@ pub struct FileMeta {
     /// Size of the file in bytes, according to its metadata.
     pub size: u64,
 
-    /// True iff uploading this attachment would cause the user to exceed their quota.
+    /// True iff uploading this attachment would cause the user to exceed their quota,
+    /// or they may not upload at all. (See: Backend::quota_check_item())
     pub quota_exceeded: bool,
 }
 
@@ -284,6 +432,23 @@ impl <'de> Deserialize<'de> for Signature {
     }
 }
 
+impl <'de> Deserialize<'de> for ItemTypes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: serde::Deserializer<'de> 
+    {
+        deserializer.deserialize_str(FromStrVisitor::<Self>::new())
+    }
+}
+
+/// Serialized as base58, like in URLs.
+impl Serialize for UserID {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer
+    {
+        serializer.serialize_str(&self.to_base58())
+    }
+}
+
 impl <'de> Deserialize<'de> for UserID {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where D: serde::Deserializer<'de> 
@@ -354,11 +519,81 @@ pub struct ItemDisplayRow {
 
 /// Info about users explicitly allowed on this server.
 /// i.e.: A row in the server_user table.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ServerUser {
     pub user: UserID,
     pub notes: String,
     pub on_homepage: bool,
+    pub role: Role,
+}
+
+/// What a server user may do on this server.
+///
+/// Any role may also be featured on the homepage. (See: `ServerUser.on_homepage`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Runs the server. May post, and the server also hosts the users they follow.
+    Admin,
+
+    /// May post their own items. Unlike an admin's, the users they follow
+    /// aren't hosted here.
+    Poster,
+
+    /// A read-only copy of this user's content.
+    /// The server serves items that an admin has imported (ex: `user import`
+    /// or `db merge`), but refuses uploads, and ignores the user's follows.
+    Mirror,
+}
+
+impl Role {
+    /// May this user upload items?
+    pub fn can_post(self) -> bool {
+        match self {
+            Self::Admin | Self::Poster => true,
+            Self::Mirror => false,
+        }
+    }
+
+    /// Do the users this user follows count as known users? (See: Backend::user_known())
+    pub fn hosts_follows(self) -> bool {
+        match self {
+            Self::Admin => true,
+            Self::Poster | Self::Mirror => false,
+        }
+    }
+
+    /// The value stored in the database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Poster => "poster",
+            Self::Mirror => "mirror",
+        }
+    }
+}
+
+impl Default for Role {
+    // Server users could always post, and have their follows hosted here.
+    fn default() -> Self { Self::Admin }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "admin" => Self::Admin,
+            "poster" => Self::Poster,
+            "mirror" => Self::Mirror,
+            _ => bail!("Unknown role {:?}. Expected admin, poster, or mirror.", value),
+        })
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -404,6 +639,9 @@ pub enum QuotaDenyReason {
 
     /// We already have a profile that proves that this userID has been revoked.
     ProfileRevoked,
+
+    /// This user's content is a read-only mirror on this server. (See: Role::Mirror)
+    ReadOnly,
 }
 
 impl std::fmt::Display for QuotaDenyReason {
@@ -415,6 +653,8 @@ impl std::fmt::Display for QuotaDenyReason {
                 write!(f, "This user is not known to the server."),
             Self::ProfileRevoked => 
                 write!(f, "This user ID has been revoked."),
+            Self::ReadOnly => 
+                write!(f, "This user's content is read-only on this server."),
         }
     }
 }
@@ -494,6 +734,62 @@ impl TimeSpan {
     }
 }
 
+/// Limits which types of Items are returned when listing items.
+#[derive(Debug, Clone)]
+pub struct ItemTypes {
+    /// None means that all item types are allowed.
+    types: Option<Vec<ItemType>>,
+}
+
+impl ItemTypes {
+    /// Allow all item types.
+    pub fn all() -> Self {
+        Self { types: None }
+    }
+
+    /// Allow only the listed item types.
+    pub fn only(types: Vec<ItemType>) -> Self {
+        Self { types: Some(types) }
+    }
+
+    /// The allowed item types, or None if all types are allowed.
+    pub fn types(&self) -> Option<&[ItemType]> {
+        self.types.as_ref().map(|t| t.as_slice())
+    }
+
+    pub fn allows(&self, item_type: ItemType) -> bool {
+        match &self.types {
+            None => true,
+            Some(types) => types.contains(&item_type),
+        }
+    }
+}
+
+impl Default for ItemTypes {
+    fn default() -> Self { Self::all() }
+}
+
+/// Parses a comma-separated list of item types. ex: "post,comment"
+impl FromStr for ItemTypes {
+    type Err = anyhow::Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut types = vec![];
+        for name in value.split(",") {
+            let item_type = match name.trim().to_lowercase().as_str() {
+                "post" => ItemType::POST,
+                "profile" => ItemType::PROFILE,
+                "comment" => ItemType::COMMENT,
+                other => bail!("Unknown item type: {:?}", other),
+            };
+            if !types.contains(&item_type) {
+                types.push(item_type);
+            }
+        }
+
+        Ok(Self::only(types))
+    }
+}
+
 pub struct UsageByUserOpts {
 
 }
@@ -511,10 +807,42 @@ pub struct PruneOpts {
 
     /// Delete items from users who are no longer followed?
     pub items: bool,
+
+    /// Delete followed users' items that these rules don't keep.
+    pub retention: Option<RetentionRules>,
+
+    /// Rebuild the database file afterward to reclaim the space. (VACUUM)
+    pub vacuum: bool,
+}
+
+/// Which of a followed user's items to keep when pruning.
+///
+/// An item is kept if any rule keeps it. Server users' items, and each user's
+/// latest profile, are always kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionRules {
+    /// Keep items from the last this-many days.
+    pub keep_days: Option<u64>,
+
+    /// Keep each user's newest this-many items.
+    pub keep_items: Option<u64>,
+}
+
+impl RetentionRules {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.keep_days.is_none() && self.keep_items.is_none() {
+            bail!("Retention rules must set keep_days and/or keep_items");
+        }
+        if self.keep_days == Some(0) || self.keep_items == Some(0) {
+            bail!("Retention rules must keep at least 1 day or 1 item");
+        }
+        Ok(())
+    }
 }
 
 
 /// Report how many things would be deleted, and their size.
+#[derive(Serialize)]
 pub struct PruneResult {
     /// Was this a dry run?
     pub dry_run: bool,
@@ -524,6 +852,39 @@ pub struct PruneResult {
 
     pub items_count: u64,
     pub items_bytes: u64,
+
+    /// Items pruned, by user. Sorted by size desc.
+    pub users: Vec<PruneUserRow>,
+}
+
+#[derive(Serialize)]
+pub struct PruneUserRow {
+    pub user_id: UserID,
+    pub display_name: Option<String>,
+    pub reason: PruneReason,
+
+    pub items_count: u64,
+    pub items_bytes: u64,
+}
+
+/// Why a user's items were pruned.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PruneReason {
+    /// Nobody on the server follows the user.
+    Unfollowed,
+
+    /// A followed user's items that RetentionRules didn't keep.
+    Retention,
+}
+
+impl Display for PruneReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unfollowed => write!(f, "unfollowed"),
+            Self::Retention => write!(f, "retention"),
+        }
+    }
 }
 
 impl Display for PruneResult {
@@ -554,17 +915,188 @@ impl Display for PruneResult {
         stream.row(Row{
             name: "Items",
             count: self.items_count,
-            size: SizeDisplay::bytes(self.items_count)
+            size: SizeDisplay::bytes(self.items_bytes),
         }).map_err(|e| std::fmt::Error)?;
 
         let footer = format!("Total size: {}", SizeDisplay::bytes(self.items_bytes + self.attachments_bytes));
         stream.footer(&footer).map_err(|e| std::fmt::Error)?;
 
+        if !self.users.is_empty() {
+            let mut stream = Stream::new(&mut out, vec![
+                col!(UserRow: .user_id).header("User ID"),
+                col!(UserRow: .display_name).header("Display Name"),
+                col!(UserRow: .reason).header("Reason"),
+                col!(UserRow: .count).header("Items").right(),
+                col!(UserRow: .size).header("Size").right(),
+            ]).title("By user:");
+
+            struct UserRow {
+                user_id: String,
+                display_name: String,
+                reason: PruneReason,
+                count: u64,
+                size: SizeDisplay,
+            }
+
+            for row in &self.users {
+                stream.row(UserRow{
+                    user_id: row.user_id.to_base58(),
+                    display_name: row.display_name.clone().unwrap_or_default(),
+                    reason: row.reason,
+                    count: row.items_count,
+                    size: SizeDisplay::bytes(row.items_bytes).short(),
+                }).map_err(|e| std::fmt::Error)?;
+            }
+            stream.finish().map_err(|e| std::fmt::Error)?;
+        }
+
         write!(f, "{}", String::from_utf8_lossy(&out))
     }
 }
 
+pub struct MaintainOpts {
+    /// Update statistics that the query planner uses. (ANALYZE)
+    pub analyze: bool,
+
+    /// A lighter version of `analyze`, which only updates statistics that
+    /// look out of date.
+    pub optimize: bool,
+
+    /// Rebuild the whole database file to reclaim unused space.
+    pub vacuum: bool,
+
+    /// Reclaim unused space without rebuilding the whole file.
+    /// The first time, this switches the database to incremental vacuuming,
+    /// which requires a full vacuum.
+    pub incremental_vacuum: bool,
+
+    /// Check the database file for corruption.
+    pub integrity_check: bool,
+
+    /// Move the write-ahead log into the main database file, and truncate it.
+    pub checkpoint: bool,
+}
+
+impl MaintainOpts {
+    /// Quick maintenance which is safe to run while the server is busy.
+    pub fn light() -> Self {
+        Self {
+            analyze: false,
+            optimize: true,
+            vacuum: false,
+            incremental_vacuum: false,
+            integrity_check: false,
+            checkpoint: true,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MaintainResult {
+    pub size_before: DbFileSizes,
+    pub size_after: DbFileSizes,
+
+    /// Problems found by `integrity_check`. None if it wasn't run.
+    pub integrity_problems: Option<Vec<String>>,
+
+    /// The checkpoint couldn't finish because another connection was using the database.
+    pub checkpoint_busy: bool,
+}
+
+/// Sizes (in bytes) of the files that make up a database.
+#[derive(Serialize)]
+pub struct DbFileSizes {
+    pub database: u64,
+    /// The write-ahead log, if any.
+    pub wal: u64,
+}
+
+impl Display for MaintainResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (before, after) = (&self.size_before, &self.size_after);
+        writeln!(f, "Database: {} -> {}", SizeDisplay::bytes(before.database), SizeDisplay::bytes(after.database))?;
+        writeln!(f, "WAL:      {} -> {}", SizeDisplay::bytes(before.wal), SizeDisplay::bytes(after.wal))?;
+        if self.checkpoint_busy {
+            writeln!(f, "The database was busy, so the WAL checkpoint didn't finish.")?;
+        }
+        match &self.integrity_problems {
+            None => {},
+            Some(problems) if problems.is_empty() => writeln!(f, "Integrity check: ok")?,
+            Some(problems) => {
+                writeln!(f, "Integrity check found {} problems:", problems.len())?;
+                for problem in problems {
+                    writeln!(f, "  {}", problem)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// How far along a backup is.
+#[derive(Clone, Copy)]
+pub struct BackupProgress {
+    pub copied_pages: u64,
+    pub total_pages: u64,
+}
+
+pub struct VerifyOpts {
+    /// Rebuild derived data that doesn't match its items.
+    pub repair: bool,
+}
+
+/// A problem found by `Backend::verify()`.
+#[derive(Serialize)]
+pub struct VerifyProblem {
+    /// Where the problem is. ex: "/u/{userID}/i/{signature}"
+    pub location: String,
+    pub problem: String,
+    /// Can `VerifyOpts.repair` fix it?
+    pub repairable: bool,
+}
+
+#[derive(Serialize)]
+pub struct VerifyResult {
+    /// Did we repair the repairable problems?
+    pub repaired: bool,
+
+    pub items: usize,
+    pub files: usize,
+    pub problems: usize,
+    pub repairable: usize,
+}
+
+impl Display for VerifyResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Checked {} items and {} files. Found {} problems.", self.items, self.files, self.problems)?;
+        if self.repairable > 0 {
+            if self.repaired {
+                writeln!(f, "Repaired {} problems.", self.repairable)?;
+            } else {
+                writeln!(f, "{} problems can be fixed with --repair.", self.repairable)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Database usage for all users. (See: Backend::usage_totals())
+/// Unlike summing `UsageByUserRow`s, counts a file that several users attach only once.
+#[derive(Default)]
+pub struct UsageTotals {
+    pub users: u64,
+    pub known_users: u64,
+    pub server_users: u64,
+
+    pub items_count: u64,
+    pub items_bytes: u64,
+
+    pub attachments_count: u64,
+    pub attachments_bytes: u64,
+}
+
 /// Information about a single user's database usage.
+#[derive(Serialize)]
 pub struct UsageByUserRow {
     pub user_id: UserID,
     pub display_name: Option<String>,
@@ -5,17 +5,21 @@
 //! Mostly, this makes data management trivial since it's all in one file.
 //! But if performance is an issue we can implement a different backend.
 
-// TODO: Consider running ANALYZE: https://www.sqlite.org/lang_analyze.html -- results in better default query plans.
+// See `feoblog db maintain` for ANALYZE, VACUUM, and friends.
 
 mod upgraders;
+mod verify;
 
-use std::{io::{Read, Write}, ops::DerefMut, path::Path, collections::HashMap};
+#[cfg(test)]
+pub(crate) mod tests;
+
+use std::{io::{Read, Write}, ops::DerefMut, path::Path, collections::HashMap, time::Duration};
 
 use crate::{backend::UsageByUserRow, protos::Item, util::AsHex};
 use actix_web::web::Bytes;
 use backend::{FileMeta, RowCallback, SHA512};
 use futures::Stream;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{DatabaseName, NO_PARAMS, OpenFlags, named_params};
 use sodiumoxide::randombytes::randombytes;
@@ -24,9 +28,62 @@ use crate::backend::{self, UserID, Signature, ItemRow, ItemDisplayRow, Timestamp
 use anyhow::{Error, bail, Context};
 use rusqlite::{params, OptionalExtension, Row};
 
-use super::{FileStream, PruneResult, TimeSpan};
-
-const CURRENT_VERSION: u32 = 7;
+use super::{FileStream, ItemTypes, PruneResult, TimeSpan};
+
+const CURRENT_VERSION: u32 = 10;
+
+/// How many (user_id, signature) pairs to look up per query.
+/// Stays well under SQLite's limit on the number of query parameters.
+const ID_CHUNK_SIZE: usize = 400;
+
+/// Outside of WAL mode, backups copy this many pages at a time, then let other
+/// connections at the database. (See: backup())
+const BACKUP_PAGES_PER_STEP: i32 = 1024;
+const BACKUP_PAUSE: Duration = Duration::from_millis(10);
+
+/// Values for `temp.prune_item.reason`. (See: backend::PruneReason)
+const PRUNE_UNFOLLOWED: i64 = 1;
+const PRUNE_RETENTION: i64 = 2;
+
+/// Which `store AS s` rows `prune()` deletes: files that no item references, or
+/// that only the items being pruned reference.
+/// Uploads in progress are stored under a shorter, temporary hash. Never touch those.
+// NOTE: Can't just do a simple LEFT OUTER JOIN and check for NULL.
+// That could lead to false positives when one ref is dangling but another exists.
+const PRUNE_STORE_WHERE: &str = "
+    LENGTH(s.hash) = 64
+    AND NOT EXISTS (
+        SELECT 1
+        FROM item_attachment AS ia
+        INNER JOIN item USING (user_id, signature)
+        WHERE hash = s.hash
+        AND NOT EXISTS (
+            SELECT 1
+            FROM temp.prune_item
+            WHERE user_id = ia.user_id
+            AND signature = ia.signature
+        )
+    )
+";
+
+/// Each user's items, for `usage_by_user()` and `usage_totals()`.
+const USAGE_USER_ITEMS: &str = "
+    SELECT
+        user_id,
+        p.display_name,
+        COUNT(*) as item_count,
+        SUM(length(bytes)) as item_size
+    FROM item AS i
+    LEFT OUTER JOIN profile AS p USING (user_id)
+    GROUP BY user_id
+";
+
+/// The files attached to each user's items, for `usage_by_user()` and `usage_totals()`.
+const USAGE_USER_HASHES: &str = "
+    SELECT DISTINCT user_id, hash
+    FROM item
+    INNER JOIN item_attachment USING (user_id, signature)
+";
 
 type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 type PConn = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
@@ -87,6 +144,66 @@ impl backend::FactoryBuilder for FactoryBuilder {
         Ok(())
     }
 
+    fn db_upgrade_dry_run(&self) -> Result<(), Error> {
+        if !self.db_exists()? {
+            bail!("No such database file: {}", self.sqlite_file)
+        }
+
+        // Keep the copy next to the original, since /tmp may be too small:
+        let dir = match Path::new(&self.sqlite_file).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let temp = tempfile::Builder::new()
+            .prefix(".feoblog-upgrade-")
+            .suffix(".sqlite3")
+            .tempfile_in(dir)
+            .with_context(|| format!("Error creating temp file in {}", dir.display()))?;
+        let temp_path = temp.path().to_string_lossy().to_string();
+
+        println!("Copying database to {} ...", temp_path);
+        {
+            let conn = self.connection()?;
+            backup(&conn.conn, temp.path(), &mut |_| {})?;
+        }
+
+        let result = FactoryBuilder::new(temp_path.clone()).db_upgrade();
+
+        // The copy may have been put in WAL mode:
+        for suffix in &["-wal", "-shm", "-journal"] {
+            let _ = std::fs::remove_file(format!("{}{}", temp_path, suffix));
+        }
+
+        result.context("Dry run failed. Your database has not been changed")?;
+        println!("Dry run succeeded. Your database has not been changed.");
+        Ok(())
+    }
+
+    fn db_status(&self) -> Result<backend::DbStatus, Error> {
+        if !self.db_exists()? {
+            bail!("No such database file: {}", self.sqlite_file)
+        }
+        let version = self.connection()?.get_version()?;
+        let pending = if version > CURRENT_VERSION {
+            vec![]
+        } else {
+            upgraders::Upgraders::new().pending(version)?
+        };
+        Ok(backend::DbStatus {
+            version,
+            current_version: CURRENT_VERSION,
+            pending,
+        })
+    }
+
+    fn db_backup(&self, out: &Path, progress: &mut dyn FnMut(backend::BackupProgress)) -> Result<(), Error> {
+        if !self.db_exists()? {
+            bail!("No such database file: {}", self.sqlite_file)
+        }
+        let conn = self.connection()?;
+        backup(&conn.conn, out, progress)
+    }
+
     fn db_create(&self) -> Result<(), Error> {
         if self.db_exists()? {
             bail!("Database already exists")
@@ -199,6 +316,15 @@ impl backend::Factory for Factory
         };
         Box::new(new_factory)
     }
+
+    fn pool_state(&self) -> backend::PoolState {
+        let state = self.pool.state();
+        backend::PoolState {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_size: self.pool.max_size(),
+        }
+    }
 }
 
 
@@ -343,6 +469,187 @@ impl Connection
         Ok(())
     }
 
+    /// The body of `prune()`, once `temp.prune_item` exists.
+    fn prune_items(&self, opts: &backend::PruneOpts) -> Result<PruneResult, Error> {
+        let mut result = PruneResult{
+            dry_run: opts.dry_run,
+            attachments_bytes: 0,
+            attachments_count: 0,
+            items_bytes: 0,
+            items_count: 0,
+            users: vec![],
+        };
+
+        if opts.items {
+            self.conn.execute("
+                INSERT INTO temp.prune_item (user_id, signature, reason)
+                SELECT user_id, signature, ?
+                FROM item AS i
+                WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM known_users
+                    WHERE user_id = i.user_id
+                )
+            ", params![PRUNE_UNFOLLOWED])?;
+        }
+
+        if let Some(rules) = &opts.retention {
+            rules.validate()?;
+            let keep_after = match rules.keep_days {
+                None => i64::MAX,
+                Some(days) => Timestamp::now().unix_utc_ms - (days as i64) * 24 * 60 * 60 * 1000,
+            };
+            let keep_items = rules.keep_items.unwrap_or(0) as i64;
+
+            self.conn.execute_named("
+                INSERT INTO temp.prune_item (user_id, signature, reason)
+                SELECT user_id, signature, :reason
+                FROM (
+                    SELECT
+                        user_id,
+                        signature,
+                        unix_utc_ms,
+                        ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY unix_utc_ms DESC) AS newest
+                    FROM item AS i
+                    WHERE EXISTS (SELECT 1 FROM known_users WHERE user_id = i.user_id)
+                    AND NOT EXISTS (SELECT 1 FROM server_user WHERE user_id = i.user_id)
+                ) AS i
+                WHERE unix_utc_ms < :keep_after
+                AND newest > :keep_items
+                -- Always keep the latest profile:
+                AND NOT EXISTS (
+                    SELECT 1
+                    FROM profile
+                    WHERE user_id = i.user_id
+                    AND signature = i.signature
+                )
+            ", named_params!{
+                ":reason": PRUNE_RETENTION,
+                ":keep_after": keep_after,
+                ":keep_items": keep_items,
+            })?;
+        }
+
+        {
+            let mut stmt = self.conn.prepare("
+                SELECT
+                    p.user_id AS user_id,
+                    pr.display_name AS display_name,
+                    p.reason AS reason,
+                    COUNT(*) AS `count`,
+                    SUM(LENGTH(i.bytes) + LENGTH(i.user_id) + LENGTH(i.signature)) AS size
+                FROM temp.prune_item AS p
+                INNER JOIN item AS i USING (user_id, signature)
+                LEFT OUTER JOIN profile AS pr USING (user_id)
+                GROUP BY p.user_id, p.reason
+                ORDER BY size DESC
+            ")?;
+            let mut rows = stmt.query(params![])?;
+            while let Some(row) = rows.next()? {
+                let reason = match row.get::<_, i64>("reason")? {
+                    PRUNE_UNFOLLOWED => backend::PruneReason::Unfollowed,
+                    _ => backend::PruneReason::Retention,
+                };
+                let user = backend::PruneUserRow{
+                    user_id: UserID::from_vec(row.get("user_id")?)?,
+                    display_name: row.get("display_name")?,
+                    reason,
+                    items_count: row.get::<_, i64>("count")? as u64,
+                    items_bytes: row.get::<_, i64>("size")? as u64,
+                };
+                result.items_count += user.items_count;
+                result.items_bytes += user.items_bytes;
+                result.users.push(user);
+            }
+        }
+
+        if opts.attachments {
+            // Find attachments that are no longer referenced, or will only be
+            // referenced by items we're deleting.
+            let (count, bytes) = self.conn.query_row(
+                &format!("
+                    SELECT COUNT(*) AS `count`, COALESCE(SUM(LENGTH(contents)), 0) AS size
+                    FROM store AS s
+                    WHERE {}
+                ", PRUNE_STORE_WHERE),
+                params![],
+                |row| Ok((row.get::<usize, i64>(0)? as u64, row.get::<usize,i64>(1)? as u64)),
+            )?;
+            result.attachments_count = count;
+            result.attachments_bytes = bytes;
+        }
+
+        if opts.dry_run {
+            return Ok(result)
+        }
+
+        // Delete everything in one transaction, so that a failure part way
+        // through doesn't leave files behind that no item references.
+        self.run("SAVEPOINT prune")?;
+        if let Err(err) = self.prune_delete(opts.attachments) {
+            if let Err(rollback_err) = self.conn.execute_batch("ROLLBACK TO prune; RELEASE prune") {
+                warn!("Error rolling back prune: {}", rollback_err);
+            }
+            return Err(err.context("pruning the database"));
+        }
+        self.run("RELEASE prune")?;
+
+        Ok(result)
+    }
+
+    /// Would uploading a file for one of this user's items be refused?
+    /// (See: FileMeta.quota_exceeded)
+    fn attachment_quota_exceeded(&self, user_id: &UserID) -> Result<bool, Error> {
+        // Only users who may post items may upload their files. So far, that's the only limit:
+        let denied = backend::Backend::quota_check_item(self, user_id, &[], &Item::new())?;
+        Ok(denied.is_some())
+    }
+
+    /// The delete phase of `prune()`. Must run inside a transaction.
+    fn prune_delete(&self, attachments: bool) -> Result<(), Error> {
+        // Note: Delete items first, so that the store query below sees which files are still used.
+        self.run("
+            DELETE FROM item AS i
+            WHERE EXISTS(
+                SELECT 1
+                FROM temp.prune_item
+                WHERE user_id = i.user_id
+                AND signature = i.signature
+            )
+        ")?;
+
+        // Delete attachments now abandoned:
+        self.run("
+            DELETE FROM item_attachment AS ia
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM item
+                WHERE user_id = ia.user_id
+                AND signature = ia.signature
+            )
+        ")?;
+
+        // ... and replies from deleted comments:
+        self.run("
+            DELETE FROM reply AS r
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM item
+                WHERE user_id = r.from_user_id
+                AND signature = r.from_signature
+            )
+        ")?;
+
+        if attachments {
+            self.run(&format!("
+                DELETE FROM store AS s
+                WHERE {}
+            ", PRUNE_STORE_WHERE))?;
+        }
+
+        Ok(())
+    }
+
     fn get_version(&self) -> Result<u32, Error>
     {
         let table_count: u32  = self.conn.prepare(
@@ -386,7 +693,21 @@ impl Connection
         Ok(())
     }
 
-    fn all_items<'a>(&self, after_uid: &Option<UserID>, after_sig: &Option<Signature>, callback: RowCallback<'a, ItemRow>) -> Result<(), Error>{
+    /// Sizes of the database file and its write-ahead log.
+    fn file_sizes(&self) -> Result<backend::DbFileSizes, Error> {
+        let file: String = self.conn.query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        let size = |path: &str| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        Ok(backend::DbFileSizes {
+            database: size(&file),
+            wal: size(&format!("{}-wal", file)),
+        })
+    }
+
+    fn all_items_after<'a>(&self, after_uid: &Option<UserID>, after_sig: &Option<Signature>, callback: RowCallback<'a, ItemRow>) -> Result<(), Error>{
         let mut stmt;
         let mut rows;
         if let (Some(uid), Some(sig)) = (after_uid, after_sig) {
@@ -443,6 +764,41 @@ impl Connection
 
 }
 
+/// Copy the database to `out` with SQLite's online backup API.
+///
+/// If another connection writes to the database between steps, SQLite restarts
+/// the backup. So in WAL mode, we copy everything in one step. That reads from a
+/// single snapshot, which doesn't block writers (or get restarted by them).
+///
+/// Other journal modes lock out writers while we read, so there we copy a few
+/// pages at a time to give them a chance. (Though a busy database may restart
+/// the backup several times.)
+fn backup(conn: &rusqlite::Connection, out: &Path, progress: &mut dyn FnMut(backend::BackupProgress)) -> Result<(), Error> {
+    use rusqlite::backup::{Backup, StepResult};
+
+    let journal_mode: String = conn.query_row("PRAGMA journal_mode", NO_PARAMS, |row| row.get(0))?;
+    let pages_per_step = if journal_mode.eq_ignore_ascii_case("wal") { -1 } else { BACKUP_PAGES_PER_STEP };
+
+    let mut dest = rusqlite::Connection::open(out)
+        .with_context(|| format!("Error opening {}", out.display()))?;
+    let backup = Backup::new(conn, &mut dest)?;
+    loop {
+        let result = backup.step(pages_per_step)?;
+        let state = backup.progress();
+        progress(backend::BackupProgress {
+            copied_pages: (state.pagecount - state.remaining) as u64,
+            total_pages: state.pagecount as u64,
+        });
+        if let StepResult::Done = result {
+            break;
+        }
+        // (Also gives us time to retry if another connection has the database busy/locked.)
+        std::thread::sleep(BACKUP_PAUSE);
+    }
+
+    Ok(())
+}
+
 /// We're saving a profile. If it's new, update the profile and follow tables.
 fn update_profile(conn: &rusqlite::Savepoint, item_row: &ItemRow, item: &Item) -> Result<(), Error> {
 
@@ -496,6 +852,18 @@ fn update_profile(conn: &rusqlite::Savepoint, item_row: &ItemRow, item: &Item) -
     Ok(())
 }
 
+/// An SQL expression which limits `column` to the allowed item types.
+/// (Item type values are integers, so are safe to format directly into the query.)
+fn item_type_filter(column: &str, item_types: &ItemTypes) -> String {
+    let types = match item_types.types() {
+        None => return "1".into(),
+        Some(types) => types,
+    };
+
+    let values: Vec<String> = types.iter().map(|t| (*t as i32).to_string()).collect();
+    format!("{} IN ({})", column, values.join(", "))
+}
+
 fn save_comment_reply(conn: &rusqlite::Connection, row: &ItemRow, item: &Item) -> Result<(), Error> {
     if !item.has_comment() {
         return Ok(())
@@ -535,6 +903,7 @@ impl backend::Backend for Connection
     fn homepage_items<'a>(
         &self,
         time_span: TimeSpan,
+        item_types: &ItemTypes,
         callback: &'a mut dyn FnMut(ItemDisplayRow) -> Result<bool,Error>
     ) -> Result<(), Error> {
 
@@ -542,7 +911,7 @@ impl backend::Backend for Connection
         let query = match time_span {
             TimeSpan::Before(before) => {
                 params.push(before.unix_utc_ms);
-                "
+                format!("
                     SELECT
                         user_id
                         , i.signature
@@ -553,17 +922,18 @@ impl backend::Backend for Connection
                     FROM item AS i
                     LEFT OUTER JOIN profile AS p USING (user_id)
                     WHERE unix_utc_ms < ?
+                    AND {item_types}
                     AND user_id IN (
                         SELECT user_id
                         FROM server_user
                         WHERE on_homepage = 1
                     )
                     ORDER BY unix_utc_ms DESC, i.signature DESC
-                "
+                ", item_types=item_type_filter("i.item_type", item_types))
             },
             TimeSpan::After(after) => {
                 params.push(after.unix_utc_ms);
-                "
+                format!("
                     SELECT
                         user_id
                         , i.signature
@@ -574,17 +944,18 @@ impl backend::Backend for Connection
                     FROM item AS i
                     LEFT OUTER JOIN profile AS p USING (user_id)
                     WHERE unix_utc_ms > ?
+                    AND {item_types}
                     AND user_id IN (
                         SELECT user_id
                         FROM server_user
                         WHERE on_homepage = 1
                     )
                     ORDER BY unix_utc_ms ASC, i.signature DESC
-                "
+                ", item_types=item_type_filter("i.item_type", item_types))
             },
         };
 
-        let mut stmt = self.conn.prepare(query)?;
+        let mut stmt = self.conn.prepare(&query)?;
         let mut rows = stmt.query(params)?;
 
 
@@ -617,6 +988,7 @@ impl backend::Backend for Connection
         &self,
         user: &UserID,
         time_span: TimeSpan,
+        item_types: &ItemTypes,
         callback: &'a mut dyn FnMut(ItemRow) -> Result<bool,Error>
     ) -> Result<(), Error> {
 
@@ -625,7 +997,7 @@ impl backend::Backend for Connection
             TimeSpan::Before(before) => {
                 params.push(Box::new(before.unix_utc_ms));
                 params.push(Box::new(user.bytes().to_vec()));
-                "
+                format!("
                     SELECT
                         i.user_id
                         , i.signature
@@ -636,14 +1008,15 @@ impl backend::Backend for Connection
                     WHERE
                         unix_utc_ms < ?
                         AND user_id = ?
+                        AND {item_types}
                         AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
                     ORDER BY unix_utc_ms DESC, i.signature DESC
-                "
+                ", item_types=item_type_filter("i.item_type", item_types))
             },
             TimeSpan::After(after) => {
                 params.push(Box::new(after.unix_utc_ms));
                 params.push(Box::new(user.bytes().to_vec()));
-                "
+                format!("
                     SELECT
                         i.user_id
                         , i.signature
@@ -654,13 +1027,14 @@ impl backend::Backend for Connection
                     WHERE
                         unix_utc_ms > ?
                         AND user_id = ?
+                        AND {item_types}
                         AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
                     ORDER BY unix_utc_ms ASC, i.signature ASC
-                "          
+                ", item_types=item_type_filter("i.item_type", item_types))
             }
         };
         
-        let mut stmt = self.conn.prepare(query)?;
+        let mut stmt = self.conn.prepare(&query)?;
         let mut rows = stmt.query(params)?;
 
         let convert = |row: &Row<'_>| -> Result<ItemRow, Error> {
@@ -689,9 +1063,10 @@ impl backend::Backend for Connection
         user: &UserID,
         signature: &Signature,
         before: Timestamp,
+        item_types: &ItemTypes,
         callback: RowCallback<'a, ItemRow>,
     ) -> Result<(), Error> {
-        let mut stmt = self.conn.prepare("
+        let mut stmt = self.conn.prepare(&format!("
             SELECT
                 i.user_id
                 , i.signature
@@ -707,9 +1082,10 @@ impl backend::Backend for Connection
                 unix_utc_ms < ?
                 AND r.to_user_id = ?
                 AND r.to_signature = ?
+                AND {item_types}
                 AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
             ORDER BY unix_utc_ms DESC, i.signature DESC
-        ")?;
+        ", item_types=item_type_filter("i.item_type", item_types)))?;
 
         let mut rows = stmt.query(params![
             before.unix_utc_ms,
@@ -742,6 +1118,7 @@ impl backend::Backend for Connection
         &self,
         user_id: &UserID,
         time_span: TimeSpan,
+        item_types: &ItemTypes,
         callback: RowCallback<'a, ItemDisplayRow>,
     ) -> Result<(), Error> {
 
@@ -794,11 +1171,13 @@ impl backend::Backend for Connection
                         , bytes
                     FROM item
                     WHERE {filter_ts}
+                    AND {item_types}
                 )
                 {subselects}
                 ORDER BY unix_utc_ms {ts_order}, signature {ts_order}
             ", 
             filter_ts=filter_ts,
+            item_types=item_type_filter("item_type", item_types),
             ts_order=ts_order,
             subselects=subselects.join("\n\nUNION ALL\n")
         );
@@ -838,20 +1217,16 @@ impl backend::Backend for Connection
     -> Result<Option<backend::ServerUser>, Error> 
     { 
         let mut stmt = self.conn.prepare("
-            SELECT notes, on_homepage
+            SELECT notes, on_homepage, role
             FROM server_user
             WHERE user_id = ?
         ")?;
 
         let to_server_user = |row: &Row<'_>| {
+            let notes: String = row.get(0)?;
             let on_homepage: isize = row.get(1)?;
-             Ok(
-                 ServerUser {
-                    user: user.clone(),
-                    notes: row.get(0)?,
-                    on_homepage: on_homepage != 0,
-                }
-            )
+            let role: String = row.get(2)?;
+            Ok((notes, on_homepage != 0, role))
         };
 
         let item = stmt.query_row(
@@ -859,7 +1234,17 @@ impl backend::Backend for Connection
             to_server_user,
         ).optional()?;
 
-        Ok(item)
+        let (notes, on_homepage, role) = match item {
+            None => return Ok(None),
+            Some(item) => item,
+        };
+
+        Ok(Some(ServerUser {
+            user: user.clone(),
+            notes,
+            on_homepage,
+            role: role.parse()?,
+        }))
 
     }
 
@@ -869,6 +1254,7 @@ impl backend::Backend for Connection
                 user_id
                 , notes
                 , on_homepage
+                , role
             FROM server_user
             ORDER BY on_homepage, user_id
         ")?;
@@ -879,10 +1265,13 @@ impl backend::Backend for Connection
             let on_homepage: isize = row.get(2)?;
             let on_homepage = on_homepage != 0;
 
+            let role: String = row.get(3)?;
+
             let user = ServerUser {
                 user: UserID::from_vec(row.get(0)?)?,
                 notes: row.get(1)?,
                 on_homepage,
+                role: role.parse()?,
             };
             let more = cb(user)?;
             if !more {break;}
@@ -954,10 +1343,182 @@ impl backend::Backend for Connection
         Ok(Some(item))
     }
 
+    fn user_item_signatures<'a>(
+        &self,
+        user: &UserID,
+        start: Timestamp,
+        end: Timestamp,
+        callback: RowCallback<'a, (Timestamp, Signature)>,
+    ) -> Result<(), Error> {
+        let mut stmt = self.conn.prepare("
+            SELECT unix_utc_ms, signature
+            FROM item AS i
+            WHERE user_id = ?
+            AND unix_utc_ms >= ?
+            AND unix_utc_ms < ?
+            AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
+        ")?;
+
+        let mut rows = stmt.query(params![user.bytes(), start.unix_utc_ms, end.unix_utc_ms])?;
+
+        while let Some(row) = rows.next()? {
+            let timestamp = Timestamp{ unix_utc_ms: row.get(0)? };
+            let signature = Signature::from_vec(row.get(1)?)?;
+            if !callback((timestamp, signature))? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn changes_after<'a>(
+        &self,
+        after: i64,
+        callback: RowCallback<'a, (i64, ItemRow)>,
+    ) -> Result<(), Error> {
+        let mut stmt = self.conn.prepare("
+            SELECT
+                user_id
+                , signature
+                , unix_utc_ms
+                , received_utc_ms
+                , bytes
+                , change_seq
+            FROM item AS i
+            WHERE change_seq > ?
+            AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
+            ORDER BY change_seq ASC
+        ")?;
+
+        let mut rows = stmt.query(params![after])?;
+
+        while let Some(row) = rows.next()? {
+            let item = ItemRow{
+                user: UserID::from_vec(row.get(0)?)?,
+                signature: Signature::from_vec(row.get(1)?)?,
+                timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
+                received: Timestamp{ unix_utc_ms: row.get(3)? },
+                item_bytes: row.get(4)?,
+            };
+            if !callback((row.get(5)?, item))? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn user_items_exist<'a>(
+        &self,
+        ids: &[(UserID, Signature)],
+        callback: RowCallback<'a, (UserID, Signature)>,
+    ) -> Result<(), Error> {
+        for chunk in ids.chunks(ID_CHUNK_SIZE) {
+            let values = vec!["(?, ?)"; chunk.len()].join(", ");
+            let query = format!("
+                SELECT user_id, signature
+                FROM item
+                WHERE (user_id, signature) IN (VALUES {values})
+            ", values=values);
+
+            let mut stmt = self.conn.prepare(&query)?;
+            let params = chunk.iter().flat_map(|(user, sig)| vec![user.bytes(), sig.bytes()]);
+            let mut rows = stmt.query(params)?;
+
+            while let Some(row) = rows.next()? {
+                let id = (
+                    UserID::from_vec(row.get(0)?)?,
+                    Signature::from_vec(row.get(1)?)?,
+                );
+                if !callback(id)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn user_items_by_id<'a>(
+        &self,
+        ids: &[(UserID, Signature)],
+        callback: RowCallback<'a, ItemRow>,
+    ) -> Result<(), Error> {
+        for chunk in ids.chunks(ID_CHUNK_SIZE) {
+            let values = vec!["(?, ?)"; chunk.len()].join(", ");
+            let query = format!("
+                SELECT
+                    user_id
+                    , signature
+                    , unix_utc_ms
+                    , received_utc_ms
+                    , bytes
+                FROM item AS i
+                WHERE (user_id, signature) IN (VALUES {values})
+                AND EXISTS(SELECT user_id FROM known_users WHERE user_id = i.user_id)
+            ", values=values);
+
+            let mut stmt = self.conn.prepare(&query)?;
+            let params = chunk.iter().flat_map(|(user, sig)| vec![user.bytes(), sig.bytes()]);
+            let mut rows = stmt.query(params)?;
+
+            while let Some(row) = rows.next()? {
+                let item = ItemRow{
+                    user: UserID::from_vec(row.get(0)?)?,
+                    signature: Signature::from_vec(row.get(1)?)?,
+                    timestamp: Timestamp{ unix_utc_ms: row.get(2)? },
+                    received: Timestamp{ unix_utc_ms: row.get(3)? },
+                    item_bytes: row.get(4)?,
+                };
+                if !callback(item)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn transaction(&mut self, f: &mut dyn FnMut(&mut dyn backend::Backend) -> Result<(), Error>) -> Result<(), Error> {
+        // Note: save_user_item() uses savepoints, which nest inside this.
+        self.conn.execute_batch("BEGIN IMMEDIATE").context("starting a transaction")?;
+        match f(self) {
+            Ok(()) => {
+                self.conn.execute_batch("COMMIT").context("committing a transaction")?;
+                Ok(())
+            },
+            Err(err) => {
+                if let Err(rollback_err) = self.conn.execute_batch("ROLLBACK") {
+                    warn!("Error rolling back transaction: {}", rollback_err);
+                }
+                Err(err)
+            },
+        }
+    }
+
+    fn read_transaction(&mut self, f: &mut dyn FnMut(&dyn backend::Backend) -> Result<(), Error>) -> Result<(), Error> {
+        // A deferred transaction only takes a read lock. In WAL mode, writers can
+        // continue while we read from our snapshot.
+        self.conn.execute_batch("BEGIN DEFERRED").context("starting a read transaction")?;
+        let result = f(self);
+        if let Err(err) = self.conn.execute_batch("COMMIT") {
+            if result.is_ok() {
+                return Err(err).context("ending a read transaction");
+            }
+            warn!("Error ending read transaction: {}", err);
+        }
+        result
+    }
+
     fn save_user_item(&mut self, row: &ItemRow, item: &Item) -> Result<(), Error>
     {
         let tx = self.conn.savepoint().context("getting a transaction")?;
 
+        // Number items in the order we save them. SQLite only allows one writer
+        // at a time, so this is also the order they're committed in. (See: changes_after())
+        tx.execute("UPDATE change_counter SET seq = seq + 1", params![])?;
+
         let stmt = "
             INSERT INTO item (
                 user_id
@@ -965,7 +1526,9 @@ impl backend::Backend for Connection
                 , unix_utc_ms
                 , received_utc_ms
                 , bytes
-            ) VALUES (?, ?, ?, ?, ?);
+                , item_type
+                , change_seq
+            ) VALUES (?, ?, ?, ?, ?, ?, (SELECT seq FROM change_counter));
        ";
 
         tx.execute(stmt, params![
@@ -974,6 +1537,7 @@ impl backend::Backend for Connection
             row.timestamp.unix_utc_ms,
             row.received.unix_utc_ms,
             row.item_bytes.as_slice(),
+            item.item_type_enum() as i32,
         ])?;
 
         if item.has_profile() {
@@ -993,8 +1557,8 @@ impl backend::Backend for Connection
     fn add_server_user(&self, server_user: &ServerUser) -> Result<(), Error> {
 
         let stmt = "
-            INSERT INTO server_user(user_id, notes, on_homepage)
-            VALUES (?,?,?)
+            INSERT INTO server_user(user_id, notes, on_homepage, role)
+            VALUES (?,?,?,?)
         ";
 
         let on_homepage = if server_user.on_homepage { 1 } else { 0 };
@@ -1002,8 +1566,31 @@ impl backend::Backend for Connection
         self.conn.execute(stmt, params![
             server_user.user.bytes(),
             server_user.notes.as_str(),
-            on_homepage
+            on_homepage,
+            server_user.role.as_str(),
+        ])?;
+
+        Ok(())
+    }
+
+    fn update_server_user(&self, server_user: &ServerUser) -> Result<(), Error> {
+        let stmt = "
+            UPDATE server_user
+            SET notes = ?, on_homepage = ?, role = ?
+            WHERE user_id = ?
+        ";
+
+        let on_homepage = if server_user.on_homepage { 1 } else { 0 };
+
+        let updated = self.conn.execute(stmt, params![
+            server_user.notes.as_str(),
+            on_homepage,
+            server_user.role.as_str(),
+            server_user.user.bytes(),
         ])?;
+        if updated == 0 {
+            bail!("{} is not a server user", server_user.user);
+        }
 
         Ok(())
     }
@@ -1042,6 +1629,8 @@ impl backend::Backend for Connection
                     FROM follow AS f
                     INNER JOIN server_user AS su ON (f.source_user_id = su.user_id)
                     WHERE followed_user_id = :user_id
+                    -- See: Role::hosts_follows()
+                    AND su.role = 'admin'
                 )
         ")?;
 
@@ -1059,7 +1648,10 @@ impl backend::Backend for Connection
 
     fn quota_check_item(&self, user_id: &UserID, _bytes: &[u8], _item: &Item) -> Result<Option<QuotaDenyReason>, Error> {
         
-        if self.server_user(user_id)?.is_some() {
+        if let Some(server_user) = self.server_user(user_id)? {
+            if !server_user.role.can_post() {
+                return Ok(Some(QuotaDenyReason::ReadOnly));
+            }
             // TODO: Implement optional quotas for "server users".
             // For now, there is no quota for them:
             return Ok(None);
@@ -1074,6 +1666,8 @@ impl backend::Backend for Connection
                 INNER JOIN server_user AS su ON su.user_id = f.source_user_id
             WHERE
                 f.followed_user_id = ?
+                -- See: Role::hosts_follows()
+                AND su.role = 'admin'
         ")?;
         let mut rows = statement.query(params![user_id.bytes()])?;
         if rows.next()?.is_some() {
@@ -1205,12 +1799,105 @@ impl backend::Backend for Connection
             exists,
             hash,
             size,
-            quota_exceeded: false, // TODO
+            quota_exceeded: self.attachment_quota_exceeded(user_id)?,
         };
 
         Ok(Some(meta))
     }
 
+    fn attachments_meta<'a>(
+        &self,
+        ids: &[(UserID, Signature, String)],
+        callback: RowCallback<'a, ((UserID, Signature, String), FileMeta)>,
+    ) -> Result<(), Error> {
+        let mut quota_exceeded: HashMap<UserID, bool> = HashMap::new();
+        // Each ID takes 3 parameters, instead of 2:
+        for chunk in ids.chunks(ID_CHUNK_SIZE * 2 / 3) {
+            let values = vec!["(?, ?, ?)"; chunk.len()].join(", ");
+            let query = format!("
+                SELECT
+                    a.user_id
+                    , a.signature
+                    , a.name
+                    , a.size
+                    , a.hash
+                    , s.hash IS NOT NULL AS contents_exist
+                FROM item_attachment AS a
+                LEFT OUTER JOIN store AS s USING (hash)
+                WHERE (a.user_id, a.signature, a.name) IN (VALUES {values})
+                AND EXISTS(SELECT user_id FROM known_users WHERE user_id = a.user_id)
+            ", values=values);
+
+            let mut stmt = self.conn.prepare(&query)?;
+            let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::with_capacity(chunk.len() * 3);
+            for (user, sig, name) in chunk {
+                params.push(Box::new(user.bytes().to_vec()));
+                params.push(Box::new(sig.bytes().to_vec()));
+                params.push(Box::new(name.clone()));
+            }
+            let mut rows = stmt.query(params)?;
+
+            while let Some(row) = rows.next()? {
+                let id = (
+                    UserID::from_vec(row.get(0)?)?,
+                    Signature::from_vec(row.get(1)?)?,
+                    row.get(2)?,
+                );
+                let hash_bytes: Vec<u8> = row.get(4)?;
+                let exceeded = match quota_exceeded.get(&id.0) {
+                    Some(exceeded) => *exceeded,
+                    None => {
+                        let exceeded = self.attachment_quota_exceeded(&id.0)?;
+                        quota_exceeded.insert(id.0.clone(), exceeded);
+                        exceeded
+                    },
+                };
+                let meta = FileMeta{
+                    size: row.get::<_, i64>(3)? as u64,
+                    hash: SHA512::from_hash_bytes(&hash_bytes)?,
+                    exists: row.get(5)?,
+                    quota_exceeded: exceeded,
+                };
+                if !callback((id, meta))? {
+                    return Ok(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn copy_attachment(&self, hash: &SHA512, out: &mut dyn Write) -> Result<bool, Error> {
+        self.read_attachment(hash, &mut |_size, contents| {
+            std::io::copy(contents, out)?;
+            Ok(())
+        })
+    }
+
+    fn read_attachment(&self, hash: &SHA512, f: &mut dyn FnMut(u64, &mut dyn Read) -> Result<(), Error>) -> Result<bool, Error> {
+        let row: Option<(i64, i64)> = self.conn.query_row(
+            "SELECT rowid, length(contents) FROM store WHERE hash = ?",
+            params![ hash.bytes() ],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).optional()?;
+
+        let (row_id, size) = match row {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+
+        let mut blob = self.conn.blob_open(
+            DatabaseName::Main,
+            "store",
+            "contents",
+            row_id,
+            true // read-only
+        )?;
+        f(size as u64, &mut blob)?;
+
+        Ok(true)
+    }
+
     fn save_attachment(&self, size: u64, hash: &SHA512, file: &mut dyn Read) -> Result<(), Error> {
         // Save to a temporary hash while we stream the data into the database.
         // Note, this is 31 bytes, which is easily distinguishable from SHA-512's 64-bytes:
@@ -1274,128 +1961,105 @@ impl backend::Backend for Connection
     }
 
     fn prune(&self, opts: backend::PruneOpts) -> Result<backend::PruneResult, Error> {
-        
-        let mut result = PruneResult{
-            dry_run: opts.dry_run,
-            attachments_bytes: 0,
-            attachments_count: 0,
-            items_bytes: 0,
-            items_count: 0,
-        };
+        // Collect the items we'll delete, so that we can report on them, and
+        // see which attachments they leave unreferenced:
+        self.run("
+            CREATE TEMP TABLE IF NOT EXISTS prune_item(
+                user_id BLOB,
+                signature BLOB,
+                -- See: PRUNE_UNFOLLOWED, PRUNE_RETENTION
+                reason INTEGER,
+                PRIMARY KEY (user_id, signature)
+            )
+        ")?;
+        self.run("DELETE FROM temp.prune_item")?;
 
-        if opts.items {
-            let query = "
-                SELECT 
-                    COUNT(*) AS `count`
-                    , COALESCE(SUM(LENGTH(bytes) + LENGTH(user_id) + LENGTH(signature)), 0) AS size
-                FROM item AS i
-                WHERE NOT EXISTS (
-                    SELECT 1
-                    FROM known_users
-                    WHERE user_id = i.user_id
-                )
-            ";
+        let result = self.prune_items(&opts);
 
-            let (count, bytes) = self.conn.query_row(
-                query,
-                params![],
-                |row| Ok((row.get::<usize,i64>(0)? as u64, row.get::<usize,i64>(1)? as u64))
-            )?;
-            result.items_count = count;
-            result.items_bytes = bytes;
+        // Don't leave the temp table behind, even if we failed:
+        if let Err(err) = self.run("DROP TABLE temp.prune_item") {
+            if result.is_ok() {
+                return Err(err);
+            }
+            warn!("Error dropping temp.prune_item: {}", err);
         }
+        let result = result?;
 
-        if opts.attachments {
-            let query = if opts.items {
-                "
-                SELECT COUNT(*) AS `count`, COALESCE(SUM(LENGTH(contents)), 0) AS size
-                FROM store AS s
-                WHERE NOT EXISTS (
-                    SELECT 1
-                    FROM item_attachment
-                    INNER JOIN item USING (user_id, signature)
-                    WHERE hash = s.hash
-                )
-                "
-            } else {
-                // We'll delete more if we're also deleting items:
-                "
-                SELECT COUNT(*) AS `count`, COALESCE(SUM(LENGTH(contents)), 0) AS size
-                FROM store AS s
-                WHERE NOT EXISTS (
-                    SELECT 1
-                    FROM item_attachment
-                    INNER JOIN item USING (user_id, signature)
-                    INNER JOIN known_users USING (user_id)
-                    WHERE hash = s.hash
-                )
-                "
-            };
+        if opts.vacuum && !opts.dry_run {
+            self.conn.execute("VACUUM", params![])?;
+        }
 
+        Ok(result)
+    }
 
-            // Find attachments that are no longer referenced.
-            // NOTE: Can't just do a simple LEFT OUTER JOIN and check for NULL.
-            // That could lead to false positives when one ref is dangling but another exists.
-            let (count, bytes) = self.conn.query_row(
-                query,
-                params![],
-                |row| Ok((row.get::<usize, i64>(0)? as u64, row.get::<usize,i64>(1)? as u64)),
-            )?;
-            result.attachments_count = count;
-            result.attachments_bytes = bytes;
+    fn maintain(&self, opts: backend::MaintainOpts) -> Result<backend::MaintainResult, Error> {
+        let size_before = self.file_sizes()?;
+
+        if opts.analyze {
+            self.run("ANALYZE")?;
+        } else if opts.optimize {
+            // See: https://www.sqlite.org/pragma.html#pragma_optimize
+            self.conn.execute_batch("PRAGMA optimize")?;
         }
 
-        if opts.dry_run {
-            return Ok(result)
+        if opts.vacuum {
+            self.run("VACUUM")?;
+        }
+        if opts.incremental_vacuum {
+            // 2 = INCREMENTAL. See: https://www.sqlite.org/pragma.html#pragma_auto_vacuum
+            let auto_vacuum: i64 = self.conn.query_row("PRAGMA auto_vacuum", NO_PARAMS, |row| row.get(0))?;
+            if auto_vacuum == 2 {
+                self.conn.execute_batch("PRAGMA incremental_vacuum")?;
+            } else {
+                info!("Enabling incremental vacuuming. This requires a full VACUUM first.");
+                self.conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL")?;
+                self.run("VACUUM")?;
+            }
         }
 
-        // Note: Delete items first, which makes more things available to delete from store if we do that:
-        if opts.items {
-            let query = "
-                DELETE FROM ITEM AS i
-                WHERE NOT EXISTS(
-                    SELECT 1
-                    FROM known_users
-                    WHERE user_id = i.user_id
-                )
-            ";
-            self.conn.execute(query, params![])?;
+        let integrity_problems = if opts.integrity_check {
+            let mut stmt = self.conn.prepare("PRAGMA integrity_check(100)")?;
+            let mut rows = stmt.query(NO_PARAMS)?;
+            let mut problems = vec![];
+            while let Some(row) = rows.next()? {
+                let message: String = row.get(0)?;
+                if message != "ok" {
+                    problems.push(message);
+                }
+            }
+            Some(problems)
+        } else {
+            None
+        };
 
-            // Delete attachments now abandoned:
-            let query = "
-                DELETE FROM item_attachment AS ia
-                WHERE NOT EXISTS (
-                    SELECT 1
-                    FROM item
-                    WHERE user_id = ia.user_id
-                    AND signature = ia.signature
-                )
-            ";
-            self.conn.execute(query, params![])?;
+        let mut checkpoint_busy = false;
+        if opts.checkpoint {
+            let busy: i64 = self.conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", NO_PARAMS, |row| row.get(0))?;
+            checkpoint_busy = busy != 0;
         }
 
-        if opts.attachments {
-            // Note: We can get by w/ one query here because we already deleted
-            // items from unknown users if that was desired.
-            let query = "
-            DELETE FROM store AS s
-            WHERE NOT EXISTS (
-                SELECT 1
-                FROM item_attachment
-                INNER JOIN item USING (user_id, signature)
-                WHERE hash = s.hash
-            )
-            ";
-            self.conn.execute(query, params![])?;
-        }
+        Ok(backend::MaintainResult {
+            size_before,
+            size_after: self.file_sizes()?,
+            integrity_problems,
+            checkpoint_busy,
+        })
+    }
 
-        self.conn.execute("VACUUM", params![])?;
+    fn backup(&self, out: &Path, progress: &mut dyn FnMut(backend::BackupProgress)) -> Result<(), Error> {
+        backup(&self.conn, out, progress)
+    }
 
-        Ok(result)
+    fn all_items<'a>(&self, callback: RowCallback<'a, ItemRow>) -> Result<(), Error> {
+        self.all_items_after(&None, &None, callback)
+    }
+
+    fn verify(&mut self, opts: backend::VerifyOpts, problems: RowCallback<'_, backend::VerifyProblem>) -> Result<backend::VerifyResult, Error> {
+        verify::verify(self, &opts, problems)
     }
 
     fn usage_by_user(&self, callback: RowCallback<'_, backend::UsageByUserRow>) -> Result<(), Error> {
-        let query = "
+        let query = format!("
             SELECT
                 s1.user_id,
                 s1.display_name,
@@ -1406,31 +2070,18 @@ impl backend::Backend for Connection
                 IFNULL(attachment_size,0) AS attachment_size,
                 IFNULL(attachment_count,0) AS attachment_count,
                 item_size + COALESCE(attachment_size, 0) as total_size
-            FROM (
-                SELECT
-                    user_id,
-                    p.display_name,
-                    COUNT(*) as item_count,
-                    SUM(length(bytes)) as item_size
-                FROM item AS i
-                LEFT OUTER JOIN profile AS p USING (user_id)
-                GROUP BY user_id
-            ) AS s1
+            FROM ({user_items}) AS s1
             LEFT OUTER JOIN (
                 SELECT 
                     user_id,
                     SUM(IFNULL(LENGTH(contents),0)) as attachment_size,
                     COUNT(*) AS attachment_count
-                FROM (
-                    SELECT DISTINCT user_id, hash
-                    FROM item
-                    INNER JOIN item_attachment USING (user_id, signature)
-                ) AS user_hashes
+                FROM ({user_hashes}) AS user_hashes
                 INNER JOIN store USING (hash)
                 GROUP BY user_id
             ) AS s2 USING (user_id)
             ORDER BY total_size DESC
-        ";
+        ", user_items=USAGE_USER_ITEMS, user_hashes=USAGE_USER_HASHES);
 
         let mut stmt = self.conn.prepare(&query)?;
         let mut rows = stmt.query(params![])?;
@@ -1459,6 +2110,50 @@ impl backend::Backend for Connection
 
         Ok(())
     }
+
+    fn db_size(&self) -> Result<u64, Error> {
+        let page_count: i64 = self.conn.query_row("PRAGMA page_count", NO_PARAMS, |row| row.get(0))?;
+        let page_size: i64 = self.conn.query_row("PRAGMA page_size", NO_PARAMS, |row| row.get(0))?;
+        Ok((page_count * page_size) as u64)
+    }
+
+    fn usage_totals(&self) -> Result<backend::UsageTotals, Error> {
+        let mut totals = backend::UsageTotals::default();
+
+        let query = format!("
+            SELECT
+                COUNT(*) AS users,
+                COALESCE(SUM(EXISTS(SELECT 1 FROM known_users WHERE user_id=s1.user_id)), 0) AS known_users,
+                COALESCE(SUM(EXISTS(SELECT 1 FROM server_user WHERE user_id=s1.user_id)), 0) AS server_users,
+                COALESCE(SUM(item_count), 0) AS item_count,
+                COALESCE(SUM(item_size), 0) AS item_size
+            FROM ({user_items}) AS s1
+        ", user_items=USAGE_USER_ITEMS);
+        self.conn.query_row(&query, NO_PARAMS, |row| {
+            totals.users = row.get::<_, i64>("users")? as u64;
+            totals.known_users = row.get::<_, i64>("known_users")? as u64;
+            totals.server_users = row.get::<_, i64>("server_users")? as u64;
+            totals.items_count = row.get::<_, i64>("item_count")? as u64;
+            totals.items_bytes = row.get::<_, i64>("item_size")? as u64;
+            Ok(())
+        })?;
+
+        // Count each file once, even if several users attach it:
+        let query = format!("
+            SELECT
+                COUNT(*) AS attachment_count,
+                COALESCE(SUM(IFNULL(LENGTH(contents),0)), 0) AS attachment_size
+            FROM store
+            WHERE hash IN (SELECT hash FROM ({user_hashes}))
+        ", user_hashes=USAGE_USER_HASHES);
+        self.conn.query_row(&query, NO_PARAMS, |row| {
+            totals.attachments_count = row.get::<_, i64>("attachment_count")? as u64;
+            totals.attachments_bytes = row.get::<_, i64>("attachment_size")? as u64;
+            Ok(())
+        })?;
+
+        Ok(totals)
+    }
 }
 
 struct ReplyRow {
@@ -0,0 +1,408 @@
+//! Tests against real (temporary) SQLite databases.
+
+use sodiumoxide::crypto::hash::sha512;
+use tempfile::TempDir;
+
+use crate::{
+    backend::{Backend, Factory, FactoryBuilder as _, ItemRow, ItemTypes, PruneOpts, QuotaDenyReason, RetentionRules, Role, SHA512, ServerUser, Signature, TimeSpan, Timestamp, UserID},
+    keys::SigningKey,
+    protos::{self, Item, ItemType},
+    publish,
+};
+
+use super::{FactoryBuilder, item_type_filter};
+
+/// A new, empty database in a temp directory.
+pub(crate) struct TestDb {
+    // Deleted when dropped:
+    _dir: TempDir,
+    pub factory: Box<dyn Factory>,
+}
+
+impl TestDb {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feoblog.sqlite3");
+        let builder = FactoryBuilder::new(path.to_str().unwrap().into());
+        builder.db_create().unwrap();
+        let factory = builder.factory().unwrap();
+        Self { _dir: dir, factory }
+    }
+
+    pub fn open(&self) -> Box<dyn Backend> {
+        self.factory.open().unwrap()
+    }
+}
+
+pub(crate) fn add_server_user(backend: &dyn Backend, user: &UserID) {
+    backend.add_server_user(&ServerUser{
+        user: user.clone(),
+        notes: String::new(),
+        on_homepage: false,
+        role: Default::default(),
+    }).unwrap();
+}
+
+/// Sign and save an Item, as if a client had uploaded it.
+pub(crate) fn save_item(backend: &mut dyn Backend, key: &SigningKey, item: &Item) -> Signature {
+    save_item_received(backend, key, item, Timestamp::now())
+}
+
+pub(crate) fn save_item_received(backend: &mut dyn Backend, key: &SigningKey, item: &Item, received: Timestamp) -> Signature {
+    let (signature, bytes) = publish::sign(key, item).unwrap();
+    let row = ItemRow {
+        user: key.user_id().clone(),
+        signature: signature.clone(),
+        timestamp: Timestamp{ unix_utc_ms: item.timestamp_ms_utc },
+        received,
+        item_bytes: bytes,
+    };
+    backend.save_user_item(&row, item).unwrap();
+    signature
+}
+
+pub(crate) fn post(timestamp_ms: i64, title: &str) -> Item {
+    let mut item = Item::new();
+    item.set_timestamp_ms_utc(timestamp_ms);
+    item.mut_post().set_title(title.into());
+    item
+}
+
+/// A post with one attached file. Returns the file's hash, to save its contents.
+pub(crate) fn post_with_file(timestamp_ms: i64, name: &str, contents: &[u8]) -> (Item, SHA512) {
+    let hash = SHA512::from_digest(sha512::hash(contents));
+    let mut item = post(timestamp_ms, "Attachment");
+    let mut file = protos::File::new();
+    file.set_hash(hash.bytes().to_vec());
+    file.set_size(contents.len() as u64);
+    file.set_name(name.into());
+    item.mut_post().mut_attachments().mut_file().push(file);
+    (item, hash)
+}
+
+pub(crate) fn profile(timestamp_ms: i64, follows: &[&UserID]) -> Item {
+    let mut item = Item::new();
+    item.set_timestamp_ms_utc(timestamp_ms);
+    let profile = item.mut_profile();
+    profile.set_display_name("Test User".into());
+    for user in follows {
+        let mut follow = protos::Follow::new();
+        follow.mut_user().set_bytes(user.bytes().to_vec());
+        profile.mut_follows().push(follow);
+    }
+    item
+}
+
+pub(crate) fn comment(timestamp_ms: i64, user: &UserID, signature: &Signature) -> Item {
+    let mut item = Item::new();
+    item.set_timestamp_ms_utc(timestamp_ms);
+    let comment = item.mut_comment();
+    comment.set_text("Nice post.".into());
+    comment.mut_reply_to().mut_user_id().set_bytes(user.bytes().to_vec());
+    comment.mut_reply_to().mut_signature().set_bytes(signature.bytes().to_vec());
+    item
+}
+
+fn item_types(backend: &dyn Backend, user: &UserID, types: &str) -> Vec<ItemType> {
+    let types: ItemTypes = types.parse().unwrap();
+    let mut found = vec![];
+    let before = TimeSpan::Before(Timestamp{ unix_utc_ms: i64::MAX });
+    backend.user_items(user, before, &types, &mut |row| {
+        let mut item = Item::new();
+        protobuf::Message::merge_from_bytes(&mut item, &row.item_bytes)?;
+        found.push(item.item_type_enum());
+        Ok(true)
+    }).unwrap();
+    found
+}
+
+#[test]
+fn filter_item_types() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let key = SigningKey::generate().unwrap();
+    add_server_user(backend.as_ref(), key.user_id());
+
+    let post_sig = save_item(backend.as_mut(), &key, &post(1000, "Hello"));
+    save_item(backend.as_mut(), &key, &profile(2000, &[]));
+    save_item(backend.as_mut(), &key, &comment(3000, key.user_id(), &post_sig));
+
+    let user = key.user_id();
+    // Newest first:
+    assert_eq!(item_types(backend.as_ref(), user, "post,profile,comment"), vec![ItemType::COMMENT, ItemType::PROFILE, ItemType::POST]);
+    assert_eq!(item_types(backend.as_ref(), user, "post"), vec![ItemType::POST]);
+    assert_eq!(item_types(backend.as_ref(), user, "comment, Post"), vec![ItemType::COMMENT, ItemType::POST]);
+}
+
+#[test]
+fn item_type_filter_sql() {
+    assert_eq!(item_type_filter("i.item_type", &ItemTypes::all()), "1");
+    assert_eq!(
+        item_type_filter("item_type", &"post,comment".parse().unwrap()),
+        format!("item_type IN ({}, {})", ItemType::POST as i32, ItemType::COMMENT as i32),
+    );
+}
+
+#[test]
+fn parse_item_types() {
+    let types: ItemTypes = "post,post,profile".parse().unwrap();
+    assert_eq!(types.types(), Some(&[ItemType::POST, ItemType::PROFILE][..]));
+    assert!(types.allows(ItemType::PROFILE));
+    assert!(!types.allows(ItemType::COMMENT));
+
+    assert!("post,bogus".parse::<ItemTypes>().is_err());
+    assert!("".parse::<ItemTypes>().is_err());
+}
+
+#[test]
+fn usage_totals() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let key = SigningKey::generate().unwrap();
+    let other = SigningKey::generate().unwrap();
+    add_server_user(backend.as_ref(), key.user_id());
+    add_server_user(backend.as_ref(), other.user_id());
+
+    // Both users attach the same file:
+    let (item, hash) = post_with_file(1000, "hello.txt", b"Hello, world!");
+    save_item(backend.as_mut(), &key, &item);
+    let (other_item, _) = post_with_file(2000, "hello.txt", b"Hello, world!");
+    save_item(backend.as_mut(), &other, &other_item);
+    save_item(backend.as_mut(), &other, &post(3000, "No files"));
+    backend.save_attachment(13, &hash, &mut &b"Hello, world!"[..]).unwrap();
+
+    let mut items_bytes = 0;
+    let mut attachments_bytes = 0;
+    backend.usage_by_user(&mut |row| {
+        items_bytes += row.items_bytes;
+        attachments_bytes += row.attachments_bytes;
+        Ok(true)
+    }).unwrap();
+
+    let totals = backend.usage_totals().unwrap();
+    assert_eq!(totals.users, 2);
+    assert_eq!(totals.server_users, 2);
+    assert_eq!(totals.items_count, 3);
+    assert_eq!(totals.items_bytes, items_bytes);
+    assert_eq!(totals.attachments_count, 1);
+    assert_eq!(totals.attachments_bytes, 13);
+    assert_eq!(attachments_bytes, 26, "usage_by_user() counts the file for each user");
+}
+
+#[test]
+fn attachments_meta() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let key = SigningKey::generate().unwrap();
+    add_server_user(backend.as_ref(), key.user_id());
+
+    let (item, hash) = post_with_file(1000, "hello.txt", b"Hello, world!");
+    let sig = save_item(backend.as_mut(), &key, &item);
+    let user = key.user_id().clone();
+    let ids = vec![
+        (user.clone(), sig.clone(), "hello.txt".to_string()),
+        (user.clone(), sig.clone(), "missing.txt".to_string()),
+    ];
+
+    let mut found = vec![];
+    backend.attachments_meta(&ids, &mut |(id, meta)| { found.push((id, meta)); Ok(true) }).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].0, ids[0]);
+    assert_eq!(found[0].1.size, 13);
+    assert!(!found[0].1.exists);
+
+    backend.save_attachment(13, &hash, &mut &b"Hello, world!"[..]).unwrap();
+    let mut found = vec![];
+    backend.attachments_meta(&ids, &mut |(_, meta)| { found.push(meta); Ok(true) }).unwrap();
+    assert!(found[0].exists);
+}
+
+#[test]
+fn changes_cursor() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let key = SigningKey::generate().unwrap();
+    add_server_user(backend.as_ref(), key.user_id());
+
+    let page = |backend: &dyn Backend, after: i64| {
+        let mut page = vec![];
+        backend.changes_after(after, &mut |(seq, row)| {
+            page.push((seq, row.signature));
+            Ok(page.len() < 2)
+        }).unwrap();
+        page
+    };
+
+    for i in 0..3 {
+        save_item_received(backend.as_mut(), &key, &post(1000 + i, "Hello"), Timestamp{ unix_utc_ms: 5000 });
+    }
+    let first = page(backend.as_ref(), 0);
+    assert_eq!(first.len(), 2);
+
+    // Saved later, but with an earlier received time. (ex: a batch that took a while to commit)
+    let late = save_item_received(backend.as_mut(), &key, &post(2000, "Late"), Timestamp{ unix_utc_ms: 1000 });
+
+    let mut seen: Vec<Signature> = first.iter().map(|(_, sig)| sig.clone()).collect();
+    let mut after = first.last().unwrap().0;
+    loop {
+        let next = page(backend.as_ref(), after);
+        let last = match next.last() {
+            Some(last) => last.0,
+            None => break,
+        };
+        assert!(last > after);
+        after = last;
+        seen.extend(next.into_iter().map(|(_, sig)| sig));
+    }
+
+    assert_eq!(seen.len(), 4);
+    assert_eq!(seen.last(), Some(&late));
+}
+
+#[test]
+fn backup() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let key = SigningKey::generate().unwrap();
+    add_server_user(backend.as_ref(), key.user_id());
+    save_item(backend.as_mut(), &key, &post(1000, "Hello"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("backup.sqlite3");
+    let mut last_progress = None;
+    backend.backup(&out, &mut |progress| last_progress = Some(progress)).unwrap();
+    let progress = last_progress.expect("progress");
+    assert_eq!(progress.copied_pages, progress.total_pages);
+
+    let copy = FactoryBuilder::new(out.to_str().unwrap().into()).factory().unwrap().open().unwrap();
+    assert_eq!(item_types(copy.as_ref(), key.user_id(), "post"), vec![ItemType::POST]);
+}
+
+#[test]
+fn prune_attachments() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let server_key = SigningKey::generate().unwrap();
+    let key = SigningKey::generate().unwrap();
+    add_server_user(backend.as_ref(), server_key.user_id());
+    save_item(backend.as_mut(), &server_key, &profile(1000, &[key.user_id()]));
+
+    let (old_post, old_hash) = post_with_file(1000, "old.txt", b"old");
+    let (new_post, new_hash) = post_with_file(2000, "new.txt", b"new");
+    save_item(backend.as_mut(), &key, &old_post);
+    save_item(backend.as_mut(), &key, &new_post);
+    backend.save_attachment(3, &old_hash, &mut &b"old"[..]).unwrap();
+    backend.save_attachment(3, &new_hash, &mut &b"new"[..]).unwrap();
+    // Not referenced by any item. (ex: its item was deleted by an earlier prune)
+    let other_hash = SHA512::from_digest(sha512::hash(b"other"));
+    backend.save_attachment(5, &other_hash, &mut &b"other"[..]).unwrap();
+
+    let opts = |dry_run| PruneOpts{
+        dry_run,
+        attachments: true,
+        items: false,
+        retention: Some(RetentionRules{ keep_days: None, keep_items: Some(1) }),
+        vacuum: false,
+    };
+    let result = backend.prune(opts(true)).unwrap();
+    assert_eq!(result.items_count, 1);
+    assert_eq!(result.attachments_count, 2);
+    assert_eq!(result.attachments_bytes, 8);
+
+    backend.prune(opts(false)).unwrap();
+    let exists = |hash: &SHA512| backend.read_attachment(hash, &mut |_, _| Ok(())).unwrap();
+    assert!(!exists(&old_hash));
+    assert!(exists(&new_hash));
+    assert!(!exists(&other_hash));
+    assert_eq!(item_types(backend.as_ref(), key.user_id(), "post"), vec![ItemType::POST]);
+}
+
+#[test]
+fn mirror_roles() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let mirror_key = SigningKey::generate().unwrap();
+    let followed = SigningKey::generate().unwrap();
+    backend.add_server_user(&ServerUser{
+        user: mirror_key.user_id().clone(),
+        notes: String::new(),
+        on_homepage: false,
+        role: Role::Mirror,
+    }).unwrap();
+    save_item(backend.as_mut(), &mirror_key, &profile(1000, &[followed.user_id()]));
+
+    // Mirrors can't post, so the users they follow can't either:
+    assert!(backend.user_known(mirror_key.user_id()).unwrap());
+    assert!(!backend.user_known(followed.user_id()).unwrap());
+
+    let item = post(2000, "Hello");
+    let reason = backend.quota_check_item(mirror_key.user_id(), &[], &item).unwrap();
+    assert!(matches!(reason, Some(QuotaDenyReason::ReadOnly)));
+    let reason = backend.quota_check_item(followed.user_id(), &[], &item).unwrap();
+    assert!(matches!(reason, Some(QuotaDenyReason::UnknownUser)));
+}
+
+#[test]
+fn poster_and_admin_roles() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let admin = SigningKey::generate().unwrap();
+    let poster = SigningKey::generate().unwrap();
+    let admin_follow = SigningKey::generate().unwrap();
+    let poster_follow = SigningKey::generate().unwrap();
+    add_server_user(backend.as_ref(), admin.user_id());
+    backend.add_server_user(&ServerUser{
+        user: poster.user_id().clone(),
+        notes: String::new(),
+        on_homepage: false,
+        role: Role::Poster,
+    }).unwrap();
+    save_item(backend.as_mut(), &admin, &profile(1000, &[admin_follow.user_id()]));
+    save_item(backend.as_mut(), &poster, &profile(1000, &[poster_follow.user_id()]));
+
+    // Both may post:
+    let item = post(2000, "Hello");
+    assert!(backend.quota_check_item(admin.user_id(), &[], &item).unwrap().is_none());
+    assert!(backend.quota_check_item(poster.user_id(), &[], &item).unwrap().is_none());
+
+    // But only an admin's follows are hosted here:
+    assert!(backend.user_known(admin_follow.user_id()).unwrap());
+    assert!(backend.quota_check_item(admin_follow.user_id(), &[], &item).unwrap().is_none());
+    assert!(!backend.user_known(poster_follow.user_id()).unwrap());
+    let reason = backend.quota_check_item(poster_follow.user_id(), &[], &item).unwrap();
+    assert!(matches!(reason, Some(QuotaDenyReason::UnknownUser)));
+}
+
+#[test]
+fn attachment_quota() {
+    let db = TestDb::new();
+    let mut backend = db.open();
+    let poster = SigningKey::generate().unwrap();
+    let mirror = SigningKey::generate().unwrap();
+    add_server_user(backend.as_ref(), poster.user_id());
+    backend.add_server_user(&ServerUser{
+        user: mirror.user_id().clone(),
+        notes: String::new(),
+        on_homepage: false,
+        role: Role::Mirror,
+    }).unwrap();
+
+    // Mirrors' items are imported, but they may not upload anything, so they're always over quota:
+    let (item, _) = post_with_file(1000, "hello.txt", b"Hello, world!");
+    let poster_sig = save_item(backend.as_mut(), &poster, &item);
+    let mirror_sig = save_item(backend.as_mut(), &mirror, &item);
+
+    let meta = backend.get_attachment_meta(poster.user_id(), &poster_sig, "hello.txt").unwrap().unwrap();
+    assert!(!meta.quota_exceeded);
+    let meta = backend.get_attachment_meta(mirror.user_id(), &mirror_sig, "hello.txt").unwrap().unwrap();
+    assert!(meta.quota_exceeded);
+
+    let ids = vec![
+        (poster.user_id().clone(), poster_sig, "hello.txt".to_string()),
+        (mirror.user_id().clone(), mirror_sig, "hello.txt".to_string()),
+    ];
+    let mut found = std::collections::HashMap::new();
+    backend.attachments_meta(&ids, &mut |(id, meta)| { found.insert(id, meta.quota_exceeded); Ok(true) }).unwrap();
+    assert!(!found[&ids[0]]);
+    assert!(found[&ids[1]]);
+}
@@ -1,10 +1,11 @@
 //! Types that know how to upgrade the SQLite database.
 
 use anyhow::{Error, bail};
+use log::warn;
 use protobuf::Message;
 use rusqlite::params;
 
-use crate::{backend::{ItemRow, RowCallback, Signature, UserID}, protos::Item};
+use crate::{backend::{ItemRow, RowCallback, Signature, UpgradeStep, UserID}, protos::Item};
 
 use super::{AttachmentRow, CURRENT_VERSION, Connection, ReplyRow, get_attachment_rows, save_attachment_rows, save_reply_rows};
 
@@ -19,6 +20,9 @@ impl Upgraders {
             Box::new(From4To5),
             Box::new(From5To6),
             Box::new(From6To7),
+            Box::new(From7To8),
+            Box::new(From8To9),
+            Box::new(From9To10),
         ]}
     }
 
@@ -26,11 +30,39 @@ impl Upgraders {
         let mut current_version = conn.get_version()?; 
         while current_version < CURRENT_VERSION {
             let upgrader = self.upgrader_from(current_version)?;
-            println!("Upgrading from db version {} to {} ...", current_version, upgrader.to_version());
-            upgrader.upgrade(conn)?;
-            current_version = conn.get_version()?;
-            if current_version != upgrader.to_version() {
-                bail!("Upgrader failed to upgrade to advertised version: {}, still {}", upgrader.to_version(), current_version);
+            println!(
+                "Upgrading from db version {} to {}: {} ...",
+                current_version, upgrader.to_version(), upgrader.description()
+            );
+
+            // Each step either completes, or leaves the database as it was:
+            conn.run("SAVEPOINT upgrade")?;
+            let result = upgrader.upgrade(conn).and_then(|_| {
+                let new_version = conn.get_version()?;
+                if new_version != upgrader.to_version() {
+                    bail!("Upgrader failed to upgrade to advertised version: {}, still {}", upgrader.to_version(), new_version);
+                }
+                Ok(new_version)
+            });
+            match result {
+                Ok(new_version) => {
+                    conn.run("RELEASE upgrade")?;
+                    current_version = new_version;
+                },
+                Err(err) => {
+                    // Some errors (ex: SQLITE_FULL, SQLITE_IOERR) make SQLite roll back the
+                    // whole transaction itself, so our savepoint may already be gone.
+                    // Either way, report the error that got us here:
+                    if let Err(rollback_err) = conn.run("ROLLBACK TO upgrade") {
+                        warn!("Error rolling back upgrade: {}", rollback_err);
+                    } else if let Err(release_err) = conn.run("RELEASE upgrade") {
+                        warn!("Error releasing upgrade savepoint: {}", release_err);
+                    }
+                    return Err(err.context(format!(
+                        "Error upgrading from version {}. The database is unchanged from that version.",
+                        current_version
+                    )));
+                },
             }
         }
 
@@ -39,6 +71,22 @@ impl Upgraders {
         Ok(())
     }
 
+    /// The upgrades that a database at `version` needs, in order.
+    pub fn pending(&self, version: u32) -> Result<Vec<UpgradeStep>, Error> {
+        let mut steps = vec![];
+        let mut version = version;
+        while version < CURRENT_VERSION {
+            let upgrader = self.upgrader_from(version)?;
+            steps.push(UpgradeStep {
+                from_version: upgrader.from_version(),
+                to_version: upgrader.to_version(),
+                description: upgrader.description().into(),
+            });
+            version = upgrader.to_version();
+        }
+        Ok(steps)
+    }
+
     fn upgrader_from(&self, version: u32) -> Result<&dyn Upgrader, Error> {
         for upgrader in &self.upgraders {
             if upgrader.from_version() == version {
@@ -53,6 +101,8 @@ impl Upgraders {
 trait Upgrader {
     fn from_version(&self) -> u32;
     fn to_version(&self) -> u32;
+    /// A short, human-readable description of what this upgrade does.
+    fn description(&self) -> &'static str;
     fn upgrade(&self, conn: &Connection) -> Result<(), Error>;
 }
 
@@ -63,6 +113,7 @@ struct From3To4;
 impl Upgrader for From3To4 {
     fn from_version(&self) -> u32 { 3 }
     fn to_version(&self) -> u32 { 4 }
+    fn description(&self) -> &'static str { "Add a reply table to track comments" }
 
     fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
         conn.run("
@@ -153,7 +204,7 @@ impl ItemPager {
         }
     }
 
-    /// Iterate through the Connection.all_items() results, and store the iteration point to resume.
+    /// Iterate through the Connection.all_items_after() results, and store the iteration point to resume.
     fn iterate<'a>(&mut self, conn: &Connection, cb: RowCallback<'a, ItemRow>) -> Result<(), Error> {
         let mut new_uid = None;
         let mut new_sig = None;
@@ -174,7 +225,7 @@ impl ItemPager {
         };
 
 
-        conn.all_items(&self.after_uid, &self.after_sig, &mut my_cb)?;
+        conn.all_items_after(&self.after_uid, &self.after_sig, &mut my_cb)?;
 
         self.after_uid = new_uid;
         self.after_sig = new_sig;
@@ -189,6 +240,7 @@ struct From4To5;
 impl Upgrader for From4To5 {
     fn from_version(&self) -> u32 { 4 }
     fn to_version(&self) -> u32 { 5 }
+    fn description(&self) -> &'static str { "Add an index for finding known users" }
     fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
 
         // Note: Could have made an index on (followed_user_id, source_user_id), but:
@@ -226,6 +278,7 @@ struct From5To6;
 impl Upgrader for From5To6 {
     fn from_version(&self) -> u32 { 5 }
     fn to_version(&self) -> u32 { 6 }
+    fn description(&self) -> &'static str { "Add tables for file attachments" }
     fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
         conn.run("
             CREATE TABLE item_attachment(
@@ -328,6 +381,7 @@ struct From6To7;
 impl Upgrader for From6To7 {
     fn from_version(&self) -> u32 { 6 }
     fn to_version(&self) -> u32 { 7 }
+    fn description(&self) -> &'static str { "Make attachment indexes UNIQUE" }
     fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
         conn.run("DROP INDEX IF EXISTS item_attachment_item_idx")?;
         conn.run("
@@ -344,4 +398,168 @@ impl Upgrader for From6To7 {
         conn.set_version(self.to_version())?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// Adds an item_type column so that we can filter item lists by type in SQL.
+struct From7To8;
+impl Upgrader for From7To8 {
+    fn from_version(&self) -> u32 { 7 }
+    fn to_version(&self) -> u32 { 8 }
+    fn description(&self) -> &'static str { "Add an item_type column for filtering items by type" }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            ALTER TABLE item
+            -- The ItemType enum value for this item. (See feoblog.proto)
+            ADD COLUMN item_type INTEGER
+        ")?;
+
+        conn.run("
+            CREATE INDEX item_user_type_chrono_idx
+            ON item(user_id, item_type, unix_utc_ms)
+        ")?;
+
+        // TODO: Newer rusqlite supports u64 & usize:
+        let item_count: u32 = conn.conn.query_row(
+            "SELECT COUNT(*) FROM item",
+            params![],
+            |row| Ok(row.get(0)?)
+        )?;
+
+        if item_count > 1000 {
+            println!("Scanning {} items for their item types. This may take a some time.", item_count);
+        }
+
+        let mut pager = ItemPager::new();
+
+        // Same as above, batch up writes so that we're not reading & writing at the same time:
+        let mut item_types = Vec::<(UserID, Signature, i32)>::new();
+        let max_rows = 1000;
+
+        while !pager.done {
+            pager.iterate(conn, &mut |row| {
+                let mut item = Item::new();
+                item.merge_from_bytes(row.item_bytes.as_slice())?;
+
+                item_types.push((row.user, row.signature, item.item_type_enum() as i32));
+
+                Ok(item_types.len() < max_rows)
+            })?;
+
+            let mut stmt = conn.conn.prepare("
+                UPDATE item
+                SET item_type = ?
+                WHERE user_id = ? AND signature = ?
+            ")?;
+            for (user_id, signature, item_type) in &item_types {
+                stmt.execute(params![item_type, user_id.bytes(), signature.bytes()])?;
+            }
+            item_types.clear();
+        }
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+// Adds roles for server users. (See: backend::Role)
+struct From8To9;
+impl Upgrader for From8To9 {
+    fn from_version(&self) -> u32 { 8 }
+    fn to_version(&self) -> u32 { 9 }
+    fn description(&self) -> &'static str { "Add roles for server users" }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            ALTER TABLE server_user
+            -- See: backend::Role
+            -- Existing users could already post, and their follows were
+            -- known users, so they're admins.
+            ADD COLUMN role TEXT NOT NULL DEFAULT 'admin'
+                CHECK (role IN ('admin', 'poster', 'mirror'))
+        ")?;
+
+        // Only admins' follows are known users:
+        conn.run("DROP VIEW known_users")?;
+        conn.run("
+            CREATE VIEW known_users (user_id) AS
+            -- For internal use only. All 'known users' of the server.
+                SELECT user_id
+                FROM server_user
+            UNION ALL
+                SELECT followed_user_id
+                FROM follow AS f
+                INNER JOIN server_user AS s
+                    ON (f.source_user_id=s.user_id)
+                WHERE s.role = 'admin'
+            ;
+        ")?;
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+// Numbers items in the order they're saved, for paging through /changes.
+// (See: Backend::changes_after())
+struct From9To10;
+impl Upgrader for From9To10 {
+    fn from_version(&self) -> u32 { 9 }
+    fn to_version(&self) -> u32 { 10 }
+    fn description(&self) -> &'static str { "Add change sequence numbers to items" }
+    fn upgrade(&self, conn: &Connection) -> Result<(), Error> {
+        conn.run("
+            ALTER TABLE item
+            -- The order in which this server saved (and committed) items.
+            -- Unlike received_utc_ms, a later commit never gets a smaller value.
+            ADD COLUMN change_seq INTEGER
+        ")?;
+
+        conn.run("
+            CREATE TABLE change_counter(
+                -- The last change_seq given to an item. Kept separately so that
+                -- numbers aren't reused when the newest items are deleted.
+                seq INTEGER NOT NULL
+            )
+        ")?;
+
+        // Existing items have all been committed, so number them in the order we received them:
+        conn.run("
+            CREATE TEMP TABLE item_seq(
+                item_rowid INTEGER PRIMARY KEY,
+                seq INTEGER
+            )
+        ")?;
+        conn.run("
+            INSERT INTO temp.item_seq (item_rowid, seq)
+            SELECT rowid, ROW_NUMBER() OVER (ORDER BY received_utc_ms, signature)
+            FROM item
+        ")?;
+        conn.run("
+            UPDATE item
+            SET change_seq = (SELECT seq FROM temp.item_seq WHERE item_rowid = item.rowid)
+        ")?;
+        conn.run("DROP TABLE temp.item_seq")?;
+
+        conn.run("INSERT INTO change_counter (seq) SELECT COALESCE(MAX(change_seq), 0) FROM item")?;
+        conn.run("CREATE UNIQUE INDEX item_change_seq_idx ON item(change_seq)")?;
+
+        conn.set_version(self.to_version())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CURRENT_VERSION, Upgraders};
+
+    #[test]
+    fn upgrades_reach_current_version() {
+        let steps = Upgraders::new().pending(3).unwrap();
+        assert_eq!(steps.first().unwrap().from_version, 3);
+        assert_eq!(steps.last().unwrap().to_version, CURRENT_VERSION);
+        for pair in steps.windows(2) {
+            assert_eq!(pair[0].to_version, pair[1].from_version);
+        }
+
+        assert!(Upgraders::new().pending(CURRENT_VERSION).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,395 @@
+//! Checks for `feoblog db verify`.
+//!
+//! Items must still match their signatures, and stored files their hashes.
+//! The tables we derive from items (profile, follow, reply, item_attachment)
+//! must agree with the items' contents. Only the derived tables can be
+//! repaired. If an item or file is corrupt, the best we can do is report it.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Error;
+use protobuf::Message;
+use rusqlite::{DatabaseName, OptionalExtension, ToSql, params};
+
+use crate::{backend::{ItemRow, RowCallback, SHA512, Signature, Timestamp, UserID, VerifyOpts, VerifyProblem, VerifyResult}, protos::{Item, ItemType, ProtoValid}, util::AsHex};
+
+use super::{Connection, get_attachment_rows, save_attachment_rows, save_comment_reply, update_profile};
+
+/// Something that `--repair` can fix.
+enum Repair {
+    ItemColumns { user: UserID, signature: Signature, timestamp: i64, item_type: i32 },
+    Replies(UserID, Signature),
+    Attachments(UserID, Signature),
+    Profile(UserID),
+    OrphanReplies(UserID, Signature),
+    OrphanAttachments(UserID, Signature),
+}
+
+/// The newest profile we've seen for a user.
+struct LatestProfile {
+    timestamp: i64,
+    received: i64,
+    signature: Signature,
+}
+
+pub(super) fn verify(conn: &mut Connection, opts: &VerifyOpts, problems: RowCallback<'_, VerifyProblem>) -> Result<VerifyResult, Error> {
+    let mut count = 0;
+    let mut repairs = vec![];
+    let mut report = |location: String, problem: String, repair: Option<Repair>| -> Result<(), Error> {
+        count += 1;
+        let repairable = repair.is_some();
+        if let Some(repair) = repair {
+            repairs.push(repair);
+        }
+        problems(VerifyProblem { location, problem, repairable })?;
+        Ok(())
+    };
+
+    let mut profiles = HashMap::<UserID, LatestProfile>::new();
+    let mut items = 0;
+    conn.all_items_after(&None, &None, &mut |row| {
+        items += 1;
+        let location = item_location(&row.user, &row.signature);
+
+        if !row.signature.is_valid(&row.user, &row.item_bytes) {
+            report(location.clone(), "Invalid signature".into(), None)?;
+        }
+
+        let mut item = Item::new();
+        if let Err(err) = item.merge_from_bytes(&row.item_bytes) {
+            report(location, format!("Can't parse Item: {}", err), None)?;
+            return Ok(true);
+        }
+        if let Err(err) = item.validate() {
+            report(location.clone(), err.to_string(), None)?;
+        }
+
+        let item_type = item.item_type_enum() as i32;
+        let stored_type: Option<i32> = conn.conn.prepare_cached(
+            "SELECT item_type FROM item WHERE user_id = ? AND signature = ?"
+        )?.query_row(params![row.user.bytes(), row.signature.bytes()], |r| r.get(0))?;
+        if row.timestamp.unix_utc_ms != item.timestamp_ms_utc || stored_type != Some(item_type) {
+            report(location.clone(), "Indexed timestamp or item type doesn't match Item".into(), Some(Repair::ItemColumns {
+                user: row.user.clone(),
+                signature: row.signature.clone(),
+                timestamp: item.timestamp_ms_utc,
+                item_type,
+            }))?;
+        }
+
+        if replies_differ(conn, &row, &item)? {
+            let repair = Repair::Replies(row.user.clone(), row.signature.clone());
+            report(location.clone(), "reply rows don't match Item".into(), Some(repair))?;
+        }
+        if attachments_differ(conn, &row, &item)? {
+            let repair = Repair::Attachments(row.user.clone(), row.signature.clone());
+            report(location.clone(), "item_attachment rows don't match Item".into(), Some(repair))?;
+        }
+
+        if item.has_profile() {
+            // Same rule as update_profile(): the first-saved of the newest profiles wins.
+            let newer = match profiles.get(&row.user) {
+                None => true,
+                Some(latest) => (item.timestamp_ms_utc, -row.received.unix_utc_ms) > (latest.timestamp, -latest.received),
+            };
+            if newer {
+                profiles.insert(row.user.clone(), LatestProfile {
+                    timestamp: item.timestamp_ms_utc,
+                    received: row.received.unix_utc_ms,
+                    signature: row.signature.clone(),
+                });
+            }
+        }
+
+        Ok(true)
+    })?;
+
+    for (user, signature) in orphans(conn, "reply", "from_user_id", "from_signature")? {
+        let location = item_location(&user, &signature);
+        report(location, "reply rows for a missing Item".into(), Some(Repair::OrphanReplies(user, signature)))?;
+    }
+    for (user, signature) in orphans(conn, "item_attachment", "user_id", "signature")? {
+        let location = item_location(&user, &signature);
+        report(location, "item_attachment rows for a missing Item".into(), Some(Repair::OrphanAttachments(user, signature)))?;
+    }
+
+    // Profiles & follows:
+    let mut users: HashSet<UserID> = profiles.keys().cloned().collect();
+    {
+        let mut stmt = conn.conn.prepare("
+            SELECT user_id FROM profile
+            UNION
+            SELECT source_user_id FROM follow
+        ")?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            users.insert(UserID::from_vec(row.get(0)?)?);
+        }
+    }
+    for user in users {
+        if let Some(problem) = profile_problem(conn, &user, profiles.get(&user))? {
+            report(format!("/u/{}/profile", user), problem, Some(Repair::Profile(user)))?;
+        }
+    }
+
+    // File contents:
+    let mut files = vec![];
+    {
+        let mut stmt = conn.conn.prepare("SELECT rowid, hash FROM store")?;
+        let mut rows = stmt.query(params![])?;
+        while let Some(row) = rows.next()? {
+            files.push((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?));
+        }
+    }
+    let file_count = files.len();
+    for (row_id, hash) in files {
+        let location = format!("file {}", hash.as_slice().as_hex());
+        let expected = match SHA512::from_hash_bytes(&hash) {
+            Ok(hash) => hash,
+            Err(_) => {
+                // See save_attachment(). It uses a temporary 31-byte hash.
+                report(location, "Incomplete file upload".into(), None)?;
+                continue;
+            }
+        };
+        let mut blob = conn.conn.blob_open(DatabaseName::Main, "store", "contents", row_id, true)?;
+        if SHA512::from_file(&mut blob)? != expected {
+            report(location, "Contents don't match hash".into(), None)?;
+        }
+    }
+
+    drop(report);
+    let result = VerifyResult {
+        repaired: opts.repair,
+        items,
+        files: file_count,
+        problems: count,
+        repairable: repairs.len(),
+    };
+    if opts.repair && !repairs.is_empty() {
+        apply(conn, repairs)?;
+    }
+
+    Ok(result)
+}
+
+fn item_location(user: &UserID, signature: &Signature) -> String {
+    format!("/u/{}/i/{}", user, signature.to_base58())
+}
+
+fn replies_differ(conn: &Connection, row: &ItemRow, item: &Item) -> Result<bool, Error> {
+    let mut expected = vec![];
+    if item.has_comment() {
+        let reply_to = item.get_comment().get_reply_to();
+        expected.push((reply_to.get_user_id().get_bytes().to_vec(), reply_to.get_signature().get_bytes().to_vec()));
+    }
+
+    let mut stmt = conn.conn.prepare_cached("
+        SELECT to_user_id, to_signature
+        FROM reply
+        WHERE from_user_id = ? AND from_signature = ?
+    ")?;
+    let mut rows = stmt.query(params![row.user.bytes(), row.signature.bytes()])?;
+    let mut found = vec![];
+    while let Some(row) = rows.next()? {
+        found.push((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?));
+    }
+
+    Ok(found != expected)
+}
+
+fn attachments_differ(conn: &Connection, row: &ItemRow, item: &Item) -> Result<bool, Error> {
+    // Items with invalid attachments don't get indexed. (See From5To6)
+    // Later duplicate names replace earlier ones. (See save_attachment_rows())
+    let expected: HashMap<String, (i64, Vec<u8>)> = get_attachment_rows(row, item).unwrap_or_default()
+        .into_iter()
+        .map(|a| (a.name, (a.size, a.hash.bytes().to_vec())))
+        .collect();
+
+    let mut stmt = conn.conn.prepare_cached("
+        SELECT name, size, hash
+        FROM item_attachment
+        WHERE user_id = ? AND signature = ?
+    ")?;
+    let mut rows = stmt.query(params![row.user.bytes(), row.signature.bytes()])?;
+    let mut found = HashMap::new();
+    while let Some(row) = rows.next()? {
+        found.insert(row.get::<_, String>(0)?, (row.get::<_, i64>(1)?, row.get::<_, Vec<u8>>(2)?));
+    }
+
+    Ok(found != expected)
+}
+
+/// Find (user_id, signature) pairs in a derived table that have no matching Item.
+fn orphans(conn: &Connection, table: &str, user_column: &str, signature_column: &str) -> Result<Vec<(UserID, Signature)>, Error> {
+    // Table and column names are our own constants, so are safe to format into the query.
+    let query = format!("
+        SELECT DISTINCT {user}, {sig}
+        FROM {table} AS t
+        WHERE NOT EXISTS (
+            SELECT 1
+            FROM item AS i
+            WHERE i.user_id = t.{user} AND i.signature = t.{sig}
+        )
+    ", table = table, user = user_column, sig = signature_column);
+
+    let mut stmt = conn.conn.prepare(&query)?;
+    let mut rows = stmt.query(params![])?;
+    let mut orphans = vec![];
+    while let Some(row) = rows.next()? {
+        orphans.push((UserID::from_vec(row.get(0)?)?, Signature::from_vec(row.get(1)?)?));
+    }
+    Ok(orphans)
+}
+
+/// Check that the profile & follow rows for a user match their latest Profile.
+fn profile_problem(conn: &Connection, user: &UserID, latest: Option<&LatestProfile>) -> Result<Option<String>, Error> {
+    let stored: Option<(Vec<u8>, String)> = conn.conn.query_row(
+        "SELECT signature, display_name FROM profile WHERE user_id = ?",
+        params![user.bytes()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+
+    let mut follows = HashMap::<Vec<u8>, String>::new();
+    {
+        let mut stmt = conn.conn.prepare_cached("
+            SELECT followed_user_id, display_name
+            FROM follow
+            WHERE source_user_id = ?
+        ")?;
+        let mut rows = stmt.query(params![user.bytes()])?;
+        while let Some(row) = rows.next()? {
+            follows.insert(row.get(0)?, row.get(1)?);
+        }
+    }
+
+    let latest = match latest {
+        Some(latest) => latest,
+        None => return Ok(Some("profile/follow rows for a missing Profile".into())),
+    };
+
+    let (signature, display_name) = match stored {
+        Some(stored) => stored,
+        None => return Ok(Some("Missing profile row".into())),
+    };
+    if signature != latest.signature.bytes() {
+        return Ok(Some("profile row doesn't point to the latest Profile".into()));
+    }
+
+    let bytes: Vec<u8> = conn.conn.query_row(
+        "SELECT bytes FROM item WHERE user_id = ? AND signature = ?",
+        params![user.bytes(), latest.signature.bytes()],
+        |row| row.get(0),
+    )?;
+    let mut item = Item::new();
+    item.merge_from_bytes(&bytes)?;
+    let profile = item.get_profile();
+
+    if display_name != profile.get_display_name() {
+        return Ok(Some("profile display_name doesn't match Profile".into()));
+    }
+
+    // Later duplicates replace earlier ones. (See update_profile())
+    let expected: HashMap<Vec<u8>, String> = profile.get_follows().iter()
+        .map(|f| (f.get_user().get_bytes().to_vec(), f.get_display_name().to_string()))
+        .collect();
+    if follows != expected {
+        return Ok(Some("follow rows don't match Profile".into()));
+    }
+
+    Ok(None)
+}
+
+fn apply(conn: &mut Connection, repairs: Vec<Repair>) -> Result<(), Error> {
+    let tx = conn.conn.savepoint()?;
+
+    for repair in repairs {
+        match repair {
+            Repair::ItemColumns { user, signature, timestamp, item_type } => {
+                tx.execute(
+                    "UPDATE item SET unix_utc_ms = ?, item_type = ? WHERE user_id = ? AND signature = ?",
+                    params![timestamp, item_type, user.bytes(), signature.bytes()],
+                )?;
+            },
+            Repair::Replies(user, signature) => {
+                tx.execute(
+                    "DELETE FROM reply WHERE from_user_id = ? AND from_signature = ?",
+                    params![user.bytes(), signature.bytes()],
+                )?;
+                let row = item_row(&tx, &user, "signature = ?", &signature.bytes())?.expect("item exists");
+                save_comment_reply(&tx, &row, &parse(&row)?)?;
+            },
+            Repair::Attachments(user, signature) => {
+                tx.execute(
+                    "DELETE FROM item_attachment WHERE user_id = ? AND signature = ?",
+                    params![user.bytes(), signature.bytes()],
+                )?;
+                let row = item_row(&tx, &user, "signature = ?", &signature.bytes())?.expect("item exists");
+                // Like From5To6, skip items with invalid attachments:
+                if let Ok(rows) = get_attachment_rows(&row, &parse(&row)?) {
+                    save_attachment_rows(&tx, rows)?;
+                }
+            },
+            Repair::OrphanReplies(user, signature) => {
+                tx.execute(
+                    "DELETE FROM reply WHERE from_user_id = ? AND from_signature = ?",
+                    params![user.bytes(), signature.bytes()],
+                )?;
+            },
+            Repair::OrphanAttachments(user, signature) => {
+                tx.execute(
+                    "DELETE FROM item_attachment WHERE user_id = ? AND signature = ?",
+                    params![user.bytes(), signature.bytes()],
+                )?;
+            },
+            Repair::Profile(user) => {
+                tx.execute("DELETE FROM profile WHERE user_id = ?", params![user.bytes()])?;
+                tx.execute("DELETE FROM follow WHERE source_user_id = ?", params![user.bytes()])?;
+                let profile_type = ItemType::PROFILE as i32;
+                if let Some(row) = item_row(&tx, &user, "item_type = ?", &profile_type)? {
+                    update_profile(&tx, &row, &parse(&row)?)?;
+                }
+            },
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+fn parse(row: &ItemRow) -> Result<Item, Error> {
+    let mut item = Item::new();
+    item.merge_from_bytes(&row.item_bytes)?;
+    Ok(item)
+}
+
+/// Fetch a user's item matching `condition`.
+/// If more than one matches, get the first-saved of the newest. (See update_profile())
+fn item_row(conn: &rusqlite::Connection, user: &UserID, condition: &str, param: &dyn ToSql) -> Result<Option<ItemRow>, Error> {
+    let query = format!("
+        SELECT signature, unix_utc_ms, received_utc_ms, bytes
+        FROM item
+        WHERE user_id = ? AND {}
+        ORDER BY unix_utc_ms DESC, received_utc_ms ASC
+        LIMIT 1
+    ", condition);
+
+    let row = conn.query_row(&query, params![user.bytes(), param], |row| Ok((
+        row.get::<_, Vec<u8>>(0)?,
+        row.get::<_, i64>(1)?,
+        row.get::<_, i64>(2)?,
+        row.get::<_, Vec<u8>>(3)?,
+    ))).optional()?;
+
+    let (signature, timestamp, received, item_bytes) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    Ok(Some(ItemRow {
+        user: user.clone(),
+        signature: Signature::from_vec(signature)?,
+        timestamp: Timestamp { unix_utc_ms: timestamp },
+        received: Timestamp { unix_utc_ms: received },
+        item_bytes,
+    }))
+}
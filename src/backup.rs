@@ -0,0 +1,120 @@
+//! Database backups, for `feoblog db backup` and scheduled backups in `serve`.
+//!
+//! The backend copies a consistent snapshot of the database (even while the
+//! server is writing to it) into a temp file. We then optionally compress it
+//! and move it into place, so a backup file is never left half-written.
+
+use std::{fs::File, io::{BufReader, BufWriter, Write}, path::{Path, PathBuf}};
+
+use anyhow::{Context, Error};
+use flate2::{Compression, write::GzEncoder};
+
+use crate::backend::BackupProgress;
+
+/// Scheduled backups are named `<PREFIX><timestamp>.sqlite3[.gz]`.
+const PREFIX: &str = "feoblog-";
+
+/// Back up the database to `out`.
+/// `backup` is called to write an uncompressed copy of the database to a temp file.
+pub(crate) fn write<F>(out: &Path, gzip: bool, backup: F) -> Result<(), Error>
+where F: FnOnce(&Path) -> Result<(), Error>
+{
+    let dir = match out.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let temp = tempfile::Builder::new()
+        .prefix(".feoblog-backup")
+        .tempfile_in(dir)
+        .with_context(|| format!("Error creating temp file in {}", dir.display()))?;
+    backup(temp.path())?;
+
+    if !gzip {
+        temp.persist(out).with_context(|| format!("Error writing {}", out.display()))?;
+        return Ok(());
+    }
+
+    let compressed = tempfile::Builder::new()
+        .prefix(".feoblog-backup")
+        .tempfile_in(dir)
+        .with_context(|| format!("Error creating temp file in {}", dir.display()))?;
+    {
+        let mut encoder = GzEncoder::new(BufWriter::new(compressed.as_file()), Compression::default());
+        let mut input = BufReader::new(File::open(temp.path())?);
+        std::io::copy(&mut input, &mut encoder).context("Error compressing backup")?;
+        encoder.finish()?.flush()?;
+    }
+    compressed.persist(out).with_context(|| format!("Error writing {}", out.display()))?;
+    Ok(())
+}
+
+/// A new file name for a scheduled backup.
+pub(crate) fn file_name(gzip: bool) -> String {
+    let now = time::OffsetDateTime::now_utc().format("%Y%m%d-%H%M%S");
+    let extension = if gzip { ".sqlite3.gz" } else { ".sqlite3" };
+    format!("{}{}{}", PREFIX, now, extension)
+}
+
+/// Delete all but the newest `keep` scheduled backups in `dir`.
+/// Returns the deleted files.
+pub(crate) fn remove_old(dir: &Path, keep: usize) -> Result<Vec<PathBuf>, Error> {
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Error reading {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.starts_with(PREFIX) && (name.ends_with(".sqlite3") || name.ends_with(".sqlite3.gz")) {
+            backups.push(entry.path());
+        }
+    }
+
+    // Timestamps in the names sort chronologically:
+    backups.sort();
+    let remove = backups.len().saturating_sub(keep);
+    let removed: Vec<PathBuf> = backups.into_iter().take(remove).collect();
+    for path in &removed {
+        std::fs::remove_file(path).with_context(|| format!("Error removing {}", path.display()))?;
+    }
+    Ok(removed)
+}
+
+/// Print backup progress to stderr.
+pub(crate) fn print_progress(progress: BackupProgress) {
+    if progress.total_pages == 0 {
+        return;
+    }
+    let percent = progress.copied_pages * 100 / progress.total_pages;
+    eprint!("\rBacking up: {}% ({}/{} pages)", percent, progress.copied_pages, progress.total_pages);
+    if progress.copied_pages == progress.total_pages {
+        eprintln!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use super::{remove_old, write};
+
+    #[test]
+    fn gzip_and_retention() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let out = dir.path().join("backup.gz");
+        write(&out, true, |temp| Ok(fs::write(temp, b"database")?)).unwrap();
+        let mut contents = vec![];
+        flate2::read::GzDecoder::new(fs::File::open(&out).unwrap()).read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"database");
+
+        for name in &["feoblog-20200101-000000.sqlite3", "feoblog-20200102-000000.sqlite3.gz", "feoblog-20200103-000000.sqlite3"] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let removed = remove_old(dir.path(), 2).unwrap();
+        assert_eq!(removed, vec![dir.path().join("feoblog-20200101-000000.sqlite3")]);
+        // Other files are left alone:
+        assert!(out.exists());
+    }
+}
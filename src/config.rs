@@ -0,0 +1,684 @@
+//! Configuration for `feoblog serve`.
+//!
+//! Settings may be read from a TOML file (`--config`), and CLI flags override
+//! values from that file. Any setting that isn't specified falls back to its
+//! default, so an empty file is a valid config.
+//!
+//! Example:
+//!
+//! ```toml
+//! [server]
+//! binds = ["127.0.0.1:8080"]
+//! sqlite_file = "/var/lib/feoblog/feoblog.sqlite3"
+//!
+//! [limits]
+//! max_item_size = 32768
+//! clock_drift_ms = 60000
+//! ```
+
+use std::{net::IpAddr, path::{Path, PathBuf}, sync::{Arc, RwLock}};
+
+use anyhow::{Context, Error, bail};
+use log::warn;
+use serde::Deserialize;
+
+use crate::{ServeCommand, backend::RetentionRules};
+
+/// Servers should accept items up to 32KiB. (See: feoblog.proto)
+pub(crate) const DEFAULT_MAX_ITEM_SIZE: usize = 1024 * 32;
+
+/// Don't let a config file raise the item size so high that we'd buffer huge requests in memory.
+const MAX_MAX_ITEM_SIZE: usize = 1024 * 1024 * 16;
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub server: ServerConfig,
+    pub limits: Limits,
+    pub cors: CorsConfig,
+    pub logging: LoggingConfig,
+    pub tls: TlsConfig,
+    pub metrics: MetricsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub uploads: UploadsConfig,
+    pub backup: BackupConfig,
+    pub maintenance: MaintenanceConfig,
+    pub retention: RetentionConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    /// Local addresses to bind to.
+    /// May also be `unix:/path/to/socket`, or `systemd` for socket activation.
+    pub binds: Vec<String>,
+
+    /// Should we open a browser window?
+    pub open: bool,
+
+    pub sqlite_file: String,
+
+    /// File permissions for Unix sockets we create. ex: 0o660
+    pub socket_mode: Option<u32>,
+
+    /// How long (in seconds) to let in-flight requests finish when shutting down.
+    pub shutdown_timeout_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Limits {
+    /// The maximum size of an Item, in bytes.
+    pub max_item_size: usize,
+
+    /// The maximum number of entries returned in a single REST `ItemList`.
+    pub max_list_items: usize,
+
+    /// How far in the future (in milliseconds) we'll accept Item timestamps,
+    /// to allow for clients whose clocks have drifted.
+    pub clock_drift_ms: i64,
+
+    /// The most items that may be fetched in one `/items/batch/proto3` request,
+    /// or checked in one `/have/proto3` request.
+    pub max_batch_items: usize,
+
+    /// The largest request body accepted by `/items/upload/proto3`.
+    pub max_batch_upload_bytes: usize,
+
+    /// How many uploads (items or attachments) may be in progress at once.
+    pub max_concurrent_uploads: usize,
+
+    /// How long (in seconds) we'll wait for the next chunk of a request body
+    /// before giving up on the client.
+    pub body_read_timeout_secs: u64,
+
+    /// When we reject an upload, we read (and discard) the rest of the request
+    /// so that clients see our response. But only up to this many bytes...
+    pub max_drain_bytes: u64,
+
+    /// ... and for up to this many seconds.
+    pub drain_timeout_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+    /// Throttle uploads per client IP and per user.
+    pub enabled: bool,
+
+    /// Proxies which we trust to tell us the client's IP in `X-Forwarded-For`.
+    pub trusted_proxies: Vec<IpAddr>,
+
+    pub per_ip: BucketConfig,
+    pub per_user: BucketConfig,
+}
+
+/// Settings for a token bucket.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BucketConfig {
+    /// How many requests may be made in a burst.
+    pub burst: u32,
+
+    /// How many requests per second are allowed after that.
+    pub per_second: f64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CorsConfig {
+    /// Value for the Access-Control-Allow-Origin header.
+    pub allow_origin: String,
+
+    /// How long (in seconds) browsers may cache CORS permissions.
+    pub max_age_secs: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LoggingConfig {
+    /// An env_logger filter. ex: "info", or "feoblog=debug,actix_web=info".
+    /// The RUST_LOG environment variable takes precedence over this.
+    pub filter: Option<String>,
+
+    /// Log each HTTP request.
+    pub access_log: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    /// A PEM file containing the certificate chain.
+    pub cert: Option<PathBuf>,
+
+    /// A PEM file containing the private key.
+    pub key: Option<PathBuf>,
+
+    /// Local addresses to serve HTTPS (and HTTP/2) on.
+    pub binds: Vec<String>,
+
+    /// Local addresses which redirect plain HTTP requests to HTTPS.
+    pub redirect_binds: Vec<String>,
+
+    /// How often (in seconds) to check whether the certificate files have changed.
+    /// 0 disables this check. (You can still reload them with SIGHUP.)
+    pub check_interval_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MetricsConfig {
+    /// Serve Prometheus metrics at `/metrics`.
+    pub enabled: bool,
+
+    /// If set, serve metrics only on these local addresses, instead of
+    /// alongside the rest of the server.
+    pub binds: Vec<String>,
+
+    /// How often (in seconds) to collect database statistics.
+    /// These require a scan of the database, so shouldn't be too frequent.
+    pub db_stats_interval_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct UploadsConfig {
+    /// Where to keep partial (resumable) attachment uploads.
+    /// Default: `<sqlite_file>.uploads`
+    pub dir: Option<PathBuf>,
+
+    /// Remove partial uploads this many hours after they were started.
+    pub expire_hours: u64,
+}
+
+impl UploadsConfig {
+    /// The uploads directory, defaulting to one next to the database.
+    pub fn dir(&self, server: &ServerConfig) -> PathBuf {
+        self.dir.clone().unwrap_or_else(|| format!("{}.uploads", server.sqlite_file).into())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BackupConfig {
+    /// Periodically back up the database while the server runs.
+    pub enabled: bool,
+
+    /// Where to write backups.
+    /// Default: `<sqlite_file>.backups`
+    pub dir: Option<PathBuf>,
+
+    /// How often (in hours) to take a backup.
+    pub interval_hours: u64,
+
+    /// How many backups to keep. Older ones are deleted.
+    pub keep: usize,
+
+    /// Compress backups with gzip.
+    pub gzip: bool,
+}
+
+impl BackupConfig {
+    /// The backup directory, defaulting to one next to the database.
+    pub fn dir(&self, server: &ServerConfig) -> PathBuf {
+        self.dir.clone().unwrap_or_else(|| format!("{}.backups", server.sqlite_file).into())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MaintenanceConfig {
+    /// Periodically run light database maintenance. (`PRAGMA optimize`, and a WAL checkpoint)
+    pub enabled: bool,
+
+    /// How often (in hours) to run it.
+    pub interval_hours: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RetentionConfig {
+    /// Periodically delete followed users' items that the rules below don't keep.
+    /// (Also used by `feoblog db prune --retention --config`.)
+    pub enabled: bool,
+
+    /// How often (in hours) to prune.
+    pub interval_hours: u64,
+
+    /// Keep items from the last this-many days.
+    pub keep_days: Option<u64>,
+
+    /// Keep each followed user's newest this-many items.
+    pub keep_items: Option<u64>,
+}
+
+impl RetentionConfig {
+    pub fn rules(&self) -> RetentionRules {
+        RetentionRules {
+            keep_days: self.keep_days,
+            keep_items: self.keep_items,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            binds: vec![],
+            open: false,
+            sqlite_file: "feoblog.sqlite3".into(),
+            socket_mode: None,
+            // Same as actix-web's default:
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            // We're only holding ItemListEntries in memory, so we can allow
+            // a lot of them and save some round trips.
+            max_list_items: 1000,
+            clock_drift_ms: 0,
+            max_batch_items: 500,
+            max_batch_upload_bytes: 8 * 1024 * 1024,
+            max_concurrent_uploads: 32,
+            body_read_timeout_secs: 30,
+            max_drain_bytes: 1024 * 1024,
+            drain_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allow_origin: "*".into(),
+            // FF caps this at 24 hours, and is the most permissive there, so that's what we'll use.
+            max_age_secs: 86400,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: None,
+            key: None,
+            binds: vec![],
+            redirect_binds: vec![],
+            check_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            binds: vec![],
+            db_stats_interval_secs: 5 * 60,
+        }
+    }
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            expire_hours: 24,
+        }
+    }
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: None,
+            interval_hours: 24,
+            keep: 7,
+            gzip: true,
+        }
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: 24,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: 24,
+            keep_days: None,
+            keep_items: None,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            trusted_proxies: vec![],
+            per_ip: BucketConfig{ burst: 60, per_second: 1.0 },
+            per_user: BucketConfig{ burst: 120, per_second: 2.0 },
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            filter: None,
+            access_log: true,
+        }
+    }
+}
+
+impl Config {
+    /// Read a config file. Does not validate it.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading config file {}", path.display()))?;
+        Self::from_toml(&text)
+            .with_context(|| format!("Error parsing config file {}", path.display()))
+    }
+
+    fn from_toml(text: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Load the config for a `serve` command, applying any overrides from CLI flags.
+    pub fn for_command(command: &ServeCommand) -> Result<Self, Error> {
+        let mut config = match &command.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        if !command.binds.is_empty() {
+            config.server.binds = command.binds.clone();
+        }
+        if command.open {
+            config.server.open = true;
+        }
+        if let Some(sqlite_file) = &command.sqlite_file {
+            config.server.sqlite_file = sqlite_file.clone();
+        }
+        if let Some(mode) = command.socket_mode {
+            config.server.socket_mode = Some(mode);
+        }
+        if let Some(cert) = &command.tls_cert {
+            config.tls.cert = Some(cert.clone());
+        }
+        if let Some(key) = &command.tls_key {
+            config.tls.key = Some(key.clone());
+        }
+        if !command.tls_binds.is_empty() {
+            config.tls.binds = command.tls_binds.clone();
+        }
+        if !command.tls_redirect_binds.is_empty() {
+            config.tls.redirect_binds = command.tls_redirect_binds.clone();
+        }
+
+        if config.server.binds.is_empty() && config.tls.binds.is_empty() {
+            config.server.binds.push("127.0.0.1:8080".into());
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check for values that we can't run with.
+    pub fn validate(&self) -> Result<(), Error> {
+        let limits = &self.limits;
+        if limits.max_item_size < DEFAULT_MAX_ITEM_SIZE {
+            bail!("limits.max_item_size must be at least {} bytes", DEFAULT_MAX_ITEM_SIZE);
+        }
+        if limits.max_item_size > MAX_MAX_ITEM_SIZE {
+            bail!("limits.max_item_size may not be more than {} bytes", MAX_MAX_ITEM_SIZE);
+        }
+        if limits.max_list_items == 0 {
+            bail!("limits.max_list_items must be at least 1");
+        }
+        if limits.max_batch_items == 0 {
+            bail!("limits.max_batch_items must be at least 1");
+        }
+        if limits.max_batch_upload_bytes < limits.max_item_size {
+            bail!("limits.max_batch_upload_bytes must be at least limits.max_item_size");
+        }
+        if limits.clock_drift_ms < 0 {
+            bail!("limits.clock_drift_ms may not be negative");
+        }
+        if limits.max_concurrent_uploads == 0 {
+            bail!("limits.max_concurrent_uploads must be at least 1");
+        }
+        if limits.body_read_timeout_secs == 0 {
+            bail!("limits.body_read_timeout_secs must be at least 1");
+        }
+
+        let rate_limit = &self.rate_limit;
+        for (name, bucket) in &[("per_ip", &rate_limit.per_ip), ("per_user", &rate_limit.per_user)] {
+            if bucket.burst == 0 {
+                bail!("rate_limit.{}.burst must be at least 1", name);
+            }
+            if !(bucket.per_second > 0.0) {
+                bail!("rate_limit.{}.per_second must be more than 0", name);
+            }
+        }
+
+        if self.cors.allow_origin.trim().is_empty() {
+            bail!("cors.allow_origin may not be empty");
+        }
+
+        if self.server.sqlite_file.trim().is_empty() {
+            bail!("server.sqlite_file may not be empty");
+        }
+        if let Some(mode) = self.server.socket_mode {
+            if mode > 0o777 {
+                bail!("server.socket_mode must be a file mode, like 0o660");
+            }
+        }
+
+        let metrics = &self.metrics;
+        if !metrics.binds.is_empty() && !metrics.enabled {
+            bail!("metrics.binds requires metrics.enabled");
+        }
+        if metrics.db_stats_interval_secs == 0 {
+            bail!("metrics.db_stats_interval_secs must be at least 1");
+        }
+
+        if self.uploads.expire_hours == 0 {
+            bail!("uploads.expire_hours must be at least 1");
+        }
+
+        if self.backup.interval_hours == 0 {
+            bail!("backup.interval_hours must be at least 1");
+        }
+        if self.backup.keep == 0 {
+            bail!("backup.keep must be at least 1");
+        }
+        if self.maintenance.interval_hours == 0 {
+            bail!("maintenance.interval_hours must be at least 1");
+        }
+        if self.retention.interval_hours == 0 {
+            bail!("retention.interval_hours must be at least 1");
+        }
+        if self.retention.enabled {
+            self.retention.rules().validate().context("Invalid [retention] settings")?;
+        }
+
+        let all_binds = self.server.binds.iter()
+            .chain(&self.tls.binds)
+            .chain(&self.tls.redirect_binds)
+            .chain(&self.metrics.binds);
+        if all_binds.filter(|b| b.as_str() == "systemd").count() > 1 {
+            bail!("The \"systemd\" bind may only be used once");
+        }
+
+        let tls = &self.tls;
+        if tls.cert.is_some() != tls.key.is_some() {
+            bail!("tls.cert and tls.key must be specified together");
+        }
+        if !tls.binds.is_empty() && tls.cert.is_none() {
+            bail!("tls.binds requires tls.cert and tls.key");
+        }
+        if tls.cert.is_some() && tls.binds.is_empty() {
+            bail!("tls.cert is set, but there are no tls.binds to serve HTTPS on");
+        }
+        if !tls.redirect_binds.is_empty() && tls.binds.is_empty() {
+            bail!("tls.redirect_binds requires tls.binds");
+        }
+
+        Ok(())
+    }
+}
+
+/// The Config for a running server, which can be reloaded. (ex: on SIGHUP)
+///
+/// Only some settings can safely change while the server is running.
+/// Others will log a warning and require a restart.
+pub(crate) struct LiveConfig {
+    command: ServeCommand,
+    current: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(command: ServeCommand, config: Config) -> Self {
+        Self {
+            command,
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    /// Get the current config.
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().expect("config lock").clone()
+    }
+
+    /// The config file this server was started with, if any.
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.command.config.as_ref()
+    }
+
+    /// Re-read the config file.
+    /// If it's invalid, the current config remains in place.
+    pub fn reload(&self) -> Result<(), Error> {
+        let mut new_config = Config::for_command(&self.command)?;
+        let old_config = self.get();
+
+        // These are only read at startup:
+        if new_config.server != old_config.server {
+            warn!("Changes to [server] settings require a restart.");
+        }
+        if new_config.cors != old_config.cors {
+            warn!("Changes to [cors] settings require a restart.");
+        }
+        if new_config.logging != old_config.logging {
+            warn!("Changes to [logging] settings require a restart.");
+        }
+        if new_config.metrics != old_config.metrics {
+            warn!("Changes to [metrics] settings require a restart.");
+        }
+        if new_config.uploads != old_config.uploads {
+            warn!("Changes to [uploads] settings require a restart.");
+        }
+        if new_config.backup != old_config.backup {
+            warn!("Changes to [backup] settings require a restart.");
+        }
+        if new_config.maintenance != old_config.maintenance {
+            warn!("Changes to [maintenance] settings require a restart.");
+        }
+        if new_config.retention != old_config.retention {
+            warn!("Changes to [retention] settings require a restart.");
+        }
+        if new_config.tls != old_config.tls {
+            warn!("Changes to [tls] settings require a restart. (Certificates are reloaded separately.)");
+        }
+        new_config.server = old_config.server.clone();
+        new_config.cors = old_config.cors.clone();
+        new_config.logging = old_config.logging.clone();
+        new_config.tls = old_config.tls.clone();
+        new_config.metrics = old_config.metrics.clone();
+        new_config.uploads = old_config.uploads.clone();
+        new_config.backup = old_config.backup.clone();
+        new_config.maintenance = old_config.maintenance.clone();
+        new_config.retention = old_config.retention.clone();
+
+        *self.current.write().expect("config lock") = Arc::new(new_config);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, DEFAULT_MAX_ITEM_SIZE};
+
+    #[test]
+    fn empty_config_is_default() {
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.limits.max_item_size, DEFAULT_MAX_ITEM_SIZE);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn partial_config() {
+        let config = Config::from_toml("
+            [limits]
+            clock_drift_ms = 5000
+        ").unwrap();
+        assert_eq!(config.limits.clock_drift_ms, 5000);
+        assert_eq!(config.limits.max_list_items, 1000);
+        assert_eq!(config.cors.allow_origin, "*");
+    }
+
+    #[test]
+    fn unknown_fields() {
+        assert!(Config::from_toml("[limits]\nmax_itme_size = 5").is_err());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let config = Config::from_toml("
+            [rate_limit]
+            enabled = true
+            trusted_proxies = [\"127.0.0.1\"]
+            per_ip = { burst = 10, per_second = 0.5 }
+        ").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.rate_limit.per_ip.burst, 10);
+        assert_eq!(config.rate_limit.per_user.burst, 120);
+
+        let config = Config::from_toml("[rate_limit]\nper_user = { burst = 10, per_second = 0 }").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn retention() {
+        let config = Config::from_toml("[retention]\nenabled = true\nkeep_items = 100").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.retention.rules().keep_days, None);
+
+        // Must keep *something*:
+        let config = Config::from_toml("[retention]\nenabled = true").unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::from_toml("[retention]\nenabled = true\nkeep_days = 0").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn invalid_values() {
+        let config = Config::from_toml("[limits]\nmax_item_size = 1024").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
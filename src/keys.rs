@@ -0,0 +1,180 @@
+//! Key files, for `feoblog key generate` and the commands that sign Items.
+//!
+//! A key file is JSON. The secret key inside it is encrypted with a key
+//! derived from a password (Argon2id), so the file alone isn't enough to post
+//! as its user.
+
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use anyhow::{Context, Error, bail, format_err};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::{pwhash::argon2id13 as pwhash, secretbox, sign};
+
+use crate::backend::{Signature, UserID};
+
+const FORMAT: &str = "feoblog-key";
+const VERSION: u32 = 1;
+
+/// If set, read the key file's password from this environment variable
+/// instead of prompting for it. (ex: for scripts)
+pub(crate) const PASSWORD_VAR: &str = "FEOBLOG_KEY_PASSWORD";
+
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    format: String,
+    version: u32,
+    user_id: String,
+    /// Parameters for deriving the encryption key from the password:
+    salt: String,
+    ops_limit: usize,
+    mem_limit: usize,
+    /// The encrypted secret key. (secretbox)
+    nonce: String,
+    secret_key: String,
+}
+
+/// A user's secret key, for signing Items.
+pub(crate) struct SigningKey {
+    user_id: UserID,
+    secret_key: sign::SecretKey,
+}
+
+impl SigningKey {
+    pub fn generate() -> Result<Self, Error> {
+        init()?;
+        let (public_key, secret_key) = sign::gen_keypair();
+        let user_id = UserID::from_vec(public_key.as_ref().to_vec())?;
+        Ok(Self { user_id, secret_key })
+    }
+
+    pub fn user_id(&self) -> &UserID {
+        &self.user_id
+    }
+
+    pub fn sign(&self, bytes: &[u8]) -> Signature {
+        let signature = sign::sign_detached(bytes, &self.secret_key);
+        Signature::from_vec(signature.as_ref().to_vec()).expect("64-byte signature")
+    }
+
+    /// Write a new key file. Will not overwrite an existing file.
+    pub fn save(&self, path: &Path, password: &str) -> Result<(), Error> {
+        init()?;
+        let salt = pwhash::gen_salt();
+        let ops_limit = pwhash::OPSLIMIT_INTERACTIVE;
+        let mem_limit = pwhash::MEMLIMIT_INTERACTIVE;
+        let key = derive_key(password, &salt, ops_limit, mem_limit)?;
+
+        let nonce = secretbox::gen_nonce();
+        let sealed = secretbox::seal(self.secret_key.as_ref(), &nonce, &key);
+
+        let key_file = KeyFile {
+            format: FORMAT.into(),
+            version: VERSION,
+            user_id: self.user_id.to_base58(),
+            salt: base64::encode(salt.as_ref()),
+            ops_limit: ops_limit.0,
+            mem_limit: mem_limit.0,
+            nonce: base64::encode(nonce.as_ref()),
+            secret_key: base64::encode(&sealed),
+        };
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)] {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)
+            .with_context(|| format!("Error creating {}", path.display()))?;
+        file.write_all(&serde_json::to_vec_pretty(&key_file)?)?;
+        file.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path, password: &str) -> Result<Self, Error> {
+        init()?;
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Error opening {}", path.display()))?;
+        let key_file: KeyFile = serde_json::from_reader(file)
+            .with_context(|| format!("Error reading {}", path.display()))?;
+
+        if key_file.format != FORMAT {
+            bail!("{} is not a FeoBlog key file", path.display());
+        }
+        if key_file.version != VERSION {
+            bail!("Unsupported key file version: {}", key_file.version);
+        }
+
+        let user_id = UserID::from_base58(&key_file.user_id).context("Invalid user ID in key file")?;
+        let salt = pwhash::Salt::from_slice(&base64::decode(&key_file.salt)?)
+            .ok_or_else(|| format_err!("Invalid salt in key file"))?;
+        let nonce = secretbox::Nonce::from_slice(&base64::decode(&key_file.nonce)?)
+            .ok_or_else(|| format_err!("Invalid nonce in key file"))?;
+        let sealed = base64::decode(&key_file.secret_key)?;
+
+        let key = derive_key(
+            password,
+            &salt,
+            pwhash::OpsLimit(key_file.ops_limit),
+            pwhash::MemLimit(key_file.mem_limit),
+        )?;
+        let secret_key = secretbox::open(&sealed, &nonce, &key)
+            .map_err(|_| format_err!("Incorrect password for {}", path.display()))?;
+        let secret_key = sign::SecretKey::from_slice(&secret_key)
+            .ok_or_else(|| format_err!("Invalid secret key in key file"))?;
+
+        // Make sure the file hasn't been mixed up with some other user's key:
+        if secret_key.public_key().as_ref() != user_id.bytes() {
+            bail!("Key file's secret key doesn't match its user ID");
+        }
+
+        Ok(Self { user_id, secret_key })
+    }
+}
+
+fn init() -> Result<(), Error> {
+    sodiumoxide::init().map_err(|_| format_err!("Error initializing sodiumoxide"))
+}
+
+fn derive_key(
+    password: &str,
+    salt: &pwhash::Salt,
+    ops_limit: pwhash::OpsLimit,
+    mem_limit: pwhash::MemLimit,
+) -> Result<secretbox::Key, Error> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    pwhash::derive_key(&mut key.0, password.as_bytes(), salt, ops_limit, mem_limit)
+        .map_err(|_| format_err!("Error deriving key from password"))?;
+    Ok(key)
+}
+
+/// Read a key file's password from `$FEOBLOG_KEY_PASSWORD`, or prompt for it.
+pub(crate) fn read_password(prompt: &str) -> Result<String, Error> {
+    if let Ok(password) = std::env::var(PASSWORD_VAR) {
+        return Ok(password);
+    }
+    rpassword::prompt_password(prompt).context("Error reading password")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SigningKey;
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.json");
+
+        let key = SigningKey::generate().unwrap();
+        key.save(&path, "hunter2").unwrap();
+        // Don't clobber existing keys:
+        assert!(key.save(&path, "hunter2").is_err());
+
+        assert!(SigningKey::load(&path, "wrong").is_err());
+        let loaded = SigningKey::load(&path, "hunter2").unwrap();
+        assert_eq!(loaded.user_id(), key.user_id());
+
+        let signature = loaded.sign(b"hello");
+        assert!(signature.is_valid(key.user_id(), b"hello"));
+    }
+}
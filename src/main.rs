@@ -5,15 +5,24 @@
 #[cfg(test)]
 mod tests;
 
-use crate::{backend::{Factory, PruneOpts, ServerUser, UsageByUserRow, UserID, sqlite}, util::AsHex};
-use anyhow::{Error, bail};
+use crate::{backend::{Factory, MaintainOpts, PruneOpts, RetentionRules, ServerUser, UsageByUserRow, UserID, VerifyOpts, sqlite}, protos::ProtoValid, util::AsHex, output::Format};
+use anyhow::{Context, Error, bail};
+use protobuf::Message;
+use std::{collections::HashMap, fs::File, io::{BufReader, BufWriter}, path::PathBuf};
 use sizedisplay::SizeDisplay;
 use structopt::StructOpt;
 use tablestream::{Stream, Column, col};
 
+mod archive;
 mod backend;
+mod backup;
+mod config;
+mod keys;
 mod markdown;
+mod merge;
+mod output;
 mod protos;
+mod publish;
 mod server;
 mod util;
 
@@ -26,6 +35,10 @@ fn main() -> Result<(), Error> {
         Serve(command) => server::serve(command)?,
         User(command) => command.main()?,
         Db(command) => command.main()?,
+        Item(command) => command.main()?,
+        Key(command) => command.main()?,
+        Post(command) => command.main()?,
+        Profile(command) => command.main()?,
     };
 
     Ok(())
@@ -47,13 +60,30 @@ enum Command
 
     /// Database administration commands
     Db(DbCommand),
+
+    /// Inspect items in the database
+    Item(ItemCommand),
+
+    /// Manage key files for signing Items
+    Key(KeyCommand),
+
+    /// Sign and upload a post to a server
+    Post(PostCommand),
+
+    /// Manage your profile on a server
+    Profile(ProfileCommand),
 }
 
 #[derive(StructOpt, Debug, Clone)]
 
 struct ServeCommand {
-    #[structopt(flatten)]
-    backend_options: BackendOptions,
+    /// Read settings from this TOML file. Command-line options override its values.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// [default: feoblog.sqlite3]
+    #[structopt(long)]
+    sqlite_file: Option<String>,
 
     /// Should we open a browser window?
     #[structopt(long)]
@@ -61,8 +91,33 @@ struct ServeCommand {
 
     /// Bind to this local address.
     /// If unspecified, will try to bind to some port on localhost.
+    /// May also be `unix:/path/to/socket`, or `systemd` to use sockets from systemd socket activation.
     #[structopt(long="bind")]
-    binds: Vec<String>
+    binds: Vec<String>,
+
+    /// File permissions for Unix sockets, in octal. ex: 660
+    #[structopt(long, parse(try_from_str = parse_octal))]
+    socket_mode: Option<u32>,
+
+    /// A PEM file containing the TLS certificate chain.
+    #[structopt(long, parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// A PEM file containing the TLS private key.
+    #[structopt(long, parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS on this local address.
+    #[structopt(long="tls-bind")]
+    tls_binds: Vec<String>,
+
+    /// Redirect plain HTTP requests on this local address to HTTPS.
+    #[structopt(long="tls-redirect-bind")]
+    tls_redirect_binds: Vec<String>,
+}
+
+fn parse_octal(value: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(value.trim_start_matches("0o"), 8)
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -92,8 +147,17 @@ pub(crate) enum UserCommand {
     /// Add a new user.
     Add(UserAddCommand),
 
+    /// Change a user's notes, homepage setting, or role.
+    Edit(UserEditCommand),
+
     /// Remove a user
     Remove(UserRemoveCommand),
+
+    /// Export all of a user's items and attachments to a tar archive.
+    Export(UserExportCommand),
+
+    /// Import a user's items and attachments from an archive made by `user export`.
+    Import(UserImportCommand),
 }
 
 impl UserCommand {
@@ -102,7 +166,10 @@ impl UserCommand {
         match self {
             List(command) => command.main(),
             Add(command) => command.main(),
+            Edit(command) => command.main(),
             Remove(command) => command.main(),
+            Export(command) => command.main(),
+            Import(command) => command.main(),
         }
     }
 }
@@ -111,27 +178,90 @@ impl UserCommand {
 struct UserListCommand {
     #[structopt(flatten)]
     backend_options: BackendOptions,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
 }
 
 impl UserListCommand {
     fn main(&self) -> Result<(), Error> {
         let factory = self.backend_options.factory_builder()?.factory()?;
         let conn = factory.open()?;
-        
-        conn.server_users(&mut |server_user| {
 
-            let ServerUser{user, notes, on_homepage} = server_user;
-            let on_homepage = if on_homepage { "H" } else { " " };
-
-            println!("{} {} {}", on_homepage, user.to_base58(), notes);
+        let mut usage = HashMap::new();
+        conn.usage_by_user(&mut |row| {
+            usage.insert(row.user_id.clone(), row);
+            Ok(true)
+        })?;
 
+        let mut users = vec![];
+        conn.server_users(&mut |server_user| {
+            let usage = usage.remove(&server_user.user);
+            users.push(UserListRow{
+                display_name: usage.as_ref().and_then(|u| u.display_name.clone()),
+                items_count: usage.as_ref().map(|u| u.items_count).unwrap_or(0),
+                total_bytes: usage.as_ref().map(|u| u.total_bytes).unwrap_or(0),
+                user_id: server_user.user,
+                role: server_user.role,
+                on_homepage: server_user.on_homepage,
+                notes: server_user.notes,
+            });
             Ok(true) // fetch more
         })?;
 
+        match self.format.format {
+            Format::Json => return output::print_json(&users),
+            Format::Csv => return output::print_csv(users),
+            Format::Table => {},
+        }
+
+        let stdout = std::io::stdout();
+        let mut lock = stdout.lock();
+        let mut stream = Stream::new(&mut lock, vec![
+            col!(UserListRow: .user_id).header("User ID").min_width(44),
+            Column::new(|f, r: &UserListRow| {
+                write!(f, "{}", r.display_name.as_deref().unwrap_or(""))?;
+                Ok(())
+            }).header("Display Name"),
+            col!(UserListRow: .role).header("Role"),
+            Column::new(|f, r: &UserListRow| {
+                write!(f, "{}", if r.on_homepage { "yes" } else { "" })?;
+                Ok(())
+            }).header("Homepage"),
+            col!(UserListRow: .items_count).header("Items").right(),
+            Column::new(|f, r: &UserListRow| {
+                write!(f, "{}", SizeDisplay::bytes(r.total_bytes).short())?;
+                Ok(())
+            }).header("Usage").right(),
+            col!(UserListRow: .notes).header("Notes"),
+        ]);
+        for user in users {
+            stream.row(user)?;
+        }
+        stream.finish()?;
+
         Ok(())
     }
 }
 
+/// A server user, with some info about their content.
+#[derive(serde::Serialize)]
+struct UserListRow {
+    user_id: UserID,
+    display_name: Option<String>,
+    role: backend::Role,
+    on_homepage: bool,
+    notes: String,
+
+    items_count: u64,
+    /// Items and attachments.
+    total_bytes: u64,
+}
+
+impl output::CsvRow for UserListRow {
+    const HEADERS: &'static [&'static str] = &["user_id", "display_name", "role", "on_homepage", "notes", "items_count", "total_bytes"];
+}
+
 #[derive(StructOpt, Debug, Clone)]
 struct UserAddCommand {
     #[structopt(flatten)]
@@ -146,6 +276,10 @@ struct UserAddCommand {
     /// Notes for the server admin
     #[structopt(long, default_value="")]
     comment: String,
+
+    /// admin, poster, or mirror. (See: docs/administration.md)
+    #[structopt(long, default_value="admin")]
+    role: backend::Role,
 }
 
 impl UserAddCommand {
@@ -157,6 +291,7 @@ impl UserAddCommand {
             user: self.user_id.clone(),
             on_homepage: self.on_homepage,
             notes: self.comment.clone(),
+            role: self.role,
         };
 
         conn.add_server_user(&user)?;
@@ -164,6 +299,54 @@ impl UserAddCommand {
     }
 }
 
+#[derive(StructOpt, Debug, Clone)]
+struct UserEditCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    user_id: UserID,
+
+    /// Notes for the server admin
+    #[structopt(long)]
+    notes: Option<String>,
+
+    /// Should this user's posts appear on the homepage? (true/false)
+    #[structopt(long)]
+    on_homepage: Option<bool>,
+
+    /// admin, poster, or mirror. (See: docs/administration.md)
+    #[structopt(long)]
+    role: Option<backend::Role>,
+}
+
+impl UserEditCommand {
+    fn main(&self) -> Result<(), Error> {
+        if self.notes.is_none() && self.on_homepage.is_none() && self.role.is_none() {
+            bail!("Nothing to change. See `feoblog user edit --help`.");
+        }
+
+        let factory = self.backend_options.factory_builder()?.factory()?;
+        let conn = factory.open()?;
+
+        let mut user = match conn.server_user(&self.user_id)? {
+            Some(user) => user,
+            None => bail!("{} is not a server user. (See: `feoblog user add`)", self.user_id),
+        };
+        if let Some(notes) = &self.notes {
+            user.notes = notes.clone();
+        }
+        if let Some(on_homepage) = self.on_homepage {
+            user.on_homepage = on_homepage;
+        }
+        if let Some(role) = self.role {
+            user.role = role;
+        }
+
+        conn.update_server_user(&user)?;
+        Ok(())
+    }
+}
+
 
 #[derive(StructOpt, Debug, Clone)]
 struct UserRemoveCommand {
@@ -179,6 +362,70 @@ impl UserRemoveCommand {
     }
 }
 
+#[derive(StructOpt, Debug, Clone)]
+struct UserExportCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    user_id: UserID,
+
+    /// The archive file to write.
+    #[structopt(long, parse(from_os_str))]
+    out: PathBuf,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+impl UserExportCommand {
+    fn main(&self) -> Result<(), Error> {
+        let factory = self.backend_options.factory_builder()?.factory()?;
+        let mut conn = factory.open()?;
+
+        let file = File::create(&self.out)
+            .with_context(|| format!("Error creating {}", self.out.display()))?;
+        let result = archive::export(conn.as_mut(), &self.user_id, BufWriter::new(file))?;
+
+        match self.format.format {
+            Format::Table => print!("{}", result),
+            Format::Json => output::print_json(&result)?,
+            Format::Csv => output::print_csv(&[result])?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct UserImportCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    /// An archive created by `feoblog user export`.
+    #[structopt(parse(from_os_str))]
+    archive: PathBuf,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+impl UserImportCommand {
+    fn main(&self) -> Result<(), Error> {
+        let factory = self.backend_options.factory_builder()?.factory()?;
+        let mut conn = factory.open()?;
+
+        let file = File::open(&self.archive)
+            .with_context(|| format!("Error opening {}", self.archive.display()))?;
+        let result = archive::import(conn.as_mut(), BufReader::new(file))?;
+
+        match self.format.format {
+            Format::Table => print!("{}", result),
+            Format::Json => output::print_json(&result)?,
+            Format::Csv => output::print_csv(&[result])?,
+        }
+        Ok(())
+    }
+}
+
 
 #[derive(StructOpt, Debug, Clone)]
 pub(crate) enum DbCommand {
@@ -188,11 +435,27 @@ pub(crate) enum DbCommand {
     /// Upgrade an old database to the latest version.
     Upgrade(DbUpgradeCommand),
 
+    /// Show the database's version, and any upgrades it needs.
+    Status(DbStatusCommand),
+
     /// Prune data from a datbase that is no longer referenced.
     Prune(DbPruneCommand),
 
     /// Report DB usage size by user.
     Usage(DbUsageCommand),
+
+    /// Back up the database. Safe to run while the server is running.
+    Backup(DbBackupCommand),
+
+    /// Copy items, attachments, and (optionally) users from another database into this one.
+    Merge(DbMergeCommand),
+
+    /// Optimize, compact, and check the database file.
+    /// With no options, runs the same light maintenance as `[maintenance]` in `serve`.
+    Maintain(DbMaintainCommand),
+
+    /// Check items and files for corruption, and derived data for consistency.
+    Verify(DbVerifyCommand),
 }
 
 impl DbCommand {
@@ -200,8 +463,13 @@ impl DbCommand {
         match self {
             Self::Init(command) => command.main(),
             Self::Upgrade(command) => command.main(),
+            Self::Status(command) => command.main(),
             Self::Prune(command) => command.main(),
             Self::Usage(command) => command.main(),
+            Self::Backup(command) => command.main(),
+            Self::Maintain(command) => command.main(),
+            Self::Merge(command) => command.main(),
+            Self::Verify(command) => command.main(),
         }
     }
 }
@@ -234,24 +502,65 @@ struct DbUpgradeCommand {
     /// Verify that you've backed up your database in case this upgrade has an error.
     #[structopt(long="i-have-a-backup")]
     i_have_a_backup: bool,
+
+    /// Back up the database to this file before upgrading it.
+    /// (Compressed with gzip if the name ends in `.gz`.)
+    #[structopt(long, parse(from_os_str))]
+    auto_backup: Option<PathBuf>,
+
+    /// Upgrade a temporary copy of the database to see whether the upgrade would succeed.
+    /// The database itself is not changed.
+    #[structopt(long)]
+    dry_run: bool,
 }
 
 
 impl DbUpgradeCommand {
     fn main(&self) -> Result<(), Error> {
+        let builder = self.backend_options.factory_builder()?;
+        if self.dry_run {
+            return builder.db_upgrade_dry_run();
+        }
 
-        if !self.i_have_a_backup {
+        if !self.i_have_a_backup && self.auto_backup.is_none() {
             println!("Please first back up your database in case there is an error during the upgrade process.");
             println!("If you have a backup, add the --i-have-a-backup option.");
+            println!("Or, use --auto-backup <file> to have feoblog take one first.");
             bail!("No backup");
         }
 
-        let builder = self.backend_options.factory_builder()?;
+        if let Some(out) = &self.auto_backup {
+            backup_database(builder.as_ref(), out, is_gzip(out))?;
+            println!("Backed up database to {}", out.display());
+        }
         builder.db_upgrade()?;
         Ok(())
     }
 }
 
+#[derive(StructOpt, Debug, Clone)]
+struct DbStatusCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    /// For csv, lists the pending upgrade steps.
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+impl DbStatusCommand {
+    fn main(&self) -> Result<(), Error> {
+        let builder = self.backend_options.factory_builder()?;
+        let status = builder.db_status()?;
+        match self.format.format {
+            Format::Table => print!("{}", status),
+            Format::Json => output::print_json(&status)?,
+            Format::Csv => output::print_csv(&status.pending)?,
+        }
+        Ok(())
+    }
+}
+
 #[derive(StructOpt, Debug, Clone)]
 struct DbPruneCommand {
     #[structopt(flatten)]
@@ -276,6 +585,27 @@ struct DbPruneCommand {
     #[structopt(long)]
     skip_unfollowed_items: bool,
 
+    /// Also delete followed users' items that retention rules don't keep.
+    /// Server users' items, and each user's latest profile, are always kept.
+    #[structopt(long)]
+    retention: bool,
+
+    /// Retention: keep items from the last this-many days.
+    #[structopt(long, requires = "retention")]
+    keep_days: Option<u64>,
+
+    /// Retention: keep each followed user's newest this-many items.
+    #[structopt(long, requires = "retention")]
+    keep_items: Option<u64>,
+
+    /// Retention: read rules from `[retention]` in this server config file.
+    /// --keep-days and --keep-items override its values.
+    #[structopt(long, parse(from_os_str), requires = "retention")]
+    config: Option<PathBuf>,
+
+    /// For csv, lists the items pruned by user.
+    #[structopt(flatten)]
+    format: output::FormatOptions,
 }
 
 impl DbPruneCommand {
@@ -284,6 +614,8 @@ impl DbPruneCommand {
             bail!("Must specify --dry-run or --exec");
         }
 
+        let retention = if self.retention { Some(self.retention_rules()?) } else { None };
+
         let builder = self.backend_options.factory_builder()?;
         let conn = builder.factory()?.open()?;
         
@@ -291,12 +623,316 @@ impl DbPruneCommand {
             dry_run: self.dry_run,
             attachments: !self.skip_unused_attachments,
             items: !self.skip_unfollowed_items,
+            retention,
+            vacuum: true,
         })?;
 
-        println!("{}", result);
+        match self.format.format {
+            Format::Table => println!("{}", result),
+            Format::Json => output::print_json(&result)?,
+            Format::Csv => output::print_csv(&result.users)?,
+        }
+
+        Ok(())
+    }
+
+    fn retention_rules(&self) -> Result<RetentionRules, Error> {
+        let mut rules = match &self.config {
+            Some(path) => config::Config::from_file(path)?.retention.rules(),
+            None => RetentionRules{ keep_days: None, keep_items: None },
+        };
+        if self.keep_days.is_some() {
+            rules.keep_days = self.keep_days;
+        }
+        if self.keep_items.is_some() {
+            rules.keep_items = self.keep_items;
+        }
+        rules.validate().context("--retention requires --keep-days and/or --keep-items, or a --config with [retention] rules")?;
+        Ok(rules)
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct DbBackupCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    /// The file to write the backup to.
+    #[structopt(long, parse(from_os_str))]
+    out: PathBuf,
+
+    /// Compress the backup with gzip. (The default if --out ends in `.gz`.)
+    #[structopt(long)]
+    gzip: bool,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+impl DbBackupCommand {
+    fn main(&self) -> Result<(), Error> {
+        let builder = self.backend_options.factory_builder()?;
+        let gzip = self.gzip || is_gzip(&self.out);
+        backup_database(builder.as_ref(), &self.out, gzip)?;
+
+        let result = BackupResult{
+            file: self.out.to_string_lossy().into(),
+            gzip,
+            bytes: std::fs::metadata(&self.out)?.len(),
+        };
+        match self.format.format {
+            Format::Table => println!("Backed up database to {}", self.out.display()),
+            Format::Json => output::print_json(&result)?,
+            Format::Csv => output::print_csv(&[result])?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+struct BackupResult {
+    file: String,
+    gzip: bool,
+    /// Size of the backup file.
+    bytes: u64,
+}
+
+impl output::CsvRow for BackupResult {
+    const HEADERS: &'static [&'static str] = &["file", "gzip", "bytes"];
+}
+
+fn is_gzip(path: &std::path::Path) -> bool {
+    path.extension().map(|e| e == "gz").unwrap_or(false)
+}
+
+fn backup_database(builder: &dyn backend::FactoryBuilder, out: &std::path::Path, gzip: bool) -> Result<(), Error> {
+    if out.exists() {
+        bail!("{} already exists", out.display());
+    }
+    backup::write(out, gzip, |temp| builder.db_backup(temp, &mut backup::print_progress))
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct DbMergeCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    /// The database to copy from. It must be the current database version.
+    #[structopt(long, parse(from_os_str))]
+    from: PathBuf,
+
+    /// Also copy the other database's server users. (See: `feoblog user add`)
+    #[structopt(long)]
+    server_users: bool,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+impl DbMergeCommand {
+    fn main(&self) -> Result<(), Error> {
+        let dest_path = std::path::Path::new(&self.backend_options.sqlite_file);
+        // If either path doesn't exist, opening it below reports why.
+        if let (Ok(dest), Ok(from)) = (dest_path.canonicalize(), self.from.canonicalize()) {
+            if dest == from {
+                bail!("Can't merge a database into itself");
+            }
+        }
+
+        let source_options = BackendOptions{ sqlite_file: self.from.to_string_lossy().into() };
+        let source = source_options.factory_builder()?.factory()
+            .with_context(|| format!("Error opening {}", self.from.display()))?
+            .open()?;
+        let mut dest = self.backend_options.factory_builder()?.factory()?.open()?;
+
+        let result = merge::merge(source.as_ref(), dest.as_mut(), merge::MergeOpts{
+            server_users: self.server_users,
+        })?;
+
+        match self.format.format {
+            Format::Table => print!("{}", result),
+            Format::Json => output::print_json(&result)?,
+            Format::Csv => output::print_csv(&[result])?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct DbMaintainCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    /// Update the query planner's statistics for all tables. (ANALYZE)
+    #[structopt(long)]
+    analyze: bool,
+
+    /// Rebuild the database file to reclaim unused space. (VACUUM)
+    /// Locks the database, and temporarily needs up to twice its size in disk space.
+    #[structopt(long)]
+    vacuum: bool,
+
+    /// Reclaim unused space without rebuilding the whole file.
+    /// The first time, this switches the database to incremental vacuuming, with a full VACUUM.
+    #[structopt(long)]
+    incremental_vacuum: bool,
+
+    /// Check the database file for corruption. (PRAGMA integrity_check)
+    #[structopt(long)]
+    integrity_check: bool,
+
+    /// Move the write-ahead log into the database file, and truncate it.
+    #[structopt(long)]
+    checkpoint: bool,
+
+    /// Analyze, vacuum, check integrity, and checkpoint.
+    #[structopt(long)]
+    all: bool,
+
+    /// For csv, lists the database's file sizes before and after.
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+impl DbMaintainCommand {
+    fn main(&self) -> Result<(), Error> {
+        let opts = if self.all {
+            MaintainOpts {
+                analyze: true,
+                optimize: false,
+                vacuum: true,
+                incremental_vacuum: false,
+                integrity_check: true,
+                checkpoint: true,
+            }
+        } else if self.analyze || self.vacuum || self.incremental_vacuum || self.integrity_check || self.checkpoint {
+            MaintainOpts {
+                analyze: self.analyze,
+                optimize: false,
+                vacuum: self.vacuum,
+                incremental_vacuum: self.incremental_vacuum,
+                integrity_check: self.integrity_check,
+                checkpoint: self.checkpoint,
+            }
+        } else {
+            MaintainOpts::light()
+        };
+
+        let builder = self.backend_options.factory_builder()?;
+        let conn = builder.factory()?.open()?;
+        let result = conn.maintain(opts)?;
+        match self.format.format {
+            Format::Table => print!("{}", result),
+            Format::Json => output::print_json(&result)?,
+            Format::Csv => {
+                let (before, after) = (&result.size_before, &result.size_after);
+                output::print_csv(vec![
+                    MaintainSizeRow{ file: "database", size_before: before.database, size_after: after.database },
+                    MaintainSizeRow{ file: "wal", size_before: before.wal, size_after: after.wal },
+                ])?;
+            },
+        }
+
+        if let Some(problems) = &result.integrity_problems {
+            if !problems.is_empty() {
+                bail!("Integrity check failed");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+struct MaintainSizeRow {
+    file: &'static str,
+    size_before: u64,
+    size_after: u64,
+}
+
+impl output::CsvRow for MaintainSizeRow {
+    const HEADERS: &'static [&'static str] = &["file", "size_before", "size_after"];
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct DbVerifyCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    /// Rebuild derived data (profiles, follows, replies, attachment lists)
+    /// that doesn't match its items.
+    #[structopt(long)]
+    repair: bool,
+
+    /// For csv, lists the problems found.
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
 
+impl DbVerifyCommand {
+    fn main(&self) -> Result<(), Error> {
+        let builder = self.backend_options.factory_builder()?;
+        let mut conn = builder.factory()?.open()?;
+
+        if self.format.format != Format::Table {
+            let mut problems = vec![];
+            let result = conn.verify(VerifyOpts{ repair: self.repair }, &mut |problem| {
+                problems.push(problem);
+                Ok(true)
+            })?;
+
+            #[derive(serde::Serialize)]
+            struct Output<'a> {
+                #[serde(flatten)]
+                result: &'a backend::VerifyResult,
+                problems: Vec<backend::VerifyProblem>,
+            }
+
+            let failed = self.failed(&result);
+            match self.format.format {
+                Format::Csv => output::print_csv(problems)?,
+                _ => output::print_json(&Output{ result: &result, problems })?,
+            }
+            if failed {
+                bail!("Database verification failed");
+            }
+            return Ok(());
+        }
+
+        let stdout = std::io::stdout();
+        let mut lock = stdout.lock();
+        let mut stream = Stream::new(&mut lock, vec![
+            col!(Row: .location).header("Location"),
+            col!(Row: .problem).header("Problem"),
+            col!(Row: .repairable).header("Repairable"),
+        ]);
+
+        struct Row {
+            location: String,
+            problem: String,
+            repairable: &'static str,
+        }
+
+        let result = conn.verify(VerifyOpts{ repair: self.repair }, &mut |problem| {
+            stream.row(Row{
+                location: problem.location,
+                problem: problem.problem,
+                repairable: if problem.repairable { "yes" } else { "no" },
+            })?;
+            Ok(true)
+        })?;
+        stream.finish()?;
+
+        println!("{}", result);
+        if self.failed(&result) {
+            bail!("Database verification failed");
+        }
         Ok(())
     }
+
+    /// Are there problems left that we didn't (or couldn't) repair?
+    fn failed(&self, result: &backend::VerifyResult) -> bool {
+        result.problems > result.repairable || (result.repairable > 0 && !self.repair)
+    }
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -312,6 +948,9 @@ struct DbUsageCommand {
     // useful if you need to make a DB query in the form of x'hexadecimal'. 
     #[structopt(long)]
     hex: bool,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
 }
 
 impl DbUsageCommand {
@@ -320,6 +959,18 @@ impl DbUsageCommand {
         let builder = self.backend_options.factory_builder()?;
         let conn = builder.factory()?.open()?;
 
+        if self.format.format != Format::Table {
+            let mut rows = vec![];
+            conn.usage_by_user(&mut |row| {
+                rows.push(row);
+                Ok(rows.len() < self.limit)
+            })?;
+            return match self.format.format {
+                Format::Csv => output::print_csv(rows),
+                _ => output::print_json(&rows),
+            };
+        }
+
         let stdout = std::io::stdout();
         let mut lock = stdout.lock();
     
@@ -366,4 +1017,524 @@ impl DbUsageCommand {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub(crate) enum KeyCommand {
+    /// Generate a new user ID, and save its secret key to an encrypted key file.
+    Generate(KeyGenerateCommand),
+}
+
+impl KeyCommand {
+    fn main(&self) -> Result<(), Error> {
+        match self {
+            Self::Generate(command) => command.main(),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct KeyGenerateCommand {
+    /// The key file to create.
+    #[structopt(long, parse(from_os_str))]
+    out: PathBuf,
+}
+
+impl KeyGenerateCommand {
+    fn main(&self) -> Result<(), Error> {
+        let password = keys::read_password("New password: ")?;
+        if std::env::var(keys::PASSWORD_VAR).is_err() {
+            let confirm = keys::read_password("Confirm password: ")?;
+            if password != confirm {
+                bail!("Passwords did not match");
+            }
+        }
+        if password.is_empty() {
+            bail!("The password may not be empty");
+        }
+
+        let key = keys::SigningKey::generate()?;
+        key.save(&self.out, &password)?;
+
+        println!("{}", key.user_id());
+        Ok(())
+    }
+}
+
+/// Options for commands that sign & upload Items.
+#[derive(StructOpt, Debug, Clone)]
+struct PublishOptions {
+    /// A key file created by `feoblog key generate`.
+    #[structopt(long, parse(from_os_str))]
+    key: PathBuf,
+
+    /// The URL of the server to upload to. ex: https://feo.example.com
+    #[structopt(long)]
+    server: String,
+}
+
+impl PublishOptions {
+    fn load_key(&self) -> Result<keys::SigningKey, Error> {
+        let password = keys::read_password(&format!("Password for {}: ", self.key.display()))?;
+        keys::SigningKey::load(&self.key, &password)
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct PostCommand {
+    #[structopt(flatten)]
+    publish_options: PublishOptions,
+
+    /// A CommonMark markdown file containing the body of the post.
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+
+    /// The post's title.
+    #[structopt(long)]
+    title: Option<String>,
+
+    /// Attach a file to the post. May be repeated.
+    /// Link to it from the body as `files/<name>`.
+    #[structopt(long, parse(from_os_str))]
+    attach: Vec<PathBuf>,
+}
+
+impl PostCommand {
+    fn main(&self) -> Result<(), Error> {
+        let body = std::fs::read_to_string(&self.file)
+            .with_context(|| format!("Error reading {}", self.file.display()))?;
+        let files = self.attach.iter()
+            .map(|path| publish::LocalFile::open(path))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let key = self.publish_options.load_key()?;
+
+        let mut item = publish::new_item();
+        let post = item.mut_post();
+        post.set_body(body);
+        if let Some(title) = &self.title {
+            post.set_title(title.clone());
+        }
+        for file in &files {
+            post.mut_attachments().mut_file().push(file.to_proto());
+        }
+
+        let (signature, bytes) = publish::sign(&key, &item)?;
+        let client = publish::Client::new(&self.publish_options.server);
+        client.put_item(key.user_id(), &signature, &bytes)?;
+        for file in &files {
+            client.put_file(key.user_id(), &signature, file)
+                .with_context(|| format!("Error uploading {}", file.path.display()))?;
+        }
+
+        println!("{}", signature.to_base58());
+        Ok(())
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub(crate) enum ProfileCommand {
+    /// Update your profile, starting from the latest version on the server.
+    Edit(ProfileEditCommand),
+}
+
+impl ProfileCommand {
+    fn main(&self) -> Result<(), Error> {
+        match self {
+            Self::Edit(command) => command.main(),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct ProfileEditCommand {
+    #[structopt(flatten)]
+    publish_options: PublishOptions,
+
+    /// Set your display name.
+    #[structopt(long)]
+    display_name: Option<String>,
+
+    /// Set your "about me" section from a CommonMark markdown file.
+    #[structopt(long, parse(from_os_str))]
+    about: Option<PathBuf>,
+
+    /// Follow a user, as `<userID>` or `<userID>=<display name>`. May be repeated.
+    #[structopt(long)]
+    follow: Vec<FollowArg>,
+
+    /// Stop following a user. May be repeated.
+    #[structopt(long)]
+    unfollow: Vec<UserID>,
+}
+
+#[derive(Debug, Clone)]
+struct FollowArg {
+    user_id: UserID,
+    display_name: Option<String>,
+}
+
+impl std::str::FromStr for FollowArg {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self, Error> {
+        let (user_id, display_name) = match value.split_once('=') {
+            Some((user_id, name)) => (user_id, Some(name.to_string())),
+            None => (value, None),
+        };
+        Ok(Self {
+            user_id: UserID::from_base58(user_id)?,
+            display_name,
+        })
+    }
+}
+
+impl ProfileEditCommand {
+    fn main(&self) -> Result<(), Error> {
+        let about = match &self.about {
+            None => None,
+            Some(path) => Some(
+                std::fs::read_to_string(path).with_context(|| format!("Error reading {}", path.display()))?
+            ),
+        };
+        if self.display_name.is_none() && about.is_none() && self.follow.is_empty() && self.unfollow.is_empty() {
+            bail!("Nothing to change. See `feoblog profile edit --help`.");
+        }
+
+        let key = self.publish_options.load_key()?;
+        let client = publish::Client::new(&self.publish_options.server);
+        let mut profile = client.get_profile(key.user_id())?
+            .map(|mut item| item.take_profile())
+            .unwrap_or_default();
+
+        if let Some(name) = &self.display_name {
+            profile.set_display_name(name.clone());
+        }
+        if let Some(about) = about {
+            profile.set_about(about);
+        }
+
+        let follows = profile.mut_follows();
+        follows.retain(|f| !self.unfollow.iter().any(|u| u.bytes() == f.get_user().get_bytes()));
+        for arg in &self.follow {
+            let index = match follows.iter().position(|f| f.get_user().get_bytes() == arg.user_id.bytes()) {
+                Some(index) => index,
+                None => {
+                    let mut follow = protos::Follow::new();
+                    follow.mut_user().set_bytes(arg.user_id.bytes().to_vec());
+                    follows.push(follow);
+                    follows.len() - 1
+                },
+            };
+            if let Some(name) = &arg.display_name {
+                follows[index].set_display_name(name.clone());
+            }
+        }
+
+        let mut item = publish::new_item();
+        item.set_profile(profile);
+        let (signature, bytes) = publish::sign(&key, &item)?;
+        client.put_item(key.user_id(), &signature, &bytes)?;
+
+        println!("{}", signature.to_base58());
+        Ok(())
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub(crate) enum ItemCommand {
+    /// List a user's items, newest first.
+    List(ItemListCommand),
+
+    /// Show an item's contents, and check its signature.
+    Show(ItemShowCommand),
+
+    /// List an item's file attachments, and whether we have their contents.
+    Files(ItemFilesCommand),
+}
+
+impl ItemCommand {
+    fn main(&self) -> Result<(), Error> {
+        match self {
+            Self::List(command) => command.main(),
+            Self::Show(command) => command.main(),
+            Self::Files(command) => command.main(),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct ItemListCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    #[structopt(long)]
+    user: UserID,
+
+    /// Only list these item types. ex: "post" or "post,comment"
+    #[structopt(long="type")]
+    item_types: Option<backend::ItemTypes>,
+
+    /// Only list items before this timestamp. (ms since the UNIX epoch, UTC)
+    #[structopt(long)]
+    before: Option<i64>,
+
+    #[structopt(long, default_value = "20")]
+    limit: usize,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+/// An item, for `item list --format json|csv`.
+#[derive(serde::Serialize)]
+struct ItemListRow {
+    signature: String,
+    timestamp_ms_utc: i64,
+    utc_offset_minutes: i32,
+    item_type: String,
+    /// Size of the item's (signed) bytes.
+    bytes: u64,
+}
+
+impl output::CsvRow for ItemListRow {
+    const HEADERS: &'static [&'static str] = &["signature", "timestamp_ms_utc", "utc_offset_minutes", "item_type", "bytes"];
+}
+
+impl ItemListCommand {
+    fn main(&self) -> Result<(), Error> {
+        let builder = self.backend_options.factory_builder()?;
+        let conn = builder.factory()?.open()?;
+
+        let before = backend::Timestamp{ unix_utc_ms: self.before.unwrap_or(i64::MAX) };
+        let item_types = self.item_types.clone().unwrap_or_default();
+
+        if self.format.format != Format::Table {
+            let mut rows = vec![];
+            conn.user_items(&self.user, backend::TimeSpan::Before(before), &item_types, &mut |row| {
+                let mut item = protos::Item::new();
+                let item_type = match item.merge_from_bytes(&row.item_bytes) {
+                    Ok(()) => format!("{:?}", item.item_type_enum()),
+                    Err(_) => "(invalid)".into(),
+                };
+                rows.push(ItemListRow{
+                    signature: row.signature.to_base58(),
+                    timestamp_ms_utc: row.timestamp.unix_utc_ms,
+                    utc_offset_minutes: item.utc_offset_minutes,
+                    item_type,
+                    bytes: row.item_bytes.len() as u64,
+                });
+                Ok(rows.len() < self.limit)
+            })?;
+            return match self.format.format {
+                Format::Csv => output::print_csv(rows),
+                _ => output::print_json(&rows),
+            };
+        }
+
+        let stdout = std::io::stdout();
+        let mut lock = stdout.lock();
+        let mut stream = Stream::new(&mut lock, vec![
+            col!(Row: .timestamp).header("Timestamp"),
+            col!(Row: .date).header("Date"),
+            col!(Row: .item_type).header("Type"),
+            col!(Row: .signature).header("Signature"),
+            col!(Row: .size).header("Size").right(),
+        ]);
+
+        struct Row {
+            timestamp: i64,
+            date: String,
+            item_type: String,
+            signature: String,
+            size: SizeDisplay,
+        }
+
+        let mut count = 0;
+        conn.user_items(&self.user, backend::TimeSpan::Before(before), &item_types, &mut |row| {
+            let mut item = protos::Item::new();
+            let item_type = match item.merge_from_bytes(&row.item_bytes) {
+                Ok(()) => format!("{:?}", item.item_type_enum()),
+                Err(_) => "(invalid)".into(),
+            };
+            stream.row(Row{
+                timestamp: row.timestamp.unix_utc_ms,
+                date: row.timestamp.format_with_offset(item.utc_offset_minutes as i16),
+                item_type,
+                signature: row.signature.to_base58(),
+                size: SizeDisplay::bytes(row.item_bytes.len() as u64).short(),
+            })?;
+            count += 1;
+            Ok(count < self.limit)
+        })?;
+
+        stream.finish()?;
+        Ok(())
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct ItemShowCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    user_id: UserID,
+    signature: backend::Signature,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+/// An item's details, for `item show --format csv`.
+#[derive(serde::Serialize)]
+struct ItemShowRow {
+    user_id: String,
+    signature: String,
+    signature_valid: bool,
+    item_type: String,
+    timestamp_ms_utc: i64,
+    utc_offset_minutes: i32,
+    received_ms_utc: i64,
+    /// Size of the item's (signed) bytes.
+    bytes: u64,
+}
+
+impl output::CsvRow for ItemShowRow {
+    const HEADERS: &'static [&'static str] = &[
+        "user_id", "signature", "signature_valid", "item_type",
+        "timestamp_ms_utc", "utc_offset_minutes", "received_ms_utc", "bytes",
+    ];
+}
+
+impl ItemShowCommand {
+    fn main(&self) -> Result<(), Error> {
+        let builder = self.backend_options.factory_builder()?;
+        let conn = builder.factory()?.open()?;
+
+        let row = match conn.user_item(&self.user_id, &self.signature)? {
+            Some(row) => row,
+            None => bail!("No such item"),
+        };
+        let valid = self.signature.is_valid(&self.user_id, &row.item_bytes);
+        let mut item = protos::Item::new();
+        item.merge_from_bytes(&row.item_bytes).context("Error parsing item")?;
+
+        match self.format.format {
+            Format::Json => output::print_json(&serde_json::json!({
+                "userId": self.user_id.to_base58(),
+                "signature": self.signature.to_base58(),
+                "signatureValid": valid,
+                "receivedMs": row.received.unix_utc_ms.to_string(),
+                "item": server::json::to_json(&item),
+            }))?,
+            Format::Csv => output::print_csv(&[ItemShowRow{
+                user_id: self.user_id.to_base58(),
+                signature: self.signature.to_base58(),
+                signature_valid: valid,
+                item_type: format!("{:?}", item.item_type_enum()),
+                timestamp_ms_utc: row.timestamp.unix_utc_ms,
+                utc_offset_minutes: item.utc_offset_minutes,
+                received_ms_utc: row.received.unix_utc_ms,
+                bytes: row.item_bytes.len() as u64,
+            }])?,
+            Format::Table => {
+                let offset = item.utc_offset_minutes as i16;
+                println!("User:      {}", self.user_id);
+                println!("Signature: {} ({})", self.signature.to_base58(), if valid { "valid" } else { "INVALID" });
+                println!("Type:      {:?}", item.item_type_enum());
+                println!("Timestamp: {} ({})", row.timestamp.unix_utc_ms, row.timestamp.format_with_offset(offset));
+                println!("Received:  {} ({})", row.received.unix_utc_ms, row.received.format_with_offset(offset));
+                println!("Size:      {}", SizeDisplay::bytes(row.item_bytes.len() as u64));
+                println!();
+                print!("{}", protobuf::text_format::print_to_string(&item));
+                println!();
+            }
+        }
+
+        if let Err(err) = item.validate() {
+            eprintln!("Warning: {}", err);
+        }
+        if !valid {
+            bail!("Invalid signature");
+        }
+        Ok(())
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct ItemFilesCommand {
+    #[structopt(flatten)]
+    backend_options: BackendOptions,
+
+    user_id: UserID,
+    signature: backend::Signature,
+
+    #[structopt(flatten)]
+    format: output::FormatOptions,
+}
+
+/// A file attachment, for `item files --format json|csv`.
+#[derive(serde::Serialize)]
+struct ItemFileRow {
+    name: String,
+    size: u64,
+    /// "yes", "no", or "invalid" if the attachment isn't indexed.
+    stored: &'static str,
+    sha512_hex: String,
+}
+
+impl output::CsvRow for ItemFileRow {
+    const HEADERS: &'static [&'static str] = &["name", "size", "stored", "sha512_hex"];
+}
+
+impl ItemFilesCommand {
+    fn main(&self) -> Result<(), Error> {
+        let builder = self.backend_options.factory_builder()?;
+        let conn = builder.factory()?.open()?;
+
+        let row = match conn.user_item(&self.user_id, &self.signature)? {
+            Some(row) => row,
+            None => bail!("No such item"),
+        };
+        let mut item = protos::Item::new();
+        item.merge_from_bytes(&row.item_bytes).context("Error parsing item")?;
+
+        let mut rows = vec![];
+        for file in item.get_post().get_attachments().get_file() {
+            let meta = conn.get_attachment_meta(&self.user_id, &self.signature, &file.name)?;
+            rows.push(ItemFileRow{
+                name: file.name.clone(),
+                size: file.size,
+                stored: match meta {
+                    Some(meta) if meta.exists => "yes",
+                    Some(_) => "no",
+                    // Not indexed, because the attachment is invalid.
+                    None => "invalid",
+                },
+                sha512_hex: file.hash.as_slice().as_hex().to_string(),
+            });
+        }
+
+        match self.format.format {
+            Format::Json => return output::print_json(&rows),
+            Format::Csv => return output::print_csv(rows),
+            Format::Table => {},
+        }
+
+        let stdout = std::io::stdout();
+        let mut lock = stdout.lock();
+        let mut stream = Stream::new(&mut lock, vec![
+            col!(ItemFileRow: .name).header("Name"),
+            Column::new(|f, r: &ItemFileRow| {
+                write!(f, "{}", SizeDisplay::bytes(r.size).short())?;
+                Ok(())
+            }).header("Size").right(),
+            col!(ItemFileRow: .stored).header("Stored"),
+            col!(ItemFileRow: .sha512_hex).header("SHA-512 (hex)"),
+        ]);
+        for row in rows {
+            stream.row(row)?;
+        }
+        stream.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Merging one FeoBlog database into another, for `feoblog db merge`.
+//!
+//! Like `user import`, we don't trust the source database. Every item's
+//! signature and every file's hash is checked before it's copied. Items are
+//! saved through `Backend::save_user_item()`, so the destination's derived
+//! data (profiles, follows, replies, attachment lists) is rebuilt as usual.
+
+use std::{collections::HashSet, fmt, io::{Seek, SeekFrom}};
+
+use anyhow::{Context, Error, bail};
+use protobuf::Message;
+use serde::Serialize;
+
+use crate::{backend::{Backend, ItemRow, SHA512, Timestamp}, output::CsvRow, protos::{Item, ProtoValid}};
+
+/// How many items to save per transaction.
+const BATCH_SIZE: usize = 1000;
+
+pub(crate) struct MergeOpts {
+    /// Also copy `server_user` rows that the destination doesn't have.
+    pub server_users: bool,
+}
+
+/// Copy everything from `source` that `dest` doesn't already have.
+pub(crate) fn merge(source: &dyn Backend, dest: &mut dyn Backend, opts: MergeOpts) -> Result<MergeResult, Error> {
+    let mut result = MergeResult::default();
+
+    if opts.server_users {
+        source.server_users(&mut |user| {
+            if dest.server_user(&user.user)?.is_some() {
+                result.existing_server_users += 1;
+            } else {
+                dest.add_server_user(&user)?;
+                result.server_users += 1;
+            }
+            Ok(true)
+        })?;
+    }
+
+    let mut copied_files = HashSet::new();
+    let mut batch = vec![];
+    source.all_items(&mut |row| {
+        let item = match check_item(&row) {
+            Ok(item) => item,
+            Err(err) => {
+                eprintln!("Skipping /u/{}/i/{}: {:#}", row.user, row.signature.to_base58(), err);
+                result.invalid_items += 1;
+                return Ok(true);
+            },
+        };
+        batch.push((row, item));
+        if batch.len() >= BATCH_SIZE {
+            save_batch(source, dest, &mut batch, &mut copied_files, &mut result)?;
+        }
+        Ok(true)
+    })?;
+    save_batch(source, dest, &mut batch, &mut copied_files, &mut result)?;
+
+    Ok(result)
+}
+
+fn check_item(row: &ItemRow) -> Result<Item, Error> {
+    if !row.signature.is_valid(&row.user, &row.item_bytes) {
+        bail!("Invalid signature");
+    }
+    let mut item = Item::new();
+    item.merge_from_bytes(&row.item_bytes)?;
+    item.validate()?;
+    Ok(item)
+}
+
+fn save_batch(
+    source: &dyn Backend,
+    dest: &mut dyn Backend,
+    batch: &mut Vec<(ItemRow, Item)>,
+    copied_files: &mut HashSet<Vec<u8>>,
+    result: &mut MergeResult,
+) -> Result<(), Error> {
+    dest.transaction(&mut |dest| {
+        for (row, item) in batch.iter() {
+            if dest.user_item_exists(&row.user, &row.signature)? {
+                result.existing_items += 1;
+                continue;
+            }
+            let new_row = ItemRow {
+                user: row.user.clone(),
+                signature: row.signature.clone(),
+                timestamp: row.timestamp,
+                received: Timestamp::now(),
+                item_bytes: row.item_bytes.clone(),
+            };
+            dest.save_user_item(&new_row, item)?;
+            result.items += 1;
+        }
+        Ok(())
+    })?;
+
+    // Existing items may still be missing their attachments, so check those too:
+    for (row, item) in batch.drain(..) {
+        for file in item.get_post().get_attachments().get_file() {
+            if copied_files.contains(&file.hash) {
+                continue;
+            }
+            copy_file(source, dest, &row, &file.name, result)
+                .with_context(|| format!("Error copying /u/{}/i/{}/files/{}", row.user, row.signature.to_base58(), file.name))?;
+            copied_files.insert(file.hash.clone());
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_file(source: &dyn Backend, dest: &mut dyn Backend, row: &ItemRow, name: &str, result: &mut MergeResult) -> Result<(), Error> {
+    let meta = match dest.get_attachment_meta(&row.user, &row.signature, name)? {
+        Some(meta) => meta,
+        // Invalid attachments aren't indexed.
+        None => return Ok(()),
+    };
+    if meta.exists {
+        result.existing_files += 1;
+        return Ok(());
+    }
+
+    let mut temp = tempfile::tempfile().context("Error opening temp file")?;
+    if !source.copy_attachment(&meta.hash, &mut temp)? {
+        result.missing_files += 1;
+        return Ok(());
+    }
+    let size = temp.seek(SeekFrom::End(0))?;
+    if size != meta.size {
+        bail!("Expected {} bytes but found {}", meta.size, size);
+    }
+    if SHA512::from_file(&mut temp)? != meta.hash {
+        bail!("Invalid data. Expected {}", meta.hash);
+    }
+
+    temp.seek(SeekFrom::Start(0))?;
+    dest.save_attachment(meta.size, &meta.hash, &mut temp)?;
+    result.files += 1;
+    result.file_bytes += size;
+    Ok(())
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct MergeResult {
+    items: usize,
+    existing_items: usize,
+    invalid_items: usize,
+    files: usize,
+    file_bytes: u64,
+    existing_files: usize,
+    missing_files: usize,
+    server_users: usize,
+    existing_server_users: usize,
+}
+
+impl CsvRow for MergeResult {
+    const HEADERS: &'static [&'static str] = &[
+        "items", "existing_items", "invalid_items",
+        "files", "file_bytes", "existing_files", "missing_files",
+        "server_users", "existing_server_users",
+    ];
+}
+
+impl fmt::Display for MergeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Copied {} items ({} already existed).", self.items, self.existing_items)?;
+        writeln!(f, "Copied {} files, {} bytes ({} already existed).", self.files, self.file_bytes, self.existing_files)?;
+        if self.invalid_items > 0 {
+            writeln!(f, "Skipped {} invalid items.", self.invalid_items)?;
+        }
+        if self.missing_files > 0 {
+            writeln!(f, "{} attachments were not available in the source database.", self.missing_files)?;
+        }
+        if self.server_users + self.existing_server_users > 0 {
+            writeln!(f, "Added {} server users ({} already existed).", self.server_users, self.existing_server_users)?;
+        }
+        Ok(())
+    }
+}
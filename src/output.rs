@@ -0,0 +1,142 @@
+//! Machine-readable output for admin commands. (`--format json|csv`)
+//!
+//! Commands still print their own `tablestream` tables for `--format table`.
+//! For the other formats, they pass their (serde-serializable) results here.
+
+use std::{fmt, io::Write, str::FromStr};
+
+use anyhow::{Error, bail};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::backend;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "table" => Self::Table,
+            "json" => Self::Json,
+            "csv" => Self::Csv,
+            _ => bail!("Unknown format {:?}. Expected table, json, or csv.", value),
+        })
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Table => write!(f, "table"),
+            Self::Json => write!(f, "json"),
+            Self::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub(crate) struct FormatOptions {
+    /// Output format: table, json, or csv.
+    #[structopt(long, default_value = "table")]
+    pub format: Format,
+}
+
+/// Print a value as (pretty) JSON.
+pub(crate) fn print_json<T: Serialize>(value: &T) -> Result<(), Error> {
+    let stdout = std::io::stdout();
+    let mut lock = stdout.lock();
+    serde_json::to_writer_pretty(&mut lock, value)?;
+    writeln!(lock)?;
+    Ok(())
+}
+
+/// A row that can be printed with `print_csv()`.
+/// Rows must be "flat" structs. (No nested structs or lists.)
+pub(crate) trait CsvRow: Serialize {
+    /// Column names. Must match the struct's (serialized) fields, in order.
+    /// We can't get these from serde without a row, and we want a header even when there are no rows.
+    const HEADERS: &'static [&'static str];
+}
+
+impl<T: CsvRow> CsvRow for &T {
+    const HEADERS: &'static [&'static str] = T::HEADERS;
+}
+
+/// Print rows as CSV, with a header row.
+pub(crate) fn print_csv<T, I>(rows: I) -> Result<(), Error>
+where
+    T: CsvRow,
+    I: IntoIterator<Item=T>,
+{
+    let stdout = std::io::stdout();
+    write_csv(stdout.lock(), rows)
+}
+
+fn write_csv<T, I, W>(out: W, rows: I) -> Result<(), Error>
+where
+    T: CsvRow,
+    I: IntoIterator<Item=T>,
+    W: Write,
+{
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+    writer.write_record(T::HEADERS)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+impl CsvRow for backend::UpgradeStep {
+    const HEADERS: &'static [&'static str] = &["from_version", "to_version", "description"];
+}
+
+impl CsvRow for backend::PruneUserRow {
+    const HEADERS: &'static [&'static str] = &["user_id", "display_name", "reason", "items_count", "items_bytes"];
+}
+
+impl CsvRow for backend::VerifyProblem {
+    const HEADERS: &'static [&'static str] = &["location", "problem", "repairable"];
+}
+
+impl CsvRow for backend::UsageByUserRow {
+    const HEADERS: &'static [&'static str] = &[
+        "user_id", "display_name", "server_user", "known_user",
+        "attachments_count", "attachments_bytes", "items_count", "items_bytes", "total_bytes",
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::VerifyProblem;
+
+    use super::{CsvRow, Format, write_csv};
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert_eq!("csv".parse::<Format>().unwrap(), Format::Csv);
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    fn csv_string<T: CsvRow>(rows: Vec<T>) -> String {
+        let mut out = vec![];
+        write_csv(&mut out, rows).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_headers() {
+        let none: Vec<VerifyProblem> = vec![];
+        assert_eq!(csv_string(none), "location,problem,repairable\n");
+
+        let problem = VerifyProblem{ location: "/u/x".into(), problem: "Bad".into(), repairable: true };
+        assert_eq!(csv_string(vec![problem]), "location,problem,repairable\n/u/x,Bad,true\n");
+    }
+}
@@ -58,6 +58,18 @@ impl ProtoValid for Profile {
     }
 }
 
+impl Item {
+    /// The ItemType that corresponds to this Item's `item_type` oneof.
+    pub(crate) fn item_type_enum(&self) -> ItemType {
+        match self.item_type {
+            Some(Item_oneof_item_type::post(_)) => ItemType::POST,
+            Some(Item_oneof_item_type::profile(_)) => ItemType::PROFILE,
+            Some(Item_oneof_item_type::comment(_)) => ItemType::COMMENT,
+            None => ItemType::UNKNOWN,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct ValidationError {
     message: Cow<'static, str>,
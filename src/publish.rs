@@ -0,0 +1,163 @@
+//! Building, signing, and uploading Items from the command line.
+//! (`feoblog post` and `feoblog profile edit`)
+
+use std::{fs::File, io::Read, path::{Path, PathBuf}};
+
+use anyhow::{Context, Error, bail, format_err};
+use protobuf::Message;
+
+use crate::{backend::{SHA512, Signature, Timestamp, UserID}, keys::SigningKey, protos::{Item, ProtoValid}};
+
+/// A new Item, dated now, in the local time zone.
+pub(crate) fn new_item() -> Item {
+    let mut item = Item::new();
+    item.set_timestamp_ms_utc(Timestamp::now().unix_utc_ms);
+    // If we can't tell, fall back to the default. (UTC)
+    if let Ok(offset) = time::UtcOffset::try_current_local_offset() {
+        item.set_utc_offset_minutes(offset.as_minutes().into());
+    }
+    item
+}
+
+/// Validate and sign an Item.
+pub(crate) fn sign(key: &SigningKey, item: &Item) -> Result<(Signature, Vec<u8>), Error> {
+    item.validate()?;
+    let bytes = item.write_to_bytes()?;
+    Ok((key.sign(&bytes), bytes))
+}
+
+/// A local file to attach to a Post.
+pub(crate) struct LocalFile {
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    pub hash: SHA512,
+}
+
+impl LocalFile {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let name = path.file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| format_err!("Invalid file name: {}", path.display()))?
+            .to_string();
+
+        let mut file = File::open(path)
+            .with_context(|| format!("Error opening {}", path.display()))?;
+        let size = file.metadata()?.len();
+        if size == 0 {
+            bail!("Can't attach empty file {}", path.display());
+        }
+        let hash = SHA512::from_file(&mut file)
+            .with_context(|| format!("Error reading {}", path.display()))?;
+
+        Ok(Self { path: path.to_owned(), name, size, hash })
+    }
+
+    pub fn to_proto(&self) -> crate::protos::File {
+        let mut file = crate::protos::File::new();
+        file.set_name(self.name.clone());
+        file.set_size(self.size);
+        file.set_hash(self.hash.bytes().to_vec());
+        file
+    }
+}
+
+/// A (blocking) client for a FeoBlog server's REST API.
+pub(crate) struct Client {
+    server: String,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    /// `server` is the server's base URL. ex: "https://feo.example.com"
+    pub fn new(server: &str) -> Self {
+        Self {
+            server: server.trim_end_matches('/').to_string(),
+            agent: ureq::agent(),
+        }
+    }
+
+    /// Fetch (and verify) the latest Profile that the server has for a user.
+    pub fn get_profile(&self, user_id: &UserID) -> Result<Option<Item>, Error> {
+        let url = format!("{}/u/{}/profile/proto3", self.server, user_id);
+        let response = match self.agent.get(&url).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(err) => return Err(request_error(&url, err)),
+        };
+
+        let signature = response.header("signature")
+            .ok_or_else(|| format_err!("Server didn't send the profile's signature"))?;
+        let signature = Signature::from_base58(signature)?;
+
+        let mut bytes = vec![];
+        response.into_reader().read_to_end(&mut bytes)?;
+        if !signature.is_valid(user_id, &bytes) {
+            bail!("Invalid signature on profile from {}", url);
+        }
+
+        let mut item = Item::new();
+        item.merge_from_bytes(&bytes)?;
+        if !item.has_profile() {
+            bail!("Expected a Profile from {}", url);
+        }
+        Ok(Some(item))
+    }
+
+    pub fn put_item(&self, user_id: &UserID, signature: &Signature, bytes: &[u8]) -> Result<(), Error> {
+        let url = format!("{}/u/{}/i/{}/proto3", self.server, user_id, signature.to_base58());
+        self.agent.put(&url)
+            .set("Content-Type", "application/protobuf3")
+            .send_bytes(bytes)
+            .map_err(|err| request_error(&url, err))?;
+        Ok(())
+    }
+
+    pub fn put_file(&self, user_id: &UserID, signature: &Signature, file: &LocalFile) -> Result<(), Error> {
+        let url = format!(
+            "{}/u/{}/i/{}/files/{}",
+            self.server, user_id, signature.to_base58(), encode_path(&file.name),
+        );
+        let contents = File::open(&file.path)
+            .with_context(|| format!("Error opening {}", file.path.display()))?;
+        self.agent.put(&url)
+            .set("Content-Length", &file.size.to_string())
+            .send(contents)
+            .map_err(|err| request_error(&url, err))?;
+        Ok(())
+    }
+}
+
+fn request_error(url: &str, err: ureq::Error) -> Error {
+    match err {
+        ureq::Error::Status(code, response) => {
+            let message = response.into_string().unwrap_or_default();
+            format_err!("{} from {}: {}", code, url, message.trim())
+        },
+        err => Error::new(err).context(format!("Error requesting {}", url)),
+    }
+}
+
+/// Percent-encode a file name for use in a URL path.
+fn encode_path(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode_path;
+
+    #[test]
+    fn encoded_paths() {
+        assert_eq!(encode_path("photo.jpg"), "photo.jpg");
+        assert_eq!(encode_path("my photo#1.jpg"), "my%20photo%231.jpg");
+        assert_eq!(encode_path("é"), "%C3%A9");
+    }
+}
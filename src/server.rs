@@ -1,4 +1,4 @@
-use std::{borrow::Cow, fmt, fmt::Write, marker::PhantomData, net::TcpListener, ops::{Deref, DerefMut}};
+use std::{borrow::Cow, fmt, fmt::Write, marker::PhantomData, ops::{Deref, DerefMut}, sync::Arc};
 
 use askama_actix::actix_web::http::header::HeaderValue;
 use backend::FactoryBox;
@@ -10,6 +10,7 @@ use actix_web::http::{Method, header::{self, ContentType}};
 use actix_web::web::{
     self,
     get,
+    post,
     put,
     route,
     Data,
@@ -21,8 +22,8 @@ use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
 
 use actix_web::{App, HttpServer, Responder};
 use askama_actix::Template;
-use anyhow::{Context, format_err};
-use log::debug;
+use anyhow::format_err;
+use log::{debug, error, info};
 use logging_timer::timer;
 use rust_embed::RustEmbed;
 use serde::Deserialize;
@@ -32,40 +33,95 @@ use async_trait::async_trait;
 
 use protobuf::Message;
 
-use crate::{ServeCommand, backend::{ItemDisplayRow, TimeSpan}, protos::{ItemList, ItemListEntry, ItemType, Item_oneof_item_type}};
+use crate::{BackendOptions, ServeCommand, backend::{ItemDisplayRow, TimeSpan}, config::{Config, CorsConfig, LiveConfig}, protos::{ItemList, ItemListEntry, ItemType, Item_oneof_item_type}};
 use crate::backend::{self, UserID, Signature, ItemRow, Timestamp};
 use crate::protos::{Item, ProtoValid};
 
 mod attachments;
+mod backups;
 mod client;
+mod digest;
 mod html;
+pub(crate) mod json;
+mod listeners;
+mod maintenance;
+mod metrics;
 mod pagination;
+mod rate_limit;
 mod rest;
+mod retention;
 mod non_standard;
+mod tls;
+mod uploads;
 
 use pagination::Paginator;
 
 pub(crate) fn serve(command: ServeCommand) -> Result<(), anyhow::Error> {
 
-    env_logger::init();
+    let config = Config::for_command(&command)?;
+    init_logging(&config);
     sodiumoxide::init().expect("sodiumoxide::init()");
 
-    let ServeCommand{open, backend_options, mut binds} = command;
-
+    let backend_options = BackendOptions{ sqlite_file: config.server.sqlite_file.clone() };
     let factory_box = FactoryBox{
         factory: backend_options.factory_builder()?.factory()?
     };
 
-    let app_factory = move || {
-        let data = Data::new(
+    let binds = parse_binds(&config.server.binds)?;
+    let tls_binds = parse_binds(&config.tls.binds)?;
+    let redirect_binds = parse_binds(&config.tls.redirect_binds)?;
+    let open = config.server.open;
+    let server_config = config.server.clone();
+
+    let certs = if tls_binds.is_empty() {
+        None
+    } else {
+        Some(Arc::new(tls::CertResolver::new(&config.tls)?))
+    };
+    let cert_check_interval = config.tls.check_interval_secs;
+    let metrics_binds = parse_binds(&config.metrics.binds)?;
+    let metrics_config = config.metrics.clone();
+    let uploads = Arc::new(uploads::Uploads::new(config.uploads.dir(&config.server))?);
+    let uploads_max_age = std::time::Duration::from_secs(config.uploads.expire_hours * 60 * 60);
+    let backup_config = config.backup.clone();
+    let backup_dir = config.backup.dir(&config.server);
+    let maintenance_config = config.maintenance.clone();
+    let retention_config = config.retention.clone();
+    let live_config = Arc::new(LiveConfig::new(command, config));
+    let metrics = Arc::new(metrics::Metrics::new()?);
+    let rate_limiter = Arc::new(rate_limit::RateLimiter::new());
+
+    let app_data = {
+        let factory_box = factory_box.clone();
+        let config = live_config.clone();
+        let metrics = metrics.clone();
+        let uploads = uploads.clone();
+        move || Data::new(
             AppData{
                 backend_factory: factory_box.factory.dyn_clone(),
+                config: config.clone(),
+                metrics: metrics.clone(),
+                rate_limiter: rate_limiter.clone(),
+                uploads: uploads.clone(),
             }
-        );
+        )
+    };
+
+    let app_config = live_config.clone();
+    let app_metrics = metrics.clone();
+    let main_app_data = app_data.clone();
+    let app_factory = move || {
+        let data = main_app_data();
+        // Routes and middleware are only configured at startup:
+        let config = app_config.get();
         let mut app = App::new()
-            .wrap(actix_web::middleware::Logger::default())
+            .wrap_fn(metrics::record_requests(app_metrics.clone()))
+            .wrap(actix_web::middleware::Condition::new(
+                config.logging.access_log,
+                actix_web::middleware::Logger::default(),
+            ))
             .app_data(data)
-            .configure(routes)
+            .configure(|cfg| routes(cfg, &config))
         ;
 
         app = app.default_service(route().to(|| html::file_not_found("")));
@@ -73,59 +129,190 @@ pub(crate) fn serve(command: ServeCommand) -> Result<(), anyhow::Error> {
         return app;
     };
 
-    if binds.is_empty() {
-        binds.push("127.0.0.1:8080".into());
-    }
+    // In-flight requests (ex: large attachment uploads) get this long to
+    // finish when we receive a SIGTERM/SIGINT:
+    let server = HttpServer::new(app_factory)
+        .shutdown_timeout(server_config.shutdown_timeout_secs); 
+    let mut server = listeners::listen_all(server, &binds, &server_config)?;
 
-    let mut server = HttpServer::new(app_factory); 
-    
-    for bind in &binds {
-        let socket = open_socket(bind).with_context(|| {
-            format!("Error binding to address/port: {}", bind)
-        })?;
-        server = server.listen(socket)?;
+    if let Some(certs) = &certs {
+        for bind in &tls_binds {
+            for listener in listeners::open(bind, &server_config)? {
+                server = server.listen_rustls(listener.into_tcp()?, certs.server_config())?;
+            }
+        }
     }
 
+    // A separate, minimal server that just sends plain HTTP requests to HTTPS:
+    let redirect_server = if redirect_binds.is_empty() {
+        None
+    } else {
+        let https_port = tls_binds.iter()
+            .filter_map(|bind| match bind {
+                listeners::Bind::Tcp(addr) => Some(addr.port()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(443);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(Data::new(tls::HttpsPort(https_port)))
+                .default_service(route().to(tls::redirect_to_https))
+        }).shutdown_timeout(server_config.shutdown_timeout_secs);
+        Some(listeners::listen_all(server, &redirect_binds, &server_config)?)
+    };
+
+    // Metrics may be served separately, so they're not exposed to the public:
+    let metrics_server = if metrics_binds.is_empty() {
+        None
+    } else {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(app_data())
+                .route("/metrics", get().to(metrics::get_metrics))
+        });
+        Some(listeners::listen_all(server, &metrics_binds, &server_config)?)
+    };
+
     if open {
         // TODO: This opens up a (AFAICT) blocking CLI browser on Linux. Boo. Don't do that.
         // TODO: Handle wildcard addresses (0.0.0.0, ::0) and --open them via localhost.
-        let url = format!("http://{}/", binds[0]);
-        let opened = webbrowser::open(&url);
-        if !opened.is_ok() {
+        let http = binds.iter().map(|b| ("http", b));
+        let https = tls_binds.iter().map(|b| ("https", b));
+        let url = http.chain(https).find_map(|(scheme, bind)| match bind {
+            listeners::Bind::Tcp(addr) => Some(format!("{}://{}/", scheme, addr)),
+            _ => None,
+        });
+        let opened = match url {
+            Some(url) => webbrowser::open(&url).is_ok(),
+            None => false,
+        };
+        if !opened {
             println!("Warning: Couldn't open browser.");
         }
     }
 
     for bind in &binds {
-        println!("Started at: http://{}/", bind);
+        println!("Started at: {}", bind_url("http", bind));
+    }
+    for bind in &tls_binds {
+        println!("Started at: {}", bind_url("https", bind));
+    }
+    for bind in &redirect_binds {
+        println!("Redirecting to HTTPS from: {}", bind_url("http", bind));
+    }
+    for bind in &metrics_binds {
+        println!("Metrics at: {}metrics", bind_url("http", bind));
     }
  
     let system = actix_web::rt::System::new();
-    system.block_on(server.run())?;
+    let mut servers = vec![server.run()];
+    servers.extend(redirect_server.map(|s| s.run()));
+    servers.extend(metrics_server.map(|s| s.run()));
+    system.block_on(async move {
+        if backup_config.enabled {
+            backups::schedule(factory_box.clone(), backup_config, backup_dir);
+        }
+        if maintenance_config.enabled {
+            maintenance::schedule(
+                factory_box.clone(),
+                std::time::Duration::from_secs(maintenance_config.interval_hours * 60 * 60),
+            );
+        }
+        if retention_config.enabled {
+            retention::schedule(
+                factory_box.clone(),
+                retention_config.rules(),
+                std::time::Duration::from_secs(retention_config.interval_hours * 60 * 60),
+            );
+        }
+        if metrics_config.enabled {
+            metrics::update_db_stats(
+                metrics,
+                factory_box,
+                std::time::Duration::from_secs(metrics_config.db_stats_interval_secs),
+            );
+        }
+        if let Some(certs) = &certs {
+            if cert_check_interval > 0 {
+                tls::watch_for_changes(certs.clone(), std::time::Duration::from_secs(cert_check_interval));
+            }
+        }
+        uploads::remove_expired(uploads, uploads_max_age);
+        reload_on_hangup(live_config, certs);
+        futures::future::try_join_all(servers).await.map(|_| ())
+    })?;
+
+    for bind in binds.iter().chain(&tls_binds).chain(&redirect_binds).chain(&metrics_binds) {
+        listeners::close(bind);
+    }
    
     Ok(())
 }
 
-// Work around https://github.com/actix/actix-web/issues/1913
-fn open_socket(bind: &str) -> Result<TcpListener, anyhow::Error> {
-    use socket2::{Domain, Protocol, Socket, Type};
-    use std::net::SocketAddr;
-    
-    // Eh, this is what actix was using:
-    let backlog = 1024;
-    
-    let addr = bind.parse()?;
-    let domain = match addr {
-        SocketAddr::V4(_) => Domain::IPV4,
-        SocketAddr::V6(_) => Domain::IPV6,
-    };
-    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
-    socket.bind(&addr.into())?;
-    socket.listen(backlog)?;
+fn parse_binds(binds: &[String]) -> Result<Vec<listeners::Bind>, anyhow::Error> {
+    binds.iter().map(|b| b.parse()).collect()
+}
+
+/// A human-readable location for a bind, for logging.
+fn bind_url(scheme: &str, bind: &listeners::Bind) -> String {
+    match bind {
+        listeners::Bind::Tcp(addr) => format!("{}://{}/", scheme, addr),
+        other => format!("{} ({})", other, scheme),
+    }
+}
+
+fn init_logging(config: &Config) {
+    let mut builder = env_logger::Builder::new();
+    if let Some(filter) = &config.logging.filter {
+        builder.parse_filters(filter);
+    }
+    // RUST_LOG, if set, takes precedence:
+    if let Ok(filter) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filter);
+    }
+    builder.init();
+}
+
+/// Reload the config file and TLS certificates when we receive a SIGHUP.
+#[cfg(unix)]
+fn reload_on_hangup(config: Arc<LiveConfig>, certs: Option<Arc<tls::CertResolver>>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
 
-    Ok(socket.into())
+    if config.path().is_none() && certs.is_none() {
+        // Nothing to reload.
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                error!("Couldn't listen for SIGHUP: {}", err);
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            if config.path().is_some() {
+                match config.reload() {
+                    Ok(()) => info!("Reloaded config."),
+                    Err(err) => error!("Error reloading config: {:#}", err),
+                }
+            }
+            if let Some(certs) = &certs {
+                match certs.reload() {
+                    Ok(()) => info!("Reloaded TLS certificate."),
+                    Err(err) => error!("Error reloading TLS certificate: {:#}", err),
+                }
+            }
+        }
+    });
 }
 
+#[cfg(not(unix))]
+fn reload_on_hangup(_config: Arc<LiveConfig>, _certs: Option<Arc<tls::CertResolver>>) {}
+
 /// Data available for our whole application.
 /// Gets stored in a Data<AppData>
 // This is so that we have typesafe access to AppData fields, because actix
@@ -133,23 +320,30 @@ fn open_socket(bind: &str) -> Result<TcpListener, anyhow::Error> {
 // yourself.
 pub(crate) struct AppData {
     backend_factory: Box<dyn backend::Factory>,
+    config: Arc<LiveConfig>,
+    metrics: Arc<metrics::Metrics>,
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+    uploads: Arc<uploads::Uploads>,
 }
 
-fn routes(cfg: &mut web::ServiceConfig) {
+fn routes(cfg: &mut web::ServiceConfig, config: &Config) {
+    let cors = || cors_ok_headers(&config.cors);
+
     cfg
         .route("/", get().to(html::view_homepage))
 
+        // REST endpoints that end in {format} can also return JSON. See: json.rs
         .service(
-            web::resource("/homepage/proto3")
+            web::resource("/homepage/{format:proto3|json}")
             .route(get().to(rest::homepage_item_list))
-            .wrap(cors_ok_headers())
+            .wrap(cors())
         )
 
         .route("/u/{user_id}/", get().to(html::get_user_items))
         .service(
-            web::resource("/u/{user_id}/proto3")
+            web::resource("/u/{user_id}/{format:proto3|json}")
             .route(get().to(rest::user_item_list))
-            .wrap(cors_ok_headers())
+            .wrap(cors())
         )
 
         .service(
@@ -164,33 +358,102 @@ fn routes(cfg: &mut web::ServiceConfig) {
             .route(get().to(rest::get_item))
             .route(put().to(rest::put_item))
             .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
-            .wrap(cors_ok_headers())
+            .wrap_fn(rate_limit::limit_uploads)
+            .wrap(cors())
             .wrap_fn(immutable_etag)
         )
         .service(
-            web::resource("/u/{user_id}/i/{signature}/replies/proto3")
+            web::resource("/u/{userID}/i/{signature}/{format:json}")
+            .route(get().to(rest::get_item))
+            .wrap(cors())
+            .wrap_fn(immutable_etag)
+        )
+        .service(
+            web::resource("/u/{user_id}/i/{signature}/replies/{format:proto3|json}")
             .route(get().to(rest::item_reply_list))
-            .wrap(cors_ok_headers())
+            .wrap(cors())
         ).service(
             web::resource("/u/{user_id}/i/{signature}/files/{file_name}")
             .route(get().to(attachments::get_file))
             .route(put().to(attachments::put_file))
             .route(route().method(Method::HEAD).to(attachments::head_file))
             .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
-            .wrap(cors_ok_headers())
+            .wrap_fn(rate_limit::limit_uploads)
+            .wrap(cors())
             .wrap_fn(immutable_etag)
         )
+        .service(
+            web::resource("/u/{user_id}/i/{signature}/files/{file_name}/upload")
+            .route(post().to(uploads::create_upload))
+            .route(route().method(Method::HEAD).to(uploads::upload_status))
+            .route(route().method(Method::PATCH).to(uploads::upload_chunk))
+            .route(route().method(Method::DELETE).to(uploads::delete_upload))
+            .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap_fn(rate_limit::limit_uploads)
+            .wrap(cors())
+        )
+
+        .service(
+            web::resource("/u/{user_id}/digest/{format:proto3|json}")
+            .route(get().to(rest::user_item_digests))
+            .wrap(cors())
+        )
 
         .route("/u/{user_id}/profile/", get().to(html::show_profile))
         .service(
-            web::resource("/u/{user_id}/profile/proto3")
+            web::resource("/u/{user_id}/profile/{format:proto3|json}")
             .route(get().to(rest::get_profile_item))
-            .wrap(cors_ok_headers())
+            .wrap(cors())
         )
         .route("/u/{user_id}/feed/", get().to(html::get_user_feed))
-        .route("/u/{user_id}/feed/proto3", get().to(rest::feed_item_list))
+        .route("/u/{user_id}/feed/{format:proto3|json}", get().to(rest::feed_item_list))
+
+        .service(
+            web::resource("/changes/{format:proto3|json}")
+            .route(get().to(rest::changes))
+            .wrap(cors())
+        )
+
+        .service(
+            web::resource("/items/batch/proto3")
+            .route(post().to(rest::item_batch))
+            .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap(cors())
+        )
+
+        .service(
+            web::resource("/items/upload/proto3")
+            .route(post().to(rest::upload_items))
+            .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap_fn(rate_limit::limit_uploads)
+            .wrap(cors())
+        )
+
+        .service(
+            web::resource("/have/proto3")
+            .route(post().to(rest::have))
+            .route(route().method(Method::OPTIONS).to(cors_preflight_allow))
+            .wrap(cors())
+        )
+
+        .service(
+            web::resource("/server/info/proto3")
+            .route(get().to(rest::server_info))
+            .wrap(cors())
+        )
+        .service(
+            web::resource("/server/info/json")
+            .route(get().to(rest::server_info_json))
+            .wrap(cors())
+        )
 
     ;
+
+    // If metrics have their own binds, they're only served there:
+    if config.metrics.enabled && config.metrics.binds.is_empty() {
+        cfg.route("/metrics", get().to(metrics::get_metrics));
+    }
+
     statics(cfg);
 }
 
@@ -383,28 +646,27 @@ fn statics(cfg: &mut web::ServiceConfig) {
 
 // // CORS headers must be present for *all* responses, including 404, 500, etc.
 // // Applying it to each case individiaully may be error-prone, so here's a filter to do so for us.
-fn cors_ok_headers() -> DefaultHeaders {
+fn cors_ok_headers(cors: &CorsConfig) -> DefaultHeaders {
     DefaultHeaders::new()
-    .add(("Access-Control-Allow-Origin", "*"))
+    .add(("Access-Control-Allow-Origin", cors.allow_origin.as_str()))
     .add(("Access-Control-Expose-Headers", "*"))
 
     // Number of seconds a browser can cache the cors allows.
     // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Access-Control-Max-Age
-    // FF caps this at 24 hours, and is the most permissive there, so that's what we'll use.
     // Does this mean that my Cache-Control max-age is truncated to this value? That would be sad.
-    .add(("Access-Control-Max-Age", "86400"))
+    .add(("Access-Control-Max-Age", cors.max_age_secs.to_string()))
 }
 
 // Before browsers will post data to a server, they make a CORS OPTIONS request to see if that's OK.
 // This responds to that request to let the client know this request is allowed.
 async fn cors_preflight_allow() -> HttpResponse {
     HttpResponse::NoContent()
-        .append_header(("Access-Control-Allow-Methods", "OPTIONS, GET, PUT, HEAD"))
+        .append_header(("Access-Control-Allow-Methods", "OPTIONS, GET, PUT, POST, PATCH, DELETE, HEAD"))
+        .append_header(("Access-Control-Allow-Headers", "Content-Type, Upload-Offset"))
         .body("")
 }
 
 
-const MAX_ITEM_SIZE: usize = 1024 * 32; 
 const PLAINTEXT: &'static str = "text/plain; charset=utf-8";
 
 
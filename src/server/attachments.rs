@@ -4,9 +4,9 @@
 //! And, I suppose they could also be considered part of the REST API.
 
 
-use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::{io::{BufReader, BufWriter, Seek, SeekFrom}, time::Duration};
 
-use actix_web::{HttpRequest, HttpResponse, Responder, http::{header::{self, CONTENT_LENGTH}}, web::{Data, Path, Payload}};
+use actix_web::{HttpRequest, HttpResponse, Responder, http::{header::{self, CONTENT_LENGTH}}, web::{Bytes, Data, Path, Payload}};
 use anyhow::Context;
 use futures::{AsyncSeekExt, AsyncWriteExt, StreamExt};
 use mime_guess::mime;
@@ -14,7 +14,7 @@ use sodiumoxide::crypto::hash::sha512;
 use tempfile::tempfile;
 use log::{debug};
 
-use crate::{backend::{SHA512, Signature, UserID}, server::html::file_not_found};
+use crate::{backend::{SHA512, Signature, UserID}, config::Limits, server::html::file_not_found};
 
 use super::{AppData, Error, PLAINTEXT};
 
@@ -44,6 +44,8 @@ pub(crate) async fn get_file(
         mime_type = mime::APPLICATION_OCTET_STREAM;
     }
 
+    data.metrics.attachment_bytes_out(contents.size);
+
     let mime_string = mime_type.to_string();
     let response = HttpResponse::Ok()
         .content_type(mime_string)
@@ -60,19 +62,18 @@ pub(crate) async fn get_file(
 }
 
 // An allow-list for types we know can't embed JavaScript:
-fn safe_type(mime_type: &mime_guess::Mime) -> bool {
-    return match (mime_type.type_().as_str(), mime_type.subtype().as_str()) {
-        ("text", "plain") => true,
-        ("image", "gif") => true,
-        ("image", "jpeg") => true,
-        ("image", "png") => true,
-        ("audio", "mpeg") => true,
-        ("audio", "ogg") => true,
-        
-        // NO: javascript, HTML, SVG, others.
-        _ => false,
-    }
+// NO: javascript, HTML, SVG, others.
+pub(crate) const SAFE_MIME_TYPES: &[&str] = &[
+    "text/plain",
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "audio/mpeg",
+    "audio/ogg",
+];
 
+fn safe_type(mime_type: &mime_guess::Mime) -> bool {
+    SAFE_MIME_TYPES.contains(&mime_type.essence_str())
 }
 
 pub(crate) async fn put_file(
@@ -82,6 +83,7 @@ pub(crate) async fn put_file(
     mut body: Payload,
 ) -> Result<HttpResponse, Error> {
     let (user_id, signature, file_name) = path.into_inner();
+    let limits = data.config.get().limits.clone();
     let backend = data.backend_factory.open()?;
 
     let metadata = backend.get_attachment_meta(&user_id, &signature, &file_name)?;
@@ -105,7 +107,7 @@ pub(crate) async fn put_file(
     };
     
     if metadata.exists {
-        drain(body).await;
+        drain(body, &limits).await;
         return Ok(
             HttpResponse::Accepted()
             .content_type(PLAINTEXT)
@@ -158,11 +160,16 @@ pub(crate) async fn put_file(
 
     debug!("Receiving and hashing file: {}", &file_name);
     
-    while let Some(chunk) = body.next().await {
-        let chunk = chunk.context("Error parsing chunk")?;
+    loop {
+        let chunk = match next_chunk(&mut body, &limits).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(response) => return Ok(response),
+        };
 
         file.write_all(&chunk).await?;
         written += chunk.len() as u64;
+        data.metrics.attachment_bytes_in(chunk.len() as u64);
         hasher.update(&chunk);
         if written > size { break; }
     }
@@ -223,8 +230,43 @@ pub(crate) async fn put_file(
 /// SO, here we read the whole payload, even though we don't care what
 /// they sent. This seems ripe for DoS but it just seems to be the way
 /// browsers and HTTP clients work? 😢
-pub(crate) async fn drain(mut payload: Payload) {
-    while payload.next().await.is_some() {}
+///
+/// To limit the damage, we give up after `limits.max_drain_bytes` or
+/// `limits.drain_timeout_secs`, whichever comes first.
+pub(crate) async fn drain(mut payload: Payload, limits: &Limits) {
+    let max_bytes = limits.max_drain_bytes;
+    let read_all = async move {
+        let mut read: u64 = 0;
+        while let Some(Ok(chunk)) = payload.next().await {
+            read += chunk.len() as u64;
+            if read > max_bytes { break; }
+        }
+    };
+    let timeout = Duration::from_secs(limits.drain_timeout_secs);
+    if actix_web::rt::time::timeout(timeout, read_all).await.is_err() {
+        debug!("Timed out draining request body");
+    }
+}
+
+/// Read the next chunk of a request body, giving up if the client stalls
+/// for more than `limits.body_read_timeout_secs`.
+/// On error, returns the response to send to the client.
+pub(crate) async fn next_chunk(body: &mut Payload, limits: &Limits) -> Result<Option<Bytes>, HttpResponse> {
+    let timeout = Duration::from_secs(limits.body_read_timeout_secs);
+    match actix_web::rt::time::timeout(timeout, body.next()).await {
+        Err(_) => Err(
+            HttpResponse::RequestTimeout()
+            .content_type(PLAINTEXT)
+            .body("Timed out waiting for the request body.")
+        ),
+        Ok(None) => Ok(None),
+        Ok(Some(Ok(chunk))) => Ok(Some(chunk)),
+        Ok(Some(Err(err))) => Err(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(format!("Error reading request body: {}", err))
+        ),
+    }
 }
 
 pub(crate) async fn head_file(
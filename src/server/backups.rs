@@ -0,0 +1,41 @@
+//! Scheduled database backups. (See: `[backup]` in the server config.)
+
+use std::{path::PathBuf, time::Duration};
+
+use actix_web::web;
+use anyhow::Context;
+use log::{error, info};
+
+use crate::{backend::FactoryBox, backup, config::BackupConfig};
+
+/// Back up the database every `interval_hours`, keeping the newest `keep` backups.
+pub(crate) fn schedule(factory: FactoryBox, config: BackupConfig, dir: PathBuf) {
+    let interval = Duration::from_secs(config.interval_hours * 60 * 60);
+    actix_web::rt::spawn(async move {
+        loop {
+            // Don't take a backup right at startup. (ex: several in a row if the server is restarting.)
+            actix_web::rt::time::sleep(interval).await;
+
+            let factory = factory.clone();
+            let config = config.clone();
+            let dir = dir.clone();
+            let result = web::block(move || -> Result<PathBuf, anyhow::Error> {
+                std::fs::create_dir_all(&dir)
+                    .with_context(|| format!("Error creating {}", dir.display()))?;
+                let out = dir.join(backup::file_name(config.gzip));
+                let backend = factory.factory.open()?;
+                backup::write(&out, config.gzip, |temp| backend.backup(temp, &mut |_| {}))?;
+                for removed in backup::remove_old(&dir, config.keep)? {
+                    info!("Removed old backup {}", removed.display());
+                }
+                Ok(out)
+            }).await;
+
+            match result {
+                Ok(Ok(out)) => info!("Backed up database to {}", out.display()),
+                Ok(Err(err)) => error!("Error backing up database: {:#}", err),
+                Err(err) => error!("Error backing up database: {}", err),
+            }
+        }
+    });
+}
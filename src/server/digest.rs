@@ -0,0 +1,118 @@
+//! Range digests, for finding the differences between two servers' copies of
+//! a user's items.
+//!
+//! A time range is split into equal buckets. Each bucket reports how many
+//! items it holds, and the XOR of their signatures. Peers compare digests and
+//! only subdivide (or fetch) the buckets that differ.
+
+use crate::{backend::Signature, protos::{ItemDigest, ItemDigests}};
+
+pub(crate) struct Digests {
+    start: i64,
+    end: i64,
+    // i128 so that ranges spanning most of i64 don't overflow:
+    width: i128,
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    count: u64,
+    xor: Vec<u8>,
+}
+
+impl Digests {
+    /// Split `[start, end)` into (at most) `buckets` equal-width buckets.
+    /// Requires `start < end` and `buckets > 0`.
+    pub fn new(start: i64, end: i64, buckets: usize) -> Self {
+        let range = end as i128 - start as i128;
+        let width = (range + buckets as i128 - 1) / buckets as i128;
+        // If the range is smaller than the bucket count, we need fewer buckets:
+        let count = (range + width - 1) / width;
+
+        let mut digests = Self {
+            start,
+            end,
+            width,
+            buckets: vec![],
+        };
+        digests.buckets.resize_with(count as usize, Bucket::default);
+        digests
+    }
+
+    /// Add an item to its bucket. Items outside of the range are ignored.
+    pub fn add(&mut self, timestamp_ms: i64, signature: &Signature) {
+        if timestamp_ms < self.start || timestamp_ms >= self.end {
+            return;
+        }
+        let index = (timestamp_ms as i128 - self.start as i128) / self.width;
+        let bucket = &mut self.buckets[index as usize];
+
+        bucket.count += 1;
+        let bytes = signature.bytes();
+        if bucket.xor.is_empty() {
+            bucket.xor.resize(bytes.len(), 0);
+        }
+        for (x, b) in bucket.xor.iter_mut().zip(bytes) {
+            *x ^= b;
+        }
+    }
+
+    pub fn into_proto(self) -> ItemDigests {
+        let mut digests = ItemDigests::new();
+        for (index, bucket) in self.buckets.into_iter().enumerate() {
+            let start = self.start as i128 + index as i128 * self.width;
+            let end = (start + self.width).min(self.end as i128);
+
+            let mut digest = ItemDigest::new();
+            digest.set_start_ms(start as i64);
+            digest.set_end_ms(end as i64);
+            digest.set_count(bucket.count);
+            digest.set_signatures_xor(bucket.xor);
+            digests.mut_buckets().push(digest);
+        }
+        digests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Signature;
+
+    use super::Digests;
+
+    fn sig(byte: u8) -> Signature {
+        Signature::from_vec(vec![byte; 64]).unwrap()
+    }
+
+    #[test]
+    fn buckets() {
+        let mut digests = Digests::new(0, 10, 4);
+        digests.add(0, &sig(0b01));
+        digests.add(2, &sig(0b10));
+        digests.add(9, &sig(0b11));
+        // Out of range:
+        digests.add(10, &sig(0b11));
+        digests.add(-1, &sig(0b11));
+
+        let digests = digests.into_proto();
+        let ranges: Vec<_> = digests.get_buckets().iter()
+            .map(|d| (d.get_start_ms(), d.get_end_ms(), d.get_count()))
+            .collect();
+        assert_eq!(ranges, vec![(0, 3, 2), (3, 6, 0), (6, 9, 0), (9, 10, 1)]);
+
+        let buckets = digests.get_buckets();
+        assert_eq!(buckets[0].get_signatures_xor(), &[0b11; 64][..]);
+        assert!(buckets[1].get_signatures_xor().is_empty());
+    }
+
+    #[test]
+    fn small_range() {
+        let digests = Digests::new(5, 7, 16).into_proto();
+        assert_eq!(digests.get_buckets().len(), 2);
+
+        let digests = Digests::new(i64::MIN, i64::MAX, 16).into_proto();
+        assert_eq!(digests.get_buckets().len(), 16);
+        assert_eq!(digests.get_buckets()[15].get_end_ms(), i64::MAX);
+    }
+}
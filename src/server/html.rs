@@ -8,7 +8,7 @@ use askama_actix::Template;
 use askama_actix as askama;
 use protobuf::Message;
 
-use crate::{backend::{ItemDisplayRow, ItemRow, Signature, UserID}, markdown::ToHTML, protos::Item, server::{IndexPageItem, Nav, non_standard::identicon_url, pagination::Paginator}};
+use crate::{backend::{ItemDisplayRow, ItemRow, ItemTypes, Signature, UserID}, markdown::ToHTML, protos::{Item, ItemType}, server::{IndexPageItem, Nav, non_standard::identicon_url, pagination::Paginator}};
 use super::{AppData, Error, ProfileFollow, pagination::Pagination};
 
 mod filters;
@@ -41,7 +41,7 @@ pub(crate) async fn view_homepage(
     paginator.max_items = 20;
 
     let backend = data.backend_factory.open()?;
-    backend.homepage_items(paginator.time_span(), &mut paginator.callback())?;
+    backend.homepage_items(paginator.time_span(), &html_item_types(), &mut paginator.callback())?;
     
 
     let mut nav = vec![
@@ -92,7 +92,7 @@ pub(crate) async fn get_user_feed(
     );
 
     let backend = data.backend_factory.open()?;
-    backend.user_feed_items(&user_id, paginator.time_span(), &mut paginator.callback())?;
+    backend.user_feed_items(&user_id, paginator.time_span(), &html_item_types(), &mut paginator.callback())?;
 
     let display_name = backend.user_profile(&user_id)?.map(
         |row| -> Result<Item, anyhow::Error> {
@@ -168,7 +168,7 @@ pub(crate) async fn get_user_items(
 
     let (user,) = path.into_inner();
     let backend = data.backend_factory.open()?;
-    backend.user_items(&user, paginator.time_span(), &mut paginator.callback())?;
+    backend.user_items(&user, paginator.time_span(), &html_item_types(), &mut paginator.callback())?;
 
     
     let mut nav = vec![];
@@ -343,6 +343,11 @@ struct IndexPage {
     show_authors: bool,
 }
 
+/// The item types that the plain-HTML pages display. (See: display_by_default())
+fn html_item_types() -> ItemTypes {
+    ItemTypes::only(vec![ItemType::POST])
+}
+
 /// Should this Item be displayed on the plain-HTML version of the site?
 /// i.e.: should it be indexed by search engines?
 // TODO: Rename.
@@ -0,0 +1,124 @@
+//! JSON representations of our REST responses.
+//!
+//! These follow the proto3 JSON mapping (camelCase field names, enums as
+//! names, 64-bit ints as strings, default values omitted) except that `bytes`
+//! fields are base58-encoded to match the IDs in our URLs.
+//!
+//! JSON can't be used to verify signatures, so Items are wrapped in an
+//! envelope which also includes their raw signed bytes.
+
+use actix_web::{FromRequest, HttpRequest, HttpResponse, dev::Payload};
+use futures::future::{Ready, ready};
+use protobuf::{Message, reflect::{ReflectFieldRef, ReflectValueRef}};
+use serde_json::{Map, Value, json};
+
+use crate::{backend::{Signature, UserID}, protos::Item};
+
+use super::Error;
+
+/// The response format requested by a route's `{format}` path segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Proto3,
+    Json,
+}
+
+impl FromRequest for Format {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let format = match req.match_info().get("format") {
+            Some("json") => Format::Json,
+            _ => Format::Proto3,
+        };
+        ready(Ok(format))
+    }
+}
+
+/// Convert a protobuf message to proto3-style JSON.
+pub(crate) fn to_json(message: &dyn Message) -> Value {
+    let mut map = Map::new();
+    for field in message.descriptor().fields() {
+        let value = match field.get_reflect(message) {
+            ReflectFieldRef::Optional(None) => continue,
+            ReflectFieldRef::Optional(Some(value)) => value_to_json(value),
+            ReflectFieldRef::Repeated(values) => {
+                if values.len() == 0 { continue; }
+                Value::Array(
+                    (0..values.len()).map(|i| value_to_json(values.get(i))).collect()
+                )
+            },
+            // We don't use any map fields:
+            ReflectFieldRef::Map(_) => continue,
+        };
+        map.insert(field.json_name().to_string(), value);
+    }
+    Value::Object(map)
+}
+
+fn value_to_json(value: ReflectValueRef) -> Value {
+    match value {
+        ReflectValueRef::U32(v) => v.into(),
+        ReflectValueRef::I32(v) => v.into(),
+        // proto3 JSON uses strings for 64-bit ints, since JavaScript can't represent them all:
+        ReflectValueRef::U64(v) => v.to_string().into(),
+        ReflectValueRef::I64(v) => v.to_string().into(),
+        ReflectValueRef::F32(v) => v.into(),
+        ReflectValueRef::F64(v) => v.into(),
+        ReflectValueRef::Bool(v) => v.into(),
+        ReflectValueRef::String(v) => v.into(),
+        ReflectValueRef::Bytes(v) => bs58::encode(v).into_string().into(),
+        ReflectValueRef::Enum(v) => v.name().into(),
+        ReflectValueRef::Message(v) => to_json(v),
+    }
+}
+
+/// An Item, along with the bytes that were signed.
+pub(crate) fn item_envelope(user_id: &UserID, signature: &Signature, item_bytes: &[u8]) -> Result<Value, Error> {
+    let mut item = Item::new();
+    item.merge_from_bytes(item_bytes)?;
+
+    Ok(json!({
+        "userId": user_id.to_base58(),
+        "signature": signature.to_base58(),
+        "item": to_json(&item),
+        // base58 is too slow for large values, so we use base64 here:
+        "itemBytesBase64": base64::encode(item_bytes),
+    }))
+}
+
+pub(crate) const JSON: &str = "application/json";
+
+pub(crate) fn json_ok(value: &Value) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(JSON)
+        .body(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protos::{ItemList, ItemListEntry, ItemType};
+
+    use super::to_json;
+
+    #[test]
+    fn item_list_json() {
+        let mut entry = ItemListEntry::new();
+        entry.set_timestamp_ms_utc(1234);
+        entry.set_item_type(ItemType::POST);
+        entry.mut_signature().set_bytes(vec![0, 1, 2]);
+
+        let mut list = ItemList::new();
+        list.mut_items().push(entry);
+
+        let json = to_json(&list);
+        assert_eq!(json, serde_json::json!({
+            "items": [{
+                "signature": {"bytes": "15T"},
+                "timestampMsUtc": "1234",
+                "itemType": "POST",
+            }],
+        }));
+    }
+}
@@ -0,0 +1,253 @@
+//! Opening the sockets that the server listens on.
+//!
+//! A bind may be:
+//!  * an IP address and port. ex: `127.0.0.1:8080`, `[::1]:8080`
+//!  * `unix:/path/to/socket`, for a Unix domain socket.
+//!  * `systemd`, to use the socket(s) passed to us by systemd socket activation.
+
+use std::{fmt, net::{SocketAddr, TcpListener}, path::PathBuf, str::FromStr};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+use actix_http::Request;
+use actix_service::IntoServiceFactory;
+use actix_web::{HttpServer, body::MessageBody, dev::{AppConfig, Response, Service, ServiceFactory}};
+use anyhow::{Context, Error, bail};
+
+use crate::config::ServerConfig;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Systemd,
+}
+
+impl FromStr for Bind {
+    type Err = Error;
+
+    fn from_str(bind: &str) -> Result<Self, Self::Err> {
+        if bind == "systemd" {
+            return Ok(Bind::Systemd);
+        }
+        if let Some(path) = bind.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("Missing path in bind: {}", bind);
+            }
+            return Ok(Bind::Unix(path.into()));
+        }
+        let addr = bind.parse().with_context(|| format!("Invalid bind address: {}", bind))?;
+        Ok(Bind::Tcp(addr))
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "{}", addr),
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+            Bind::Systemd => write!(f, "systemd"),
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Only TCP sockets can serve HTTPS.
+    pub fn into_tcp(self) -> Result<TcpListener, Error> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener),
+            #[cfg(unix)]
+            Listener::Unix(_) => bail!("HTTPS isn't supported on Unix sockets"),
+        }
+    }
+}
+
+/// Open the socket(s) for a bind.
+/// (systemd may pass us more than one.)
+pub(crate) fn open(bind: &Bind, config: &ServerConfig) -> Result<Vec<Listener>, Error> {
+    let listeners = match bind {
+        Bind::Tcp(addr) => open_tcp(addr).map(|l| vec![Listener::Tcp(l)]),
+        Bind::Unix(path) => open_unix(path, config.socket_mode).map(|l| vec![l]),
+        Bind::Systemd => from_systemd(),
+    };
+    listeners.with_context(|| format!("Error binding to: {}", bind))
+}
+
+/// Serve plain HTTP on the socket(s) for each of `binds`.
+pub(crate) fn listen_all<F, I, S, B>(
+    mut server: HttpServer<F, I, S, B>,
+    binds: &[Bind],
+    config: &ServerConfig,
+) -> Result<HttpServer<F, I, S, B>, Error>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    for bind in binds {
+        for listener in open(bind, config)? {
+            server = match listener {
+                Listener::Tcp(listener) => server.listen(listener)?,
+                #[cfg(unix)]
+                Listener::Unix(listener) => server.listen_uds(listener)?,
+            };
+        }
+    }
+    Ok(server)
+}
+
+// Work around https://github.com/actix/actix-web/issues/1913
+fn open_tcp(addr: &SocketAddr) -> Result<TcpListener, Error> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    // Eh, this is what actix was using:
+    let backlog = 1024;
+
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(backlog)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn open_unix(path: &std::path::Path, mode: Option<u32>) -> Result<Listener, Error> {
+    use std::io::ErrorKind;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixStream;
+
+    // A socket left over from a previous run would prevent us from binding.
+    // But only remove it if nothing is listening on it anymore:
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            bail!("{} exists, and is not a socket", path.display());
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => bail!("{} is in use by another process", path.display()),
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Error removing old socket {}", path.display()))?;
+            },
+            Err(err) => {
+                return Err(err).with_context(|| format!("Error checking old socket {}", path.display()));
+            },
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Error setting permissions on {}", path.display()))?;
+    }
+
+    Ok(Listener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn open_unix(_path: &std::path::Path, _mode: Option<u32>) -> Result<Listener, Error> {
+    bail!("Unix sockets are only supported on Unix-like systems");
+}
+
+/// Clean up after a Unix socket when we shut down.
+pub(crate) fn close(bind: &Bind) {
+    if let Bind::Unix(path) = bind {
+        if let Err(err) = std::fs::remove_file(path) {
+            log::warn!("Error removing socket {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Take the sockets passed to us via systemd socket activation.
+/// See: https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+#[cfg(unix)]
+fn from_systemd() -> Result<Vec<Listener>, Error> {
+    use socket2::Socket;
+    use std::os::unix::io::FromRawFd;
+
+    // File descriptors passed by systemd start here:
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let pid = std::env::var("LISTEN_PID").context("LISTEN_PID is not set. Not started by systemd?")?;
+    let pid: u32 = pid.parse().context("Invalid LISTEN_PID")?;
+    if pid != std::process::id() {
+        bail!("LISTEN_PID is for another process");
+    }
+    let count: i32 = std::env::var("LISTEN_FDS")
+        .context("LISTEN_FDS is not set")?
+        .parse()
+        .context("Invalid LISTEN_FDS")?;
+
+    // Don't let these leak to child processes, or let us take the same FDs twice:
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if count < 1 {
+        bail!("systemd didn't pass us any sockets");
+    }
+
+    let mut listeners = Vec::with_capacity(count as usize);
+    for fd in SD_LISTEN_FDS_START .. SD_LISTEN_FDS_START + count {
+        // Safety: systemd gives us ownership of these FDs, and we only take them once. (see above)
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let addr = socket.local_addr()
+            .with_context(|| format!("systemd socket {} is not a socket", fd))?;
+        socket.set_nonblocking(true)?;
+        if addr.as_socket().is_some() {
+            listeners.push(Listener::Tcp(socket.into()));
+        } else {
+            listeners.push(Listener::Unix(socket.into()));
+        }
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+fn from_systemd() -> Result<Vec<Listener>, Error> {
+    bail!("systemd sockets are only supported on Unix-like systems");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bind;
+
+    #[test]
+    fn parse_binds() {
+        assert_eq!(Bind::Tcp("127.0.0.1:8080".parse().unwrap()), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(Bind::Unix("/run/feoblog.sock".into()), "unix:/run/feoblog.sock".parse().unwrap());
+        assert_eq!(Bind::Systemd, "systemd".parse().unwrap());
+        assert!("unix:".parse::<Bind>().is_err());
+        assert!("localhost".parse::<Bind>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reuse_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.sock");
+
+        let live = super::open_unix(&path, None).unwrap();
+        assert!(super::open_unix(&path, None).is_err(), "Must not remove a socket that's in use");
+
+        drop(live);
+        super::open_unix(&path, None).expect("A stale socket should be replaced");
+    }
+}
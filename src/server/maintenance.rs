@@ -0,0 +1,34 @@
+//! Periodic database maintenance. (See: `[maintenance]` in the server config.)
+
+use std::time::Duration;
+
+use actix_web::web;
+use log::{error, info, warn};
+
+use crate::backend::{FactoryBox, MaintainOpts};
+
+/// Run light maintenance (see: `MaintainOpts::light()`) every `interval`.
+pub(crate) fn schedule(factory: FactoryBox, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(interval).await;
+
+            let factory = factory.clone();
+            let result = web::block(move || {
+                let backend = factory.factory.open()?;
+                backend.maintain(MaintainOpts::light())
+            }).await;
+
+            match result {
+                Ok(Ok(result)) => {
+                    if result.checkpoint_busy {
+                        warn!("Database maintenance: WAL checkpoint didn't finish because the database was busy.");
+                    }
+                    info!("Database maintenance complete. WAL size: {} bytes", result.size_after.wal);
+                },
+                Ok(Err(err)) => error!("Error maintaining database: {:#}", err),
+                Err(err) => error!("Error maintaining database: {}", err),
+            }
+        }
+    });
+}
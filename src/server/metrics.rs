@@ -0,0 +1,224 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! Counters are always collected, since they're cheap. The endpoint and the
+//! (more expensive) database statistics are only enabled by the `[metrics]`
+//! config.
+
+use std::{future::Future, pin::Pin, sync::Arc, time::{Duration, Instant}};
+
+use actix_web::{HttpResponse, dev::{Service, ServiceRequest, ServiceResponse}, http::StatusCode, web::{self, Data}};
+use anyhow::Context;
+use log::error;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+use crate::backend::{Backend, FactoryBox, PoolState, UsageTotals};
+
+use super::{AppData, Error};
+
+pub(crate) struct Metrics {
+    registry: Registry,
+
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    put_items: IntCounterVec,
+    rejected_uploads: IntCounterVec,
+    attachment_bytes: IntCounterVec,
+
+    pool_connections: IntGaugeVec,
+    pool_max: IntGauge,
+
+    db_size: IntGauge,
+    db_rows: IntGaugeVec,
+    db_bytes: IntGaugeVec,
+    db_users: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("feoblog".into()), None)?;
+
+        let metrics = Self {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests, by route pattern."),
+                &["route", "method", "status"],
+            )?,
+            http_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP response times, by route pattern."),
+                &["route", "method"],
+            )?,
+            put_items: IntCounterVec::new(
+                Opts::new("put_item_total", "Items PUT to the server, by outcome."),
+                &["outcome"],
+            )?,
+            rejected_uploads: IntCounterVec::new(
+                Opts::new("rejected_uploads_total", "Uploads turned away by rate limits, by reason."),
+                &["reason"],
+            )?,
+            attachment_bytes: IntCounterVec::new(
+                Opts::new("attachment_bytes_total", "Bytes of file attachments uploaded (in) and served (out)."),
+                &["direction"],
+            )?,
+            pool_connections: IntGaugeVec::new(
+                Opts::new("db_pool_connections", "Database connections in the pool."),
+                &["state"],
+            )?,
+            pool_max: IntGauge::new("db_pool_max_connections", "The maximum size of the database connection pool.")?,
+            db_size: IntGauge::new("db_size_bytes", "Total size of the database.")?,
+            db_rows: IntGaugeVec::new(
+                Opts::new("db_rows", "Items, and stored attachments that they reference."),
+                &["table"],
+            )?,
+            db_bytes: IntGaugeVec::new(
+                Opts::new("db_table_bytes", "Bytes of content per table."),
+                &["table"],
+            )?,
+            db_users: IntGaugeVec::new(
+                Opts::new("db_users", "Users with items stored on this server."),
+                &["kind"],
+            )?,
+            registry,
+        };
+
+        let r = &metrics.registry;
+        r.register(Box::new(metrics.http_requests.clone()))?;
+        r.register(Box::new(metrics.http_duration.clone()))?;
+        r.register(Box::new(metrics.put_items.clone()))?;
+        r.register(Box::new(metrics.rejected_uploads.clone()))?;
+        r.register(Box::new(metrics.attachment_bytes.clone()))?;
+        r.register(Box::new(metrics.pool_connections.clone()))?;
+        r.register(Box::new(metrics.pool_max.clone()))?;
+        r.register(Box::new(metrics.db_size.clone()))?;
+        r.register(Box::new(metrics.db_rows.clone()))?;
+        r.register(Box::new(metrics.db_bytes.clone()))?;
+        r.register(Box::new(metrics.db_users.clone()))?;
+
+        Ok(metrics)
+    }
+
+    /// Record the result of a put_item request. ex: "created", "too_large".
+    pub fn put_item(&self, outcome: &str) {
+        self.put_items.with_label_values(&[outcome]).inc();
+    }
+
+    /// Record an upload that we didn't accept because of rate limits.
+    pub fn rejected_upload(&self, reason: &str) {
+        self.rejected_uploads.with_label_values(&[reason]).inc();
+    }
+
+    pub fn attachment_bytes_in(&self, bytes: u64) {
+        self.attachment_bytes.with_label_values(&["in"]).inc_by(bytes);
+    }
+
+    pub fn attachment_bytes_out(&self, bytes: u64) {
+        self.attachment_bytes.with_label_values(&["out"]).inc_by(bytes);
+    }
+
+    fn observe(&self, route: Option<String>, method: &str, status: StatusCode, elapsed: Duration) {
+        // Use the route pattern, not the path, so that we don't get a new series per user/item:
+        let route = route.unwrap_or_else(|| "unmatched".into());
+        let status = status.as_u16().to_string();
+
+        self.http_requests.with_label_values(&[&route, method, &status]).inc();
+        self.http_duration.with_label_values(&[&route, method]).observe(elapsed.as_secs_f64());
+    }
+
+    fn update_pool(&self, state: PoolState) {
+        let in_use = state.connections.saturating_sub(state.idle_connections);
+        self.pool_connections.with_label_values(&["idle"]).set(state.idle_connections.into());
+        self.pool_connections.with_label_values(&["in_use"]).set(in_use.into());
+        self.pool_max.set(state.max_size.into());
+    }
+
+    fn update_db(&self, stats: &DbStats) {
+        self.db_size.set(stats.db_size as i64);
+        let totals = &stats.totals;
+        self.db_rows.with_label_values(&["item"]).set(totals.items_count as i64);
+        self.db_rows.with_label_values(&["store"]).set(totals.attachments_count as i64);
+        self.db_bytes.with_label_values(&["item"]).set(totals.items_bytes as i64);
+        self.db_bytes.with_label_values(&["store"]).set(totals.attachments_bytes as i64);
+        self.db_users.with_label_values(&["all"]).set(totals.users as i64);
+        self.db_users.with_label_values(&["known"]).set(totals.known_users as i64);
+        self.db_users.with_label_values(&["server"]).set(totals.server_users as i64);
+    }
+
+    fn render(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut out = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut out)?;
+        Ok(out)
+    }
+}
+
+/// Database-wide statistics. Totals match `feoblog db usage`.
+struct DbStats {
+    db_size: u64,
+    totals: UsageTotals,
+}
+
+impl DbStats {
+    fn collect(backend: &dyn Backend) -> Result<Self, anyhow::Error> {
+        Ok(DbStats {
+            db_size: backend.db_size()?,
+            totals: backend.usage_totals()?,
+        })
+    }
+}
+
+/// `GET /metrics`
+pub(crate) async fn get_metrics(data: Data<AppData>) -> Result<HttpResponse, Error> {
+    data.metrics.update_pool(data.backend_factory.pool_state());
+    let body = data.metrics.render().context("Error rendering metrics")?;
+    Ok(
+        HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(body)
+    )
+}
+
+type ResponseFuture<B> = Pin<Box<dyn Future<Output=Result<ServiceResponse<B>, actix_web::Error>>>>;
+
+/// Middleware which records the count & duration of each request.
+pub(crate) fn record_requests<S, B>(metrics: Arc<Metrics>) -> impl Fn(ServiceRequest, &S) -> ResponseFuture<B> + Clone
+where S: Service<ServiceRequest, Response=ServiceResponse<B>, Error=actix_web::Error>,
+      S::Future: 'static,
+      B: 'static,
+{
+    move |req, service| {
+        let start = Instant::now();
+        let metrics = metrics.clone();
+        let route = req.match_pattern();
+        let method = req.method().clone();
+        let response = service.call(req);
+        Box::pin(async move {
+            let response = response.await;
+            let status = match &response {
+                Ok(response) => response.status(),
+                // Errors returned by handlers (ex: via `?`) haven't been rendered yet:
+                Err(err) => err.as_response_error().status_code(),
+            };
+            metrics.observe(route, method.as_str(), status, start.elapsed());
+            response
+        })
+    }
+}
+
+/// Periodically update the database statistics.
+/// These require a scan of the whole database, so we don't do them on every scrape.
+pub(crate) fn update_db_stats(metrics: Arc<Metrics>, factory: FactoryBox, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        loop {
+            let factory = factory.clone();
+            let result = web::block(move || -> Result<DbStats, anyhow::Error> {
+                let backend = factory.factory.open()?;
+                DbStats::collect(backend.as_ref())
+            }).await;
+
+            match result {
+                Ok(Ok(stats)) => metrics.update_db(&stats),
+                Ok(Err(err)) => error!("Error collecting database metrics: {:#}", err),
+                Err(err) => error!("Error collecting database metrics: {}", err),
+            }
+
+            actix_web::rt::time::sleep(interval).await;
+        }
+    });
+}
@@ -10,7 +10,7 @@ use std::{
 
 use serde::Deserialize;
 
-use crate::backend::{TimeSpan, Timestamp};
+use crate::backend::{ItemTypes, TimeSpan, Timestamp};
 use super::{IndexPageItem};
 
 /// Query params to control pagination:
@@ -27,6 +27,9 @@ pub(crate) struct Pagination {
 
     /// Limit how many posts/items appear on a page.
     count: Option<usize>,
+
+    /// Only list these item types. ex: `?types=post,comment`
+    types: Option<ItemTypes>,
 }
 
 
@@ -132,6 +135,11 @@ where
         TimeSpan::Before(Timestamp::now())
     }
 
+    /// The item types requested, or `default` if the request didn't specify any.
+    pub fn item_types_or(&self, default: ItemTypes) -> ItemTypes {
+        self.params.types.clone().unwrap_or(default)
+    }
+
     fn flip_items(&mut self) {
         if !self.time_span().is_before() && !self.have_flipped {
             // Then we were iterating in backwards order, and need to flip
@@ -214,3 +222,22 @@ fn bound<T: Ord>(input: T, lower: T, upper: T) -> T {
     min(max(lower, input), upper)
 }
 
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Query;
+
+    use crate::protos::ItemType;
+    use super::Pagination;
+
+    #[test]
+    fn types_param() {
+        let params = Query::<Pagination>::from_query("types=post,comment&count=5").unwrap();
+        let types = params.types.as_ref().unwrap();
+        assert_eq!(types.types(), Some(&[ItemType::POST, ItemType::COMMENT][..]));
+
+        let params = Query::<Pagination>::from_query("count=5").unwrap();
+        assert!(params.types.is_none());
+
+        assert!(Query::<Pagination>::from_query("types=bogus").is_err());
+    }
+}
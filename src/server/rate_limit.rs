@@ -0,0 +1,293 @@
+//! Throttling for endpoints that accept uploads.
+//!
+//! Each client IP and each UserID gets a token bucket. Every upload request
+//! takes a token from both, and buckets refill at a steady rate. When a bucket
+//! is empty, we return a 429 with a Retry-After header.
+//!
+//! We also cap the number of uploads that may be in progress at once, so that
+//! slow clients can't tie up all of our workers & temp files.
+
+use std::{collections::HashMap, net::IpAddr, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
+
+use actix_web::{HttpResponse, dev::{Service, ServiceRequest, ServiceResponse}, http::{Method, header}, web::Data};
+use futures::{Future, future::Either};
+use log::debug;
+
+use crate::{backend::UserID, config::{BucketConfig, RateLimitConfig}};
+
+use super::{AppData, PLAINTEXT};
+
+/// Don't let an attacker fill our memory with buckets for spoofed IPs.
+/// Past this many buckets, we forget about ones that have refilled, and then
+/// the least recently used ones.
+const MAX_BUCKETS: usize = 10_000;
+
+/// When we have to evict buckets that are still in use, make some extra room
+/// so that we're not sorting all of them on every request.
+const EVICT_TO: usize = MAX_BUCKETS * 9 / 10;
+
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    uploads: Arc<AtomicUsize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    User(UserID),
+}
+
+impl Key {
+    fn config<'a>(&self, config: &'a RateLimitConfig) -> &'a BucketConfig {
+        match self {
+            Key::Ip(_) => &config.per_ip,
+            Key::User(_) => &config.per_user,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.per_second).min(config.burst as f64);
+        self.updated = now;
+    }
+
+    fn is_full(&self, config: &BucketConfig, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * config.per_second >= config.burst as f64
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            uploads: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Take a token for each key.
+    /// If any bucket is empty, no tokens are taken, and we return how long to wait.
+    fn check(&self, keys: &[Key], config: &RateLimitConfig) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limit lock");
+
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|key, bucket| !bucket.is_full(key.config(config), now));
+        }
+        if buckets.len() >= MAX_BUCKETS {
+            let mut by_age: Vec<(Instant, Key)> = buckets.iter()
+                .map(|(key, bucket)| (bucket.updated, key.clone()))
+                .collect();
+            by_age.sort_by_key(|(updated, _)| *updated);
+            let excess = buckets.len() - EVICT_TO;
+            for (_, key) in by_age.into_iter().take(excess) {
+                buckets.remove(&key);
+            }
+            debug!("Evicted {} rate limit buckets", excess);
+        }
+
+        let mut wait = Duration::from_secs(0);
+        for key in keys {
+            let config = key.config(config);
+            let bucket = buckets.entry(key.clone()).or_insert_with(|| Bucket{
+                tokens: config.burst as f64,
+                updated: now,
+            });
+            bucket.refill(config, now);
+            if bucket.tokens < 1.0 {
+                let secs = (1.0 - bucket.tokens) / config.per_second;
+                wait = wait.max(Duration::from_secs_f64(secs));
+            }
+        }
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+
+        for key in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserve a slot for an upload, if we're not already at `max`.
+    fn start_upload(&self, max: usize) -> Option<UploadPermit> {
+        let previous = self.uploads.fetch_add(1, Ordering::SeqCst);
+        let permit = UploadPermit{ uploads: self.uploads.clone() };
+        if previous >= max {
+            // drops the permit, and our count:
+            return None;
+        }
+        Some(permit)
+    }
+}
+
+/// Counts as an in-progress upload until dropped.
+struct UploadPermit {
+    uploads: Arc<AtomicUsize>,
+}
+
+impl Drop for UploadPermit {
+    fn drop(&mut self) {
+        self.uploads.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Find the IP address of the client.
+///
+/// If the request came from a trusted proxy, we use the last address in the
+/// X-Forwarded-For header that isn't also a trusted proxy.
+/// Connections over a Unix socket are always from a local proxy, so are trusted.
+fn client_ip(req: &ServiceRequest, config: &RateLimitConfig) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let trusted = |ip: &IpAddr| config.trusted_proxies.contains(ip);
+
+    let from_proxy = match peer {
+        None => true,
+        Some(ip) => trusted(&ip),
+    };
+    if !from_proxy {
+        return peer;
+    }
+
+    let forwarded = req.headers().get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+
+    forwarded.into_iter().rev().find(|ip| !trusted(ip)).or(peer)
+}
+
+/// The user whose content is being uploaded, if it's in the path.
+fn path_user(req: &ServiceRequest) -> Option<UserID> {
+    let info = req.match_info();
+    let user_id = info.get("user_id").or_else(|| info.get("userID"))?;
+    UserID::from_base58(user_id).ok()
+}
+
+fn too_many_requests(wait: Duration) -> HttpResponse {
+    // Round up, so clients don't retry too soon:
+    let secs = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, secs.to_string()))
+        .content_type(PLAINTEXT)
+        .body("Too many requests. Please try again later.")
+}
+
+fn too_many_uploads() -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, "5"))
+        .content_type(PLAINTEXT)
+        .body("The server is busy with other uploads. Please try again later.")
+}
+
+/// Middleware which throttles uploads. (PUT, POST, and PATCH requests.)
+/// Other methods pass through unchanged.
+pub(crate) fn limit_uploads<S>(req: ServiceRequest, service: &S)
+-> impl Future<Output = Result<ServiceResponse, S::Error>>
+where S: Service<ServiceRequest, Response=ServiceResponse>
+{
+    let is_upload = [Method::PUT, Method::POST, Method::PATCH].contains(req.method());
+    let data = req.app_data::<Data<AppData>>().cloned();
+
+    let data = match (is_upload, data) {
+        (true, Some(data)) => data,
+        _ => return Either::Left(service.call(req)),
+    };
+
+    let config = data.config.get();
+    let limiter = &data.rate_limiter;
+
+    if config.rate_limit.enabled {
+        let mut keys = vec![];
+        if let Some(ip) = client_ip(&req, &config.rate_limit) {
+            keys.push(Key::Ip(ip));
+        }
+        if let Some(user_id) = path_user(&req) {
+            keys.push(Key::User(user_id));
+        }
+        if let Err(wait) = limiter.check(&keys, &config.rate_limit) {
+            debug!("Rate limited: {:?}", keys);
+            data.metrics.rejected_upload("rate_limit");
+            let res = req.into_response(too_many_requests(wait));
+            return Either::Right(Either::Left(futures::future::ready(Ok(res))));
+        }
+    }
+
+    let permit = match limiter.start_upload(config.limits.max_concurrent_uploads) {
+        Some(permit) => permit,
+        None => {
+            data.metrics.rejected_upload("concurrent_uploads");
+            let res = req.into_response(too_many_uploads());
+            return Either::Right(Either::Left(futures::future::ready(Ok(res))));
+        }
+    };
+
+    let fut = service.call(req);
+    Either::Right(Either::Right(async move {
+        let res = fut.await;
+        drop(permit);
+        res
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use crate::config::{BucketConfig, RateLimitConfig};
+
+    use super::{Key, MAX_BUCKETS, RateLimiter};
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new();
+        let mut config = RateLimitConfig::default();
+        config.per_ip = BucketConfig{ burst: 2, per_second: 0.5 };
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let keys = [Key::Ip(ip)];
+
+        assert!(limiter.check(&keys, &config).is_ok());
+        assert!(limiter.check(&keys, &config).is_ok());
+        let wait = limiter.check(&keys, &config).unwrap_err();
+        assert!(wait > Duration::from_secs(1));
+        assert!(wait <= Duration::from_secs(2));
+
+        // Other IPs have their own bucket:
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(limiter.check(&[Key::Ip(other)], &config).is_ok());
+    }
+
+    #[test]
+    fn bounded_buckets() {
+        let limiter = RateLimiter::new();
+        let mut config = RateLimitConfig::default();
+        // Refills slowly, so buckets can't be dropped for being full:
+        config.per_ip = BucketConfig{ burst: 2, per_second: 0.001 };
+
+        for i in 0 .. MAX_BUCKETS as u32 * 2 {
+            let ip = IpAddr::from(i.to_be_bytes());
+            assert!(limiter.check(&[Key::Ip(ip)], &config).is_ok());
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+        }
+    }
+
+    #[test]
+    fn upload_permits() {
+        let limiter = RateLimiter::new();
+        let first = limiter.start_upload(1);
+        assert!(first.is_some());
+        assert!(limiter.start_upload(1).is_none());
+        drop(first);
+        assert!(limiter.start_upload(1).is_some());
+    }
+}
@@ -2,21 +2,25 @@
 //!
 //! Note: some endpoints are in attachments.rs, since they're used by both REST & HTML views.
 
-use actix_web::{HttpRequest, HttpResponse, web::{Data, Path, Payload, Query}, HttpResponseBuilder};
+use actix_web::{HttpRequest, HttpResponse, http::StatusCode, web::{Data, Path, Payload, Query}, HttpResponseBuilder};
 use anyhow::{Context, format_err};
-use futures::StreamExt;
 use logging_timer::timer;
 use protobuf::Message;
 
-use crate::{backend::{ItemDisplayRow, ItemRow, Signature, Timestamp, UserID}, protos::{Item, ItemList, ItemListEntry, ItemType, Item_oneof_item_type, ProtoValid}, server::{MAX_ITEM_SIZE, PLAINTEXT}};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 
-use super::{AppData, Error, pagination::{Pagination, Paginator}, attachments::drain};
+use crate::{backend::{Backend, ItemDisplayRow, ItemRow, ItemTypes, QuotaDenyReason, Signature, Timestamp, UserID}, config::Limits, protos::{HaveRequest, HaveResponse, Item, ItemBatch, ItemBatchEntry, ItemBatchRequest, ItemID, ItemList, ItemListEntry, ItemType, ItemUpload, ItemUploadRequest, ItemUploadResponse, ItemUploadStatus, ProtoValid, ServerInfo}, server::PLAINTEXT, util::Bitmap};
+
+use super::{AppData, Error, digest::Digests, metrics::Metrics, pagination::{Pagination, Paginator}, attachments::{SAFE_MIME_TYPES, drain, next_chunk}, json::{Format, JSON, item_envelope, json_ok, to_json}};
 
 
 // Get the protobuf ItemList for items on the homepage.
+// Lists only posts, unless other `types` are requested.
 pub(crate) async fn homepage_item_list(
     data: Data<AppData>,
     Query(pagination): Query<Pagination>,
+    format: Format,
 ) -> Result<HttpResponse, Error> {
 
     let mut paginator = Paginator::new(
@@ -26,23 +30,20 @@ pub(crate) async fn homepage_item_list(
             item.merge_from_bytes(&row.item.item_bytes)?;
             Ok(item_to_entry(&item, &row.item.user, &row.item.signature))
         }, 
-        |entry: &ItemListEntry| { 
-            entry.get_item_type() == ItemType::POST
-        }
+        |_: &ItemListEntry| { true } // filtered by item_types below
     );
     // We're only holding ItemListEntries in memory, so we can up this limit and save some round trips.
-    paginator.max_items = 1000;
+    paginator.max_items = data.config.get().limits.max_list_items;
 
     let backend = data.backend_factory.open()?;
-    backend.homepage_items(paginator.time_span(), &mut paginator.callback())?;
+    let item_types = paginator.item_types_or(ItemTypes::only(vec![ItemType::POST]));
+    backend.homepage_items(paginator.time_span(), &item_types, &mut paginator.callback())?;
     
 
     let mut list = ItemList::new();
     list.no_more_items = !paginator.has_more;
     list.items = protobuf::RepeatedField::from(paginator.into_items());
-    Ok(
-        proto_ok().body(list.write_to_bytes()?)
-    )
+    list_response(format, &list)
 }
 
 
@@ -50,6 +51,7 @@ pub(crate) async fn feed_item_list(
     data: Data<AppData>,
     path: Path<(UserID,)>,
     Query(pagination): Query<Pagination>,
+    format: Format,
 ) -> Result<HttpResponse, Error> {
     let (user_id,) = path.into_inner();
     let mut paginator = Paginator::new(
@@ -63,28 +65,27 @@ pub(crate) async fn feed_item_list(
     );
     // We're only holding ItemListEntries in memory, so we can up this limit and
     // save some round trips.
-    paginator.max_items = 1000;
+    paginator.max_items = data.config.get().limits.max_list_items;
 
     let backend = data.backend_factory.open()?;
 
     // Note: user_feed_items is doing a little bit of extra work to fetch
     // display_name, which we then throw away. We *could* make a more efficient
     // version that we use for just this case, but eh, reuse is nice.
-    backend.user_feed_items(&user_id, paginator.time_span(), &mut paginator.callback())?;
+    let item_types = paginator.item_types_or(ItemTypes::all());
+    backend.user_feed_items(&user_id, paginator.time_span(), &item_types, &mut paginator.callback())?;
 
     let mut list = ItemList::new();
     list.no_more_items = !paginator.has_more;
     list.items = protobuf::RepeatedField::from(paginator.into_items());
-    Ok(
-        proto_ok()
-        .body(list.write_to_bytes()?)
-    )
+    list_response(format, &list)
 }
 
 pub(crate) async fn user_item_list(
     data: Data<AppData>,
     path: Path<(UserID,)>,
     Query(pagination): Query<Pagination>,
+    format: Format,
 ) -> Result<HttpResponse, Error> {
     let (user_id,) = path.into_inner();
     let mut paginator = Paginator::new(
@@ -98,28 +99,27 @@ pub(crate) async fn user_item_list(
     );
     // We're only holding ItemListEntries in memory, so we can up this limit and
     // save some round trips.
-    paginator.max_items = 1000;
+    paginator.max_items = data.config.get().limits.max_list_items;
 
     let backend = data.backend_factory.open()?;
 
     // Note: user_feed_items is doing a little bit of extra work to fetch
     // display_name, which we then throw away. We *could* make a more efficient
     // version that we use for just this case, but eh, reuse is nice.
-    backend.user_items(&user_id, paginator.time_span(), &mut paginator.callback())?;
+    let item_types = paginator.item_types_or(ItemTypes::all());
+    backend.user_items(&user_id, paginator.time_span(), &item_types, &mut paginator.callback())?;
 
     let mut list = ItemList::new();
     list.no_more_items = !paginator.has_more;
     list.items = protobuf::RepeatedField::from(paginator.into_items());
-    Ok(
-        proto_ok()
-        .body(list.write_to_bytes()?)
-    )
+    list_response(format, &list)
 }
 
 pub(crate) async fn item_reply_list(
     data: Data<AppData>,
     path: Path<(UserID, Signature)>,
     Query(pagination): Query<Pagination>,
+    format: Format,
 ) -> Result<HttpResponse, Error> {
     let (user_id, signature) = path.into_inner();
     let mut paginator = Paginator::new(
@@ -133,22 +133,20 @@ pub(crate) async fn item_reply_list(
     );
     // We're only holding ItemListEntries in memory, so we can up this limit and
     // save some round trips.
-    paginator.max_items = 1000;
+    paginator.max_items = data.config.get().limits.max_list_items;
 
     let backend = data.backend_factory.open()?;
 
     // Note: user_feed_items is doing a little bit of extra work to fetch
     // display_name, which we then throw away. We *could* make a more efficient
     // version that we use for just this case, but eh, reuse is nice.
-    backend.reply_items(&user_id, &signature, paginator.before(), &mut paginator.callback())?;
+    let item_types = paginator.item_types_or(ItemTypes::all());
+    backend.reply_items(&user_id, &signature, paginator.before(), &item_types, &mut paginator.callback())?;
 
     let mut list = ItemList::new();
     list.no_more_items = !paginator.has_more;
     list.items = protobuf::RepeatedField::from(paginator.into_items());
-    Ok(
-        proto_ok()
-        .body(list.write_to_bytes()?)
-    )
+    list_response(format, &list)
 }
 
 /// Accepts a proto3 Item
@@ -166,6 +164,8 @@ pub(crate) async fn put_item(
 {
     let _timer = timer!("put_item()");
 
+    let limits = data.config.get().limits.clone();
+    let metrics = &data.metrics;
     let (user_path, sig_path) = path.into_inner();
     let user = UserID::from_base58(user_path.as_str()).context("decoding user ID")?;
     let signature = Signature::from_base58(sig_path.as_str()).context("decoding signature")?;
@@ -173,6 +173,7 @@ pub(crate) async fn put_item(
     let length = match req.headers().get("content-length") {
         Some(length) => length,
         None => {
+            metrics.put_item("no_length");
             return Ok(
                 HttpResponse::LengthRequired()
                 .content_type(PLAINTEXT)
@@ -185,6 +186,7 @@ pub(crate) async fn put_item(
     let length: usize = match length.to_str()?.parse() {
         Ok(length) => length,
         Err(_) => {
+            metrics.put_item("no_length");
             return Ok(
                 HttpResponse::BadRequest()
                 .content_type(PLAINTEXT)
@@ -193,64 +195,59 @@ pub(crate) async fn put_item(
         },
     };
 
-    if length > MAX_ITEM_SIZE {
+    if length > limits.max_item_size {
+        metrics.put_item("too_large");
         return Ok(
             HttpResponse::PayloadTooLarge()
             .content_type(PLAINTEXT)
-            .body(format!("Item must be <= {} bytes", MAX_ITEM_SIZE))
+            .body(format!("Item must be <= {} bytes", limits.max_item_size))
         );
     }
 
     let mut backend = data.backend_factory.open()?;
 
-    // If the content already exists, do nothing.
-    if backend.user_item_exists(&user, &signature)? {
-        // *sigh* this bug again. Should I handle this in middleware?
-        drain(body).await;
-        
-        return Ok(
-            HttpResponse::Accepted()
-            .content_type(PLAINTEXT)
-            .body("Item already exists")
-        );
+    if let Some(rejected) = check_new_item(backend.as_ref(), &user, &signature)? {
+        if let Rejected::AlreadyExists = rejected {
+            // *sigh* this bug again. Should I handle this in middleware?
+            drain(body, &limits).await;
+        }
+        metrics.put_item(rejected.outcome());
+        return Ok(rejected.response());
     }
 
-    if !backend.user_known(&user)? {
-        return Ok(
-            HttpResponse::Forbidden()
-            .content_type(PLAINTEXT)
-            .body("Unknown user ID")
-        )
-    }
-    
     let mut bytes: Vec<u8> = Vec::with_capacity(length);
-    while let Some(chunk) = body.next().await {
-        let chunk = chunk.context("Error parsing chunk")?;
+    loop {
+        let chunk = match next_chunk(&mut body, &limits).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(response) => {
+                metrics.put_item("bad_body");
+                return Ok(response);
+            },
+        };
         bytes.extend_from_slice(&chunk);
+        // Don't trust the client's content-length:
+        if bytes.len() > limits.max_item_size {
+            metrics.put_item("too_large");
+            return Ok(
+                HttpResponse::PayloadTooLarge()
+                .content_type(PLAINTEXT)
+                .body(format!("Item must be <= {} bytes", limits.max_item_size))
+            );
+        }
     }
 
-    if !signature.is_valid(&user, &bytes) {
-        Err(format_err!("Invalid signature"))?;
-    }
-
-    let mut item: Item = Item::new();
-    item.merge_from_bytes(&bytes)?;
-    item.validate()?;
-
-    if item.timestamp_ms_utc > Timestamp::now().unix_utc_ms {
-        return Ok(
-            HttpResponse::BadRequest()
-            .content_type(PLAINTEXT)
-            .body("The Item's timestamp is in the future")
-        )
-    }
-
-    if let Some(deny_reason) = backend.quota_check_item(&user, &bytes, &item)? {
-        return Ok(
-            HttpResponse::InsufficientStorage()
-            .body(format!("{}", deny_reason))
-        )
-    }
+    let item = match validate_item(backend.as_ref(), &limits, &user, &signature, &bytes)? {
+        Ok(item) => item,
+        Err(rejected) => {
+            metrics.put_item(rejected.outcome());
+            return match rejected {
+                Rejected::InvalidSignature => Err(format_err!("Invalid signature").into()),
+                Rejected::InvalidItem(err) => Err(err.into()),
+                rejected => Ok(rejected.response()),
+            };
+        },
+    };
 
     let message = format!("OK. Received {} bytes.", bytes.len());
     
@@ -265,6 +262,7 @@ pub(crate) async fn put_item(
     let timer = timer!("save_user_item");
     backend.save_user_item(&row, &item).context("Error saving user item")?;
     drop(timer);
+    metrics.put_item("created");
 
     let response = HttpResponse::Created()
         .content_type(PLAINTEXT)
@@ -273,6 +271,207 @@ pub(crate) async fn put_item(
     Ok(response)
 }
 
+/// Why we won't accept an uploaded Item.
+enum Rejected {
+    AlreadyExists,
+    UnknownUser,
+    TooLarge(usize),
+    InvalidSignature,
+    InvalidItem(anyhow::Error),
+    FutureTimestamp,
+    QuotaExceeded(QuotaDenyReason),
+}
+
+impl Rejected {
+    /// A label for metrics.
+    fn outcome(&self) -> &'static str {
+        match self {
+            Self::AlreadyExists => "already_exists",
+            Self::UnknownUser => "unknown_user",
+            Self::TooLarge(_) => "too_large",
+            Self::InvalidSignature => "invalid_signature",
+            Self::InvalidItem(_) => "invalid_item",
+            Self::FutureTimestamp => "future_timestamp",
+            Self::QuotaExceeded(QuotaDenyReason::ReadOnly) => "read_only",
+            Self::QuotaExceeded(_) => "quota_exceeded",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::AlreadyExists => StatusCode::ACCEPTED,
+            Self::UnknownUser => StatusCode::FORBIDDEN,
+            Self::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::InvalidSignature => StatusCode::BAD_REQUEST,
+            Self::InvalidItem(_) => StatusCode::BAD_REQUEST,
+            Self::FutureTimestamp => StatusCode::BAD_REQUEST,
+            Self::QuotaExceeded(QuotaDenyReason::ReadOnly) => StatusCode::FORBIDDEN,
+            Self::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::AlreadyExists => "Item already exists".into(),
+            Self::UnknownUser => "Unknown user ID".into(),
+            Self::TooLarge(max) => format!("Item must be <= {} bytes", max),
+            Self::InvalidSignature => "Invalid signature".into(),
+            Self::InvalidItem(err) => format!("Invalid Item: {}", err),
+            Self::FutureTimestamp => "The Item's timestamp is in the future".into(),
+            Self::QuotaExceeded(reason) => reason.to_string(),
+        }
+    }
+
+    fn response(&self) -> HttpResponse {
+        HttpResponse::build(self.status())
+            .content_type(PLAINTEXT)
+            .body(self.message())
+    }
+}
+
+/// Checks we can do before reading an Item's bytes.
+fn check_new_item(backend: &dyn Backend, user: &UserID, signature: &Signature) -> Result<Option<Rejected>, anyhow::Error> {
+    // If the content already exists, do nothing.
+    if backend.user_item_exists(user, signature)? {
+        return Ok(Some(Rejected::AlreadyExists));
+    }
+
+    if !backend.user_known(user)? {
+        return Ok(Some(Rejected::UnknownUser));
+    }
+
+    Ok(None)
+}
+
+/// Validate an uploaded Item, and check that the user may store it.
+fn validate_item(
+    backend: &dyn Backend,
+    limits: &Limits,
+    user: &UserID,
+    signature: &Signature,
+    bytes: &[u8],
+) -> Result<Result<Item, Rejected>, anyhow::Error> {
+    if bytes.len() > limits.max_item_size {
+        return Ok(Err(Rejected::TooLarge(limits.max_item_size)));
+    }
+
+    if !signature.is_valid(user, bytes) {
+        return Ok(Err(Rejected::InvalidSignature));
+    }
+
+    let mut item: Item = Item::new();
+    let valid = item.merge_from_bytes(bytes)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| Ok(item.validate()?));
+    if let Err(err) = valid {
+        return Ok(Err(Rejected::InvalidItem(err)));
+    }
+
+    if item.timestamp_ms_utc > Timestamp::now().unix_utc_ms + limits.clock_drift_ms {
+        return Ok(Err(Rejected::FutureTimestamp));
+    }
+
+    if let Some(deny_reason) = backend.quota_check_item(user, bytes, &item)? {
+        return Ok(Err(Rejected::QuotaExceeded(deny_reason)));
+    }
+
+    Ok(Ok(item))
+}
+
+/// Upload many items at once.
+///
+/// `POST /items/upload/proto3` with an `ItemUploadRequest` body.
+/// Items are validated like in `put_item()`, and saved in a single transaction.
+/// Returns an `ItemUploadResponse` with a status for each item.
+pub(crate) async fn upload_items(
+    data: Data<AppData>,
+    mut body: Payload,
+) -> Result<HttpResponse, Error> {
+    let _timer = timer!("upload_items()");
+
+    let limits = data.config.get().limits.clone();
+    let metrics = &data.metrics;
+
+    let bytes = match read_body(&mut body, &limits, limits.max_batch_upload_bytes).await {
+        Ok(bytes) => bytes,
+        Err(response) => {
+            metrics.put_item("bad_body");
+            return Ok(response);
+        },
+    };
+
+    let mut request = ItemUploadRequest::new();
+    request.merge_from_bytes(&bytes)?;
+
+    if request.items.len() > limits.max_batch_items {
+        return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(format!("May not upload more than {} items at once", limits.max_batch_items))
+        );
+    }
+
+    let mut response = ItemUploadResponse::new();
+    let mut backend = data.backend_factory.open()?;
+    backend.transaction(&mut |backend| {
+        for upload in request.get_items() {
+            let mut status = ItemUploadStatus::new();
+            status.set_user_id(upload.get_user_id().clone());
+            status.set_signature(upload.get_signature().clone());
+
+            let (code, message) = upload_item(backend, &limits, metrics, upload)?;
+            status.set_status(code.as_u16().into());
+            status.set_message(message);
+            response.mut_items().push(status);
+        }
+        Ok(())
+    })?;
+
+    Ok(
+        proto_ok().body(response.write_to_bytes()?)
+    )
+}
+
+/// Validate and save one item from an `ItemUploadRequest`.
+fn upload_item(
+    backend: &mut dyn Backend,
+    limits: &Limits,
+    metrics: &Metrics,
+    upload: &ItemUpload,
+) -> Result<(StatusCode, String), anyhow::Error> {
+    let user = UserID::from_vec(upload.get_user_id().get_bytes().into());
+    let signature = Signature::from_vec(upload.get_signature().get_bytes().into());
+    let (user, signature) = match (user, signature) {
+        (Ok(user), Ok(signature)) => (user, signature),
+        _ => return Ok((StatusCode::BAD_REQUEST, "Invalid user ID or signature".into())),
+    };
+
+    let bytes = upload.get_item_bytes();
+    let checked = match check_new_item(backend, &user, &signature)? {
+        Some(rejected) => Err(rejected),
+        None => validate_item(backend, limits, &user, &signature, bytes)?,
+    };
+    let item = match checked {
+        Ok(item) => item,
+        Err(rejected) => {
+            metrics.put_item(rejected.outcome());
+            return Ok((rejected.status(), rejected.message()));
+        }
+    };
+
+    let row = ItemRow{
+        user,
+        signature,
+        timestamp: Timestamp{ unix_utc_ms: item.get_timestamp_ms_utc()},
+        received: Timestamp::now(),
+        item_bytes: bytes.to_vec(),
+    };
+    backend.save_user_item(&row, &item).context("Error saving user item")?;
+    metrics.put_item("created");
+
+    Ok((StatusCode::CREATED, format!("OK. Received {} bytes.", bytes.len())))
+}
+
 
 
 /// Get the binary representation of the item.
@@ -281,6 +480,7 @@ pub(crate) async fn put_item(
 pub(crate) async fn get_item(
     data: Data<AppData>,
     path: Path<(UserID, Signature,)>,
+    format: Format,
 ) -> Result<HttpResponse, Error> {
     let (user_id, signature) = path.into_inner();
     let backend = data.backend_factory.open()?;
@@ -297,6 +497,9 @@ pub(crate) async fn get_item(
     // We could in theory validate the bytes ourselves, but if a client is directly fetching the 
     // protobuf bytes via this endpoint, it's probably going to be so that it can verify the bytes
     // for itself anyway.
+    if format == Format::Json {
+        return Ok(json_ok(&item_envelope(&user_id, &signature, &item.item_bytes)?));
+    }
     Ok(
         proto_ok()
         .body(item.item_bytes)
@@ -309,6 +512,7 @@ pub(crate) async fn get_item(
 pub(crate) async fn get_profile_item(
     data: Data<AppData>,
     path: Path<(UserID,)>,
+    format: Format,
 ) -> Result<HttpResponse, Error> {
     let (user_id,) = path.into_inner();
     let backend = data.backend_factory.open()?;
@@ -325,6 +529,15 @@ pub(crate) async fn get_profile_item(
     // We could in theory validate the bytes ourselves, but if a client is directly fetching the 
     // protobuf bytes via this endpoint, it's probably going to be so that it can verify the bytes
     // for itself anyway.
+    if format == Format::Json {
+        let envelope = item_envelope(&user_id, &item.signature, &item.item_bytes)?;
+        return Ok(
+            HttpResponse::Ok()
+            .content_type(JSON)
+            .append_header(("signature", item.signature.to_base58()))
+            .body(envelope.to_string())
+        );
+    }
     Ok(
         proto_ok()
         .append_header(("signature", item.signature.to_base58()))
@@ -332,9 +545,417 @@ pub(crate) async fn get_profile_item(
     )
 }
 
+#[derive(Deserialize, Debug)]
+pub(crate) struct DigestQuery {
+    /// Start of the time range. (Item.timestamp_ms_utc, inclusive)
+    start: i64,
+
+    /// End of the time range. (exclusive)
+    end: i64,
+
+    /// How many buckets to split the range into.
+    buckets: Option<usize>,
+}
+
+const DEFAULT_DIGEST_BUCKETS: usize = 16;
+const MAX_DIGEST_BUCKETS: usize = 256;
+
+/// Digests of a user's items, for finding differences between servers.
+///
+/// `GET /u/{user_id}/digest/proto3?start=<ms>&end=<ms>&buckets=<n>`
+/// Returns `ItemDigests`.
+pub(crate) async fn user_item_digests(
+    data: Data<AppData>,
+    path: Path<(UserID,)>,
+    Query(query): Query<DigestQuery>,
+    format: Format,
+) -> Result<HttpResponse, Error> {
+    let (user_id,) = path.into_inner();
+
+    let buckets = query.buckets.unwrap_or(DEFAULT_DIGEST_BUCKETS);
+    if buckets == 0 || buckets > MAX_DIGEST_BUCKETS {
+        return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(format!("buckets must be between 1 and {}", MAX_DIGEST_BUCKETS))
+        );
+    }
+    if query.start >= query.end {
+        return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body("start must be before end")
+        );
+    }
+
+    let mut digests = Digests::new(query.start, query.end, buckets);
+
+    let backend = data.backend_factory.open()?;
+    backend.user_item_signatures(
+        &user_id,
+        Timestamp{ unix_utc_ms: query.start },
+        Timestamp{ unix_utc_ms: query.end },
+        &mut |(timestamp, signature)| {
+            digests.add(timestamp.unix_utc_ms, &signature);
+            Ok(true)
+        }
+    )?;
+
+    let digests = digests.into_proto();
+    match format {
+        Format::Proto3 => Ok(proto_ok().body(digests.write_to_bytes()?)),
+        Format::Json => Ok(json_ok(&to_json(&digests))),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ChangesQuery {
+    /// Only list items saved after this one. (ItemListEntry.change_seq)
+    after: Option<i64>,
+
+    /// Limit how many items appear on a page.
+    count: Option<usize>,
+}
+
+/// List items from known users, in the order this server saved them.
+///
+/// `GET /changes/proto3?after=<change_seq>`
+/// Peers can follow this server incrementally by passing the `change_seq` of
+/// the last entry as the next `after`.
+pub(crate) async fn changes(
+    data: Data<AppData>,
+    Query(query): Query<ChangesQuery>,
+    format: Format,
+) -> Result<HttpResponse, Error> {
+    let max_items = data.config.get().limits.max_list_items;
+    let max_items = query.count.map(|c| c.max(1).min(max_items)).unwrap_or(max_items);
+
+    let mut entries = vec![];
+    let mut has_more = false;
+
+    let backend = data.backend_factory.open()?;
+    backend.changes_after(query.after.unwrap_or(0), &mut |(change_seq, row)| {
+        if entries.len() >= max_items {
+            has_more = true;
+            return Ok(false);
+        }
+
+        let mut item = Item::new();
+        item.merge_from_bytes(&row.item_bytes)?;
+        let mut entry = item_to_entry(&item, &row.user, &row.signature);
+        entry.set_received_ms(row.received.unix_utc_ms);
+        entry.set_change_seq(change_seq);
+
+        entries.push(entry);
+        Ok(true)
+    })?;
+
+    let mut list = ItemList::new();
+    list.no_more_items = !has_more;
+    list.items = protobuf::RepeatedField::from(entries);
+    list_response(format, &list)
+}
+
+/// Fetch many items at once.
+///
+/// `POST /items/batch/proto3` with an `ItemBatchRequest` body.
+/// Returns an `ItemBatch`.
+pub(crate) async fn item_batch(
+    data: Data<AppData>,
+    mut body: Payload,
+) -> Result<HttpResponse, Error> {
+    let limits = data.config.get().limits.clone();
+
+    // Each ItemID is ~100 bytes:
+    let max_bytes = limits.max_batch_items * 128 + 1024;
+    let bytes = match read_body(&mut body, &limits, max_bytes).await {
+        Ok(bytes) => bytes,
+        Err(response) => return Ok(response),
+    };
+
+    let mut request = ItemBatchRequest::new();
+    request.merge_from_bytes(&bytes)?;
+
+    if request.items.len() > limits.max_batch_items {
+        return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(format!("May not request more than {} items at once", limits.max_batch_items))
+        );
+    }
+
+    let ids = match parse_ids(request.get_items()) {
+        Ok(ids) => ids,
+        Err(response) => return Ok(response),
+    };
+
+    let backend = data.backend_factory.open()?;
+    let mut found = HashMap::with_capacity(ids.len());
+    backend.user_items_by_id(&ids, &mut |row| {
+        found.insert((row.user, row.signature), row.item_bytes);
+        Ok(true)
+    })?;
+
+    let mut batch = ItemBatch::new();
+    for (user_id, signature) in ids {
+        let mut entry = ItemBatchEntry::new();
+        entry.mut_user_id().set_bytes(user_id.bytes().into());
+        entry.mut_signature().set_bytes(signature.bytes().into());
+        match found.get(&(user_id, signature)) {
+            Some(item_bytes) => entry.set_item_bytes(item_bytes.clone()),
+            None => entry.set_not_found(true),
+        }
+        batch.mut_items().push(entry);
+    }
+
+    Ok(
+        proto_ok().body(batch.write_to_bytes()?)
+    )
+}
+
+/// Check which items and attachments the server already has.
+///
+/// `POST /have/proto3` with a `HaveRequest` body.
+/// Returns a `HaveResponse`.
+pub(crate) async fn have(
+    data: Data<AppData>,
+    mut body: Payload,
+) -> Result<HttpResponse, Error> {
+    let limits = data.config.get().limits.clone();
+
+    // Leave room for attachment file names:
+    let max_bytes = limits.max_batch_items * 512 + 1024;
+    let bytes = match read_body(&mut body, &limits, max_bytes).await {
+        Ok(bytes) => bytes,
+        Err(response) => return Ok(response),
+    };
+
+    let mut request = HaveRequest::new();
+    request.merge_from_bytes(&bytes)?;
+
+    if request.items.len() + request.attachments.len() > limits.max_batch_items {
+        return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(format!("May not check more than {} items and attachments at once", limits.max_batch_items))
+        );
+    }
+
+    let ids = match parse_ids(request.get_items()) {
+        Ok(ids) => ids,
+        Err(response) => return Ok(response),
+    };
+
+    let mut attachments = Vec::with_capacity(request.attachments.len());
+    for attachment in request.get_attachments() {
+        let user_id = UserID::from_vec(attachment.get_user_id().get_bytes().into());
+        let signature = Signature::from_vec(attachment.get_signature().get_bytes().into());
+        match (user_id, signature) {
+            (Ok(user_id), Ok(signature)) => attachments.push((user_id, signature, attachment.get_name().to_string())),
+            _ => return Ok(invalid_id()),
+        }
+    }
+
+    let backend = data.backend_factory.open()?;
+
+    let mut existing = HashSet::with_capacity(ids.len());
+    backend.user_items_exist(&ids, &mut |id| {
+        existing.insert(id);
+        Ok(true)
+    })?;
+
+    let mut items = Bitmap::new(ids.len());
+    let mut items_quota_exceeded = Bitmap::new(ids.len());
+    // Quotas are per-user, so only check each user once:
+    let mut user_quota_exceeded = HashMap::new();
+    for (index, id) in ids.iter().enumerate() {
+        if existing.contains(id) {
+            items.set(index);
+            continue;
+        }
+        let (user_id, _) = id;
+        let exceeded = match user_quota_exceeded.get(user_id) {
+            Some(exceeded) => *exceeded,
+            None => {
+                // We don't have the item yet, so can only check the user's quota/permissions:
+                let exceeded = backend.quota_check_item(user_id, &[], &Item::new())?.is_some();
+                user_quota_exceeded.insert(user_id.clone(), exceeded);
+                exceeded
+            }
+        };
+        if exceeded {
+            items_quota_exceeded.set(index);
+        }
+    }
+
+    let mut metas = HashMap::with_capacity(attachments.len());
+    backend.attachments_meta(&attachments, &mut |(id, meta)| {
+        metas.insert(id, meta);
+        Ok(true)
+    })?;
+
+    let mut files = Bitmap::new(attachments.len());
+    let mut quota_exceeded = Bitmap::new(attachments.len());
+    for (index, id) in attachments.iter().enumerate() {
+        let meta = match metas.get(id) {
+            Some(meta) => meta,
+            None => continue,
+        };
+        if meta.exists {
+            files.set(index);
+        } else if meta.quota_exceeded {
+            quota_exceeded.set(index);
+        }
+    }
+
+    let mut response = HaveResponse::new();
+    response.set_items(items.into_bytes());
+    response.set_items_quota_exceeded(items_quota_exceeded.into_bytes());
+    response.set_attachments(files.into_bytes());
+    response.set_attachments_quota_exceeded(quota_exceeded.into_bytes());
+
+    Ok(
+        proto_ok().body(response.write_to_bytes()?)
+    )
+}
+
+/// Read a (small) request body into memory.
+/// On error, returns the response to send to the client.
+async fn read_body(body: &mut Payload, limits: &Limits, max_bytes: usize) -> Result<Vec<u8>, HttpResponse> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = next_chunk(body, limits).await? {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > max_bytes {
+            return Err(
+                HttpResponse::PayloadTooLarge()
+                .content_type(PLAINTEXT)
+                .body(format!("Request must be <= {} bytes", max_bytes))
+            );
+        }
+    }
+    Ok(bytes)
+}
+
+fn parse_ids(ids: &[ItemID]) -> Result<Vec<(UserID, Signature)>, HttpResponse> {
+    let mut parsed = Vec::with_capacity(ids.len());
+    for id in ids {
+        let user_id = UserID::from_vec(id.get_user_id().get_bytes().into());
+        let signature = Signature::from_vec(id.get_signature().get_bytes().into());
+        match (user_id, signature) {
+            (Ok(user_id), Ok(signature)) => parsed.push((user_id, signature)),
+            _ => return Err(invalid_id()),
+        }
+    }
+    Ok(parsed)
+}
+
+fn invalid_id() -> HttpResponse {
+    HttpResponse::BadRequest()
+    .content_type(PLAINTEXT)
+    .body("Invalid user ID or signature")
+}
+
+/// Optional protocol features that this server supports.
+/// See: ServerInfo.features in feoblog.proto
+const FEATURES: &[&str] = &[
+    // ItemList endpoints accept `?types=`:
+    "item_type_filter",
+    // ItemList endpoints accept `?before=` and `?after=`:
+    "time_pagination",
+    // /u/{userID}/i/{signature}/files/*
+    "attachments",
+    // /u/{userID}/i/{signature}/replies/proto3
+    "replies",
+    // `/json` versions of REST endpoints:
+    "json",
+    // POST /items/batch/proto3
+    "item_batch",
+    // POST /have/proto3
+    "have",
+    // GET /changes/proto3
+    "changes",
+    // GET /u/{userID}/digest/proto3
+    "digest",
+    // POST /items/upload/proto3
+    "item_upload",
+    // /u/{userID}/i/{signature}/files/{fileName}/upload
+    "resumable_uploads",
+];
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ServerInfoQuery {
+    /// Report on this user's status on the server.
+    user: Option<UserID>,
+}
+
+/// Describe this server's version, features and limits.
+///
+/// `/server/info/proto3[?user={userID}]`
+pub(crate) async fn server_info(
+    data: Data<AppData>,
+    Query(query): Query<ServerInfoQuery>,
+) -> Result<HttpResponse, Error> {
+    let info = get_server_info(&data, query)?;
+    Ok(
+        proto_ok().body(info.write_to_bytes()?)
+    )
+}
+
+/// Same as server_info(), but as JSON.
+///
+/// `/server/info/json[?user={userID}]`
+pub(crate) async fn server_info_json(
+    data: Data<AppData>,
+    Query(query): Query<ServerInfoQuery>,
+) -> Result<HttpResponse, Error> {
+    let info = get_server_info(&data, query)?;
+    Ok(json_ok(&to_json(&info)))
+}
+
+fn get_server_info(data: &AppData, query: ServerInfoQuery) -> Result<ServerInfo, anyhow::Error> {
+    let config = data.config.get();
+
+    let mut info = ServerInfo::new();
+    info.set_software("feoblog".into());
+    info.set_version(env!("CARGO_PKG_VERSION").into());
+    info.set_features(FEATURES.iter().map(|f| f.to_string()).collect());
+    info.set_item_types(vec![ItemType::POST, ItemType::PROFILE, ItemType::COMMENT]);
+    info.set_attachment_mime_types(SAFE_MIME_TYPES.iter().map(|t| t.to_string()).collect());
+
+    let limits = info.mut_limits();
+    limits.set_max_item_size(config.limits.max_item_size as u64);
+    limits.set_max_list_items(config.limits.max_list_items as u64);
+    limits.set_clock_drift_ms(config.limits.clock_drift_ms);
+    limits.set_max_batch_items(config.limits.max_batch_items as u64);
+
+    if let Some(user_id) = query.user {
+        let backend = data.backend_factory.open()?;
+        let server_user = backend.server_user(&user_id)?;
+        let known_user = backend.user_known(&user_id)?;
+
+        let user = info.mut_user();
+        user.mut_user_id().set_bytes(user_id.bytes().into());
+        user.set_known_user(known_user);
+        if let Some(server_user) = server_user {
+            user.set_server_user(true);
+            user.set_on_homepage(server_user.on_homepage);
+        }
+    }
+
+    Ok(info)
+}
+
 
 // --------------------------------------
 
+/// Respond with an ItemList in the requested format.
+fn list_response(format: Format, list: &ItemList) -> Result<HttpResponse, Error> {
+    match format {
+        Format::Proto3 => Ok(proto_ok().body(list.write_to_bytes()?)),
+        Format::Json => Ok(json_ok(&to_json(list))),
+    }
+}
+
 // Start building a response w/ proto3 binary data.
 fn proto_ok() -> HttpResponseBuilder {
     let mut builder = HttpResponse::Ok();
@@ -355,14 +976,7 @@ fn item_to_entry(item: &Item, user_id: &UserID, signature: &Signature) -> ItemLi
         uid.set_bytes(user_id.bytes().into());
         uid
     });
-    entry.set_item_type(
-        match item.item_type {
-            Some(Item_oneof_item_type::post(_)) => ItemType::POST,
-            Some(Item_oneof_item_type::profile(_)) => ItemType::PROFILE,
-            Some(Item_oneof_item_type::comment(_)) => ItemType::COMMENT,
-            None => ItemType::UNKNOWN,
-        }
-    );
+    entry.set_item_type(item.item_type_enum());
 
     entry
 }
@@ -0,0 +1,43 @@
+//! Periodically pruning followed users' old items. (See: `[retention]` in the server config.)
+
+use std::time::Duration;
+
+use actix_web::web;
+use log::{error, info};
+
+use crate::backend::{FactoryBox, PruneOpts, RetentionRules};
+
+/// Prune items that `rules` don't keep, and the attachments they leave unused, every `interval`.
+pub(crate) fn schedule(factory: FactoryBox, rules: RetentionRules, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(interval).await;
+
+            let factory = factory.clone();
+            let rules = rules.clone();
+            let result = web::block(move || {
+                let backend = factory.factory.open()?;
+                backend.prune(PruneOpts{
+                    dry_run: false,
+                    attachments: true,
+                    // Unfollowing someone shouldn't silently delete their items.
+                    // That's still up to `feoblog db prune`.
+                    items: false,
+                    retention: Some(rules),
+                    // Leave that to `feoblog db maintain`, so we don't lock the database for long.
+                    vacuum: false,
+                })
+            }).await;
+
+            match result {
+                Ok(Ok(result)) => info!(
+                    "Retention: pruned {} items ({} bytes) from {} users, and {} attachments ({} bytes).",
+                    result.items_count, result.items_bytes, result.users.len(),
+                    result.attachments_count, result.attachments_bytes,
+                ),
+                Ok(Err(err)) => error!("Error pruning database: {:#}", err),
+                Err(err) => error!("Error pruning database: {}", err),
+            }
+        }
+    });
+}
@@ -0,0 +1,186 @@
+//! HTTPS support, via rustls.
+//!
+//! Certificates are loaded through a [`CertResolver`], which lets us swap in
+//! renewed certificates without restarting the server.
+
+use std::{fs::File, io::BufReader, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use actix_web::{HttpRequest, HttpResponse, http::header, web::Data};
+use anyhow::{Context, Error, bail, format_err};
+use log::{error, info};
+use rustls::{Certificate, PrivateKey, ServerConfig, server::{ClientHello, ResolvesServerCert}, sign::{self, CertifiedKey}};
+
+use crate::config::TlsConfig;
+
+/// Serves the current certificate for all TLS connections.
+pub(crate) struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Loaded>,
+}
+
+struct Loaded {
+    key: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+}
+
+impl CertResolver {
+    pub fn new(config: &TlsConfig) -> Result<Self, Error> {
+        let (cert_path, key_path) = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            _ => bail!("TLS requires both a certificate and a key file"),
+        };
+
+        let modified = last_modified(&cert_path, &key_path);
+        let key = load_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(Loaded{key, modified}),
+        })
+    }
+
+    /// Re-read the certificate and key files.
+    /// On error, we keep using the previous certificate.
+    pub fn reload(&self) -> Result<(), Error> {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().expect("cert lock") = Loaded{key, modified};
+        Ok(())
+    }
+
+    /// Reload the certificate only if its files have changed since we last loaded them.
+    pub fn reload_if_changed(&self) -> Result<bool, Error> {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        let previous = self.current.read().expect("cert lock").modified;
+        if modified.is_none() || modified == previous {
+            return Ok(false);
+        }
+
+        self.reload()?;
+        Ok(true)
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let current = self.current.read().ok()?;
+        Some(current.key.clone())
+    }
+}
+
+/// The time the cert or key was most recently modified.
+fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let cert = std::fs::metadata(cert_path).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(key_path).and_then(|m| m.modified()).ok()?;
+    Some(std::cmp::max(cert, key))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>, Error> {
+    let certs = {
+        let file = File::open(cert_path)
+            .with_context(|| format!("Error opening TLS certificate {}", cert_path.display()))?;
+        rustls_pemfile::certs(&mut BufReader::new(file))
+            .with_context(|| format!("Error reading TLS certificate {}", cert_path.display()))?
+    };
+    if certs.is_empty() {
+        bail!("No certificates found in {}", cert_path.display());
+    }
+    let certs = certs.into_iter().map(Certificate).collect();
+
+    let key = {
+        let file = File::open(key_path)
+            .with_context(|| format!("Error opening TLS key {}", key_path.display()))?;
+        let mut reader = BufReader::new(file);
+        read_private_key(&mut reader)
+            .with_context(|| format!("Error reading TLS key {}", key_path.display()))?
+    };
+
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| format_err!("Unsupported private key type in {}", key_path.display()))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+/// Finds the first private key in a PEM file.
+fn read_private_key(reader: &mut BufReader<File>) -> Result<PrivateKey, Error> {
+    use rustls_pemfile::Item;
+    loop {
+        match rustls_pemfile::read_one(reader)? {
+            None => bail!("No private key found"),
+            Some(Item::PKCS8Key(key)) => return Ok(PrivateKey(key)),
+            Some(Item::RSAKey(key)) => return Ok(PrivateKey(key)),
+            Some(Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+        }
+    }
+}
+
+/// Periodically check whether the certificate files have been updated. (ex: by certbot)
+pub(crate) fn watch_for_changes(certs: Arc<CertResolver>, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(interval).await;
+            match certs.reload_if_changed() {
+                Ok(true) => info!("Reloaded TLS certificate."),
+                Ok(false) => {},
+                Err(err) => error!("Error reloading TLS certificate: {:#}", err),
+            }
+        }
+    });
+}
+
+/// Redirects plain HTTP requests to the HTTPS version of the same URL.
+pub(crate) async fn redirect_to_https(req: HttpRequest, https_port: Data<HttpsPort>) -> HttpResponse {
+    let info = req.connection_info();
+    let host = strip_port(info.host());
+    let port = match https_port.0 {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = format!("https://{}{}{}", host, port, path);
+
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+/// The port that HTTPS is served on, for building redirect URLs.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HttpsPort(pub u16);
+
+/// Remove the port from a Host header value. Handles IPv6 literals like `[::1]:80`.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+    match host.rfind(':') {
+        Some(colon) => &host[..colon],
+        None => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_port;
+
+    #[test]
+    fn strip_ports() {
+        assert_eq!("example.com", strip_port("example.com"));
+        assert_eq!("example.com", strip_port("example.com:8080"));
+        assert_eq!("[::1]", strip_port("[::1]:8080"));
+        assert_eq!("[::1]", strip_port("[::1]"));
+    }
+}
@@ -0,0 +1,455 @@
+//! Resumable uploads of file attachments.
+//!
+//! `put_file()` needs a whole file in one request. For large files over flaky
+//! connections, clients can instead use a simple tus-like protocol at
+//! `/u/:userID/i/:signature/files/:fileName/upload`:
+//!
+//!  * POST starts (or resumes) an upload.
+//!  * HEAD reports how many bytes we've received, in `Upload-Offset`.
+//!  * PATCH appends bytes, starting at the `Upload-Offset` request header.
+//!  * DELETE abandons the upload.
+//!
+//! Partial uploads are kept in `[uploads] dir`, so they survive restarts. We
+//! keep the running SHA-512 state in memory between requests. After a restart
+//! we re-hash the partial file before appending to it.
+
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{Read, Seek, SeekFrom}, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
+
+use actix_web::{HttpRequest, HttpResponse, web::{self, Data, Path, Payload}};
+use anyhow::Context;
+use futures::AsyncWriteExt;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::hash::sha512;
+
+use crate::{backend::{FileMeta, SHA512, Signature, Timestamp, UserID}, util::AsHex};
+
+use super::{AppData, Error, PLAINTEXT, attachments::{drain, next_chunk}};
+
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+const UPLOAD_LENGTH: &str = "Upload-Length";
+
+pub(crate) struct Uploads {
+    dir: PathBuf,
+
+    /// Hash state for uploads we've seen since startup.
+    /// `None` while a request is writing to that upload.
+    sessions: Mutex<HashMap<String, Option<Progress>>>,
+}
+
+struct Progress {
+    /// How many bytes `hasher` has seen.
+    offset: u64,
+    hasher: sha512::State,
+}
+
+/// Saved next to each partial upload, so we can clean up old ones.
+#[derive(Serialize, Deserialize)]
+struct SessionInfo {
+    user_id: String,
+    signature: String,
+    file_name: String,
+    size: u64,
+    created_ms: i64,
+}
+
+impl Uploads {
+    pub fn new(dir: PathBuf) -> Result<Self, anyhow::Error> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Error creating uploads directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// A file-name-safe key for an attachment.
+    fn key(user_id: &UserID, signature: &Signature, file_name: &str) -> String {
+        let mut hasher = sha512::State::new();
+        hasher.update(user_id.bytes());
+        hasher.update(signature.bytes());
+        hasher.update(file_name.as_bytes());
+        let digest = hasher.finalize();
+        (&digest.0[..32]).as_hex().to_string()
+    }
+
+    fn part_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", key))
+    }
+
+    fn info_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// Get exclusive use of an upload, or None if another request has it.
+    fn claim(self: &Arc<Self>, key: &str) -> Option<Claim> {
+        let mut sessions = self.sessions.lock().expect("uploads lock");
+        let progress = match sessions.get_mut(key) {
+            Some(None) => return None,
+            Some(progress) => progress.take(),
+            None => {
+                sessions.insert(key.to_string(), None);
+                None
+            },
+        };
+        Some(Claim {
+            uploads: self.clone(),
+            key: key.to_string(),
+            progress,
+        })
+    }
+
+    /// The number of bytes received so far, or None if there's no such upload.
+    fn offset(&self, key: &str) -> Option<u64> {
+        if !self.info_path(key).exists() {
+            return None;
+        }
+        fs::metadata(self.part_path(key)).ok().map(|m| m.len())
+    }
+
+    fn remove(&self, key: &str) {
+        for path in &[self.part_path(key), self.info_path(key)] {
+            if let Err(err) = fs::remove_file(path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    error!("Error removing {}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+
+    /// Remove uploads that were started more than `max_age` ago.
+    fn remove_expired(self: &Arc<Self>, max_age: Duration) -> Result<(), anyhow::Error> {
+        let cutoff = Timestamp::now().unix_utc_ms - max_age.as_millis() as i64;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e != "json").unwrap_or(true) {
+                continue;
+            }
+            let key = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(key) => key.to_string(),
+                None => continue,
+            };
+
+            let info: Option<SessionInfo> = fs::read(&path).ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+            let expired = info.map(|info| info.created_ms < cutoff).unwrap_or(true);
+            if !expired {
+                continue;
+            }
+
+            // Skip uploads that are in progress right now.
+            // (Dropping the claim also forgets the upload's hash state.)
+            if let Some(_claim) = self.claim(&key) {
+                info!("Removing expired upload {}", path.display());
+                self.remove(&key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Exclusive use of an upload. Saves the hash state (if any) when dropped.
+struct Claim {
+    uploads: Arc<Uploads>,
+    key: String,
+    progress: Option<Progress>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut sessions = self.uploads.sessions.lock().expect("uploads lock");
+        match self.progress.take() {
+            Some(progress) => { sessions.insert(self.key.clone(), Some(progress)); },
+            // We'll re-hash the file if we need to:
+            None => { sessions.remove(&self.key); },
+        }
+    }
+}
+
+/// Checks before we accept bytes for an attachment.
+/// Returns the attachment's metadata, or the response to send to the client.
+fn check_attachment(
+    data: &AppData,
+    user_id: &UserID,
+    signature: &Signature,
+    file_name: &str,
+) -> Result<Result<FileMeta, HttpResponse>, Error> {
+    let backend = data.backend_factory.open()?;
+    let metadata = backend.get_attachment_meta(user_id, signature, file_name)?;
+
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => return Ok(Err(
+            HttpResponse::Forbidden()
+            .content_type(PLAINTEXT)
+            .body("No such attachment for this Item, or no such Item.")
+        )),
+    };
+
+    if metadata.exists {
+        return Ok(Err(
+            HttpResponse::Accepted()
+            .content_type(PLAINTEXT)
+            .body("Attachment already exists")
+        ));
+    }
+
+    if metadata.quota_exceeded {
+        return Ok(Err(
+            HttpResponse::Forbidden()
+            .content_type(PLAINTEXT)
+            .body("Uploading this attachment would voilate the users's quota.")
+        ));
+    }
+
+    Ok(Ok(metadata))
+}
+
+fn progress_response(mut response: actix_web::HttpResponseBuilder, offset: u64, size: u64) -> HttpResponse {
+    response
+        .insert_header((UPLOAD_OFFSET, offset.to_string()))
+        .insert_header((UPLOAD_LENGTH, size.to_string()))
+        .insert_header(("Cache-Control", "no-store"))
+        .finish()
+}
+
+/// `POST .../files/{file_name}/upload`: Start or resume an upload.
+pub(crate) async fn create_upload(
+    data: Data<AppData>,
+    path: Path<(UserID, Signature, String)>,
+) -> Result<HttpResponse, Error> {
+    let (user_id, signature, file_name) = path.into_inner();
+    let metadata = match check_attachment(&data, &user_id, &signature, &file_name)? {
+        Ok(metadata) => metadata,
+        Err(response) => return Ok(response),
+    };
+
+    let uploads = &data.uploads;
+    let key = Uploads::key(&user_id, &signature, &file_name);
+    if let Some(offset) = uploads.offset(&key) {
+        return Ok(progress_response(HttpResponse::Ok(), offset, metadata.size));
+    }
+
+    let info = SessionInfo {
+        user_id: user_id.to_base58(),
+        signature: signature.to_base58(),
+        file_name,
+        size: metadata.size,
+        created_ms: Timestamp::now().unix_utc_ms,
+    };
+    File::create(uploads.part_path(&key)).context("Error creating upload")?;
+    fs::write(uploads.info_path(&key), serde_json::to_vec(&info)?).context("Error creating upload")?;
+
+    Ok(progress_response(HttpResponse::Created(), 0, metadata.size))
+}
+
+/// `HEAD .../files/{file_name}/upload`: Check an upload's progress.
+pub(crate) async fn upload_status(
+    data: Data<AppData>,
+    path: Path<(UserID, Signature, String)>,
+) -> Result<HttpResponse, Error> {
+    let (user_id, signature, file_name) = path.into_inner();
+    let key = Uploads::key(&user_id, &signature, &file_name);
+    let offset = match data.uploads.offset(&key) {
+        Some(offset) => offset,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let backend = data.backend_factory.open()?;
+    let size = backend.get_attachment_meta(&user_id, &signature, &file_name)?
+        .map(|m| m.size)
+        .unwrap_or(0);
+
+    Ok(progress_response(HttpResponse::Ok(), offset, size))
+}
+
+/// `PATCH .../files/{file_name}/upload`: Append bytes to an upload.
+pub(crate) async fn upload_chunk(
+    data: Data<AppData>,
+    path: Path<(UserID, Signature, String)>,
+    req: HttpRequest,
+    mut body: Payload,
+) -> Result<HttpResponse, Error> {
+    let (user_id, signature, file_name) = path.into_inner();
+    let limits = data.config.get().limits.clone();
+
+    let start: u64 = match req.headers().get(UPLOAD_OFFSET).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()) {
+        Some(start) => start,
+        None => return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(format!("Must include a valid {} header.", UPLOAD_OFFSET))
+        ),
+    };
+
+    let key = Uploads::key(&user_id, &signature, &file_name);
+    let metadata = match check_attachment(&data, &user_id, &signature, &file_name)? {
+        Ok(metadata) => metadata,
+        Err(response) => {
+            drain(body, &limits).await;
+            return Ok(response);
+        },
+    };
+
+    let uploads = data.uploads.clone();
+    if uploads.offset(&key).is_none() {
+        return Ok(
+            HttpResponse::NotFound()
+            .content_type(PLAINTEXT)
+            .body("No such upload. POST to start one.")
+        );
+    }
+
+    let mut claim = match uploads.claim(&key) {
+        Some(claim) => claim,
+        None => return Ok(
+            HttpResponse::Conflict()
+            .content_type(PLAINTEXT)
+            .body("Another request is already uploading this file.")
+        ),
+    };
+
+    let part_path = uploads.part_path(&key);
+    let previous = claim.progress.take();
+    let (file, mut progress) = blocking::unblock(move || -> Result<(File, Progress), anyhow::Error> {
+        let file = OpenOptions::new().read(true).append(true).open(&part_path)?;
+        let len = file.metadata()?.len();
+        let progress = match previous {
+            Some(progress) if progress.offset == len => progress,
+            _ => {
+                debug!("Re-hashing partial upload {}", part_path.display());
+                rehash(&file, len)?
+            },
+        };
+        Ok((file, progress))
+    }).await?;
+
+    if start != progress.offset {
+        let offset = progress.offset;
+        claim.progress = Some(progress);
+        drain(body, &limits).await;
+        return Ok(progress_response(HttpResponse::Conflict(), offset, metadata.size));
+    }
+
+    // Unblock's default buffer for I/O is *8MiB*!?  32k at a time seems fine.
+    let mut writer = blocking::Unblock::with_capacity(32 * 1024, file);
+
+    let mut error_response = None;
+    loop {
+        let chunk = match next_chunk(&mut body, &limits).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(response) => {
+                // Keep what we've received so far:
+                error_response = Some(response);
+                break;
+            },
+        };
+
+        if progress.offset + chunk.len() as u64 > metadata.size {
+            error_response = Some(
+                HttpResponse::BadRequest()
+                .content_type(PLAINTEXT)
+                .body(format!("File should be {} bytes", metadata.size))
+            );
+            break;
+        }
+
+        writer.write_all(&chunk).await?;
+        progress.hasher.update(&chunk);
+        progress.offset += chunk.len() as u64;
+        data.metrics.attachment_bytes_in(chunk.len() as u64);
+    }
+    writer.flush().await?;
+
+    if let Some(response) = error_response {
+        claim.progress = Some(progress);
+        return Ok(response);
+    }
+
+    if progress.offset < metadata.size {
+        let offset = progress.offset;
+        claim.progress = Some(progress);
+        return Ok(progress_response(HttpResponse::NoContent(), offset, metadata.size));
+    }
+
+    let hash = SHA512::from_digest(progress.hasher.finalize());
+    if hash != metadata.hash {
+        // The client will have to start over:
+        uploads.remove(&key);
+        return Ok(
+            HttpResponse::BadRequest()
+            .content_type(PLAINTEXT)
+            .body(format!("Invalid data. Expected {}", metadata.hash))
+        );
+    }
+    debug!("Received correct hash: {}", &hash);
+
+    let mut file = writer.into_inner().await;
+    blocking::unblock(move || -> Result<(), anyhow::Error> {
+        file.seek(SeekFrom::Start(0))?;
+        let backend = data.backend_factory.open()?;
+        backend.save_attachment(metadata.size, &metadata.hash, &mut file)?;
+        Ok(())
+    }).await?;
+    uploads.remove(&key);
+
+    Ok(HttpResponse::Created().body(""))
+}
+
+/// `DELETE .../files/{file_name}/upload`: Abandon an upload.
+pub(crate) async fn delete_upload(
+    data: Data<AppData>,
+    path: Path<(UserID, Signature, String)>,
+) -> Result<HttpResponse, Error> {
+    let (user_id, signature, file_name) = path.into_inner();
+    let key = Uploads::key(&user_id, &signature, &file_name);
+
+    if data.uploads.offset(&key).is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    match data.uploads.claim(&key) {
+        Some(_claim) => {
+            data.uploads.remove(&key);
+            Ok(HttpResponse::NoContent().finish())
+        },
+        None => Ok(
+            HttpResponse::Conflict()
+            .content_type(PLAINTEXT)
+            .body("Another request is uploading this file.")
+        ),
+    }
+}
+
+/// Hash the first `len` bytes of a partial upload.
+fn rehash(mut file: &File, len: u64) -> Result<Progress, anyhow::Error> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = sha512::State::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut offset = 0;
+    while offset < len {
+        let read = file.read(&mut buf)?;
+        if read == 0 { break; }
+        hasher.update(&buf[..read]);
+        offset += read as u64;
+    }
+    Ok(Progress { offset, hasher })
+}
+
+/// Periodically remove abandoned uploads.
+pub(crate) fn remove_expired(uploads: Arc<Uploads>, max_age: Duration) {
+    actix_web::rt::spawn(async move {
+        loop {
+            let uploads = uploads.clone();
+            let result = web::block(move || uploads.remove_expired(max_age)).await;
+            match result {
+                Ok(Ok(())) => {},
+                Ok(Err(err)) => error!("Error removing expired uploads: {:#}", err),
+                Err(err) => error!("Error removing expired uploads: {}", err),
+            }
+
+            actix_web::rt::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    });
+}
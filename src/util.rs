@@ -30,13 +30,45 @@ impl <'a> Display for AsHexDisplayer<'a> {
     }
 }
 
+/// A fixed-size set of bits, packed least-significant bit first.
+/// Used for compact yes/no answers in protobuf responses.
+pub(crate) struct Bitmap {
+    bytes: Vec<u8>,
+}
+
+impl Bitmap {
+    /// A bitmap of `len` bits, all unset.
+    pub fn new(len: usize) -> Self {
+        Self { bytes: vec![0; (len + 7) / 8] }
+    }
+
+    pub fn set(&mut self, index: usize) {
+        self.bytes[index / 8] |= 1 << (index % 8);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::AsHex;
+    use super::{AsHex, Bitmap};
 
     #[test]
     fn example() {
         let bytes = [0u8, 255, 88, 42];
         assert_eq!("00ff582a", format!("{}", bytes.as_slice().as_hex()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn bitmap() {
+        let mut bits = Bitmap::new(10);
+        bits.set(0);
+        bits.set(3);
+        bits.set(9);
+        assert_eq!(vec![0b0000_1001, 0b0000_0010], bits.into_bytes());
+
+        assert!(Bitmap::new(0).into_bytes().is_empty());
+    }
+}